@@ -8,8 +8,8 @@ let
     "-o" cfg.destinationDir
     "-d" cfg.dbPath
     "-f" cfg.format
-    "-b" (toString cfg.bitrate)
   ]
+  ++ (optional (cfg.bitrate != null) [ "-b" (toString cfg.bitrate) ])
   ++ (concatMap (x: [ "-a" x ]) cfg.allowedExtensions)
   ++ (concatMap (x: [ "-x" x ]) cfg.ignoredExtensions)
   ++ (optional (cfg.maxThreads != null) [ "-t" (toString cfg.maxThreads) ])
@@ -78,9 +78,9 @@ in {
       description = "Output format (ffmpeg encoder file extension)";
     };
     bitrate = mkOption {
-      type = types.int;
+      type = types.nullOr types.int;
       default = 160;
-      description = "Bitrate in kbps.";
+      description = "Bitrate in kbps. Must be null for lossless formats.";
     };
     maxThreads = mkOption {
       type = types.nullOr types.int;
@@ -0,0 +1,127 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Mutex,
+};
+
+use crate::util::has_extension;
+
+/// Name of covers extracted by --extract-art.
+pub const ART_NAME: &str = "cover.jpg";
+
+// source images that get passed through, and make an extracted cover redundant
+const IMAGE_EXTS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp", "bmp"];
+
+/// Extracts the embedded cover of a transcoded file into each destination
+/// directory that has none. Only one worker tries a directory at a time, and it
+/// is done for the run once it has a cover, or once extracting from a file with
+/// an embedded picture failed. A file without one leaves it to the next one.
+#[derive(Default)]
+pub struct ArtExtractor {
+    // directories done for the run, or being tried by a worker
+    dirs: Mutex<HashSet<PathBuf>>,
+}
+
+impl ArtExtractor {
+    /// Extract the cover of `src` next to its output `dst`, unless the directory
+    /// already has art or another worker is on it. `has_picture` is only asked
+    /// for directories still without art, a source it says has no embedded
+    /// picture isn't tried. Returns the path written.
+    pub fn extract(
+        &self,
+        src: &Path,
+        dst: &Path,
+        has_picture: impl FnOnce() -> Option<bool>,
+    ) -> Option<PathBuf> {
+        let dir = dst.parent()?;
+        let dirs = || self.dirs.lock().unwrap_or_else(|e| e.into_inner());
+        if !dirs().insert(dir.to_path_buf()) {
+            return None;
+        }
+        let art = dir.join(ART_NAME);
+        if art.symlink_metadata().is_ok() || src.parent().is_some_and(has_images) {
+            return None;
+        }
+        let has_picture = has_picture();
+        let extracted = match has_picture {
+            Some(false) => None,
+            _ => extract_to(src, &art),
+        };
+        // retrying after a file with a picture failed would most likely fail again
+        if extracted.is_none() && has_picture != Some(true) {
+            dirs().remove(dir);
+        }
+        extracted
+    }
+}
+
+fn extract_to(src: &Path, art: &Path) -> Option<PathBuf> {
+    // -n so a cover passed through meanwhile is never overwritten
+    #[rustfmt::skip]
+    let output = Command::new("ffmpeg")
+        .arg("-threads").arg("1")
+        .arg("-v").arg("error")
+        .arg("-n")
+        .arg("-i").arg(src)
+        .arg("-map").arg("0:v")
+        .arg("-frames:v").arg("1")
+        .arg(art)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        // most often the file has no embedded picture at all
+        log::debug!(
+            "no art extracted from {}: {}",
+            src.display(),
+            String::from_utf8_lossy(&output.stderr).trim(),
+        );
+        return None;
+    }
+    log::info!("extracted art of {} to {}", src.display(), art.display());
+    Some(art.to_path_buf())
+}
+
+fn has_images(src_dir: &Path) -> bool {
+    let exts: Vec<String> = IMAGE_EXTS.iter().map(|e| e.to_string()).collect();
+    let Ok(entries) = fs::read_dir(src_dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| has_extension(&entry.path(), &exts))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn directories_are_retried_only_after_files_without_a_picture() {
+        let tmp = TempDir::new();
+        let src = tmp.file("src/a.flac", b"not audio");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        let dst = tmp.path().join("dst/a.opus");
+        let art = ArtExtractor::default();
+        let asked = &Cell::new(0);
+        let has_picture = |picture| {
+            move || {
+                asked.set(asked.get() + 1);
+                Some(picture)
+            }
+        };
+
+        // no picture, so nothing is tried and the next file gets its chance
+        assert_eq!(art.extract(&src, &dst, has_picture(false)), None);
+        assert_eq!(art.extract(&src, &dst, has_picture(false)), None);
+        assert_eq!(asked.get(), 2);
+        // a picture that can't be extracted isn't tried again this run
+        assert_eq!(art.extract(&src, &dst, has_picture(true)), None);
+        assert_eq!(art.extract(&src, &dst, has_picture(true)), None);
+        assert_eq!(asked.get(), 3);
+        assert!(!tmp.path().join("dst").join(ART_NAME).exists());
+    }
+}
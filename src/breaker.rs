@@ -0,0 +1,58 @@
+use std::collections::VecDeque;
+
+// number of most recent results the failure rate is computed over
+const WINDOW: usize = 100;
+
+/// Trips when failures look systemic (e.g. the destination drive died) rather
+/// than specific to a few bad source files.
+pub struct CircuitBreaker {
+    max_consecutive: Option<usize>,
+    max_rate: Option<u32>,
+    consecutive: usize,
+    window: VecDeque<bool>,
+    window_fails: usize,
+}
+
+impl CircuitBreaker {
+    /// `max_rate` is a percentage of failures among the last 100 results.
+    pub fn new(max_consecutive: Option<usize>, max_rate: Option<u32>) -> Self {
+        CircuitBreaker {
+            max_consecutive,
+            max_rate,
+            consecutive: 0,
+            window: VecDeque::with_capacity(WINDOW),
+            window_fails: 0,
+        }
+    }
+
+    /// Record a result, returning why the breaker tripped if it did.
+    pub fn record(&mut self, failed: bool) -> Option<String> {
+        self.consecutive = if failed { self.consecutive + 1 } else { 0 };
+
+        self.window.push_back(failed);
+        self.window_fails += failed as usize;
+        if self.window.len() > WINDOW && self.window.pop_front() == Some(true) {
+            self.window_fails -= 1;
+        }
+
+        if let Some(max) = self.max_consecutive
+            && self.consecutive >= max
+        {
+            return Some(match self.consecutive {
+                1 => "a file failed".to_string(),
+                n => format!("{n} consecutive files failed"),
+            });
+        }
+        // don't judge the rate until the window has filled up
+        if let Some(max) = self.max_rate
+            && self.window.len() == WINDOW
+            && self.window_fails * 100 > max as usize * WINDOW
+        {
+            return Some(format!(
+                "{} of the last {WINDOW} files failed",
+                self.window_fails,
+            ));
+        }
+        None
+    }
+}
@@ -0,0 +1,442 @@
+use std::{fmt::Write, str::FromStr};
+
+use anyhow::Result;
+use argh::FromArgs;
+
+/// Print a shell completion script, e.g. `source <(sidechain completions bash)`.
+#[derive(FromArgs, Debug, Clone)]
+pub struct CompletionsArgs {
+    /// the shell to complete for: bash, zsh or fish
+    #[argh(positional)]
+    pub shell: Shell,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(format!(
+                "unsupported shell '{s}', expected bash, zsh or fish"
+            )),
+        }
+    }
+}
+
+// offered for -f, any extension ffmpeg can write works
+const FORMATS: &[&str] = &["opus", "ogg", "mp3", "m4a", "aac"];
+
+/// How the value of an option is completed.
+enum Hint {
+    None,
+    Dir,
+    File,
+    Words(&'static [&'static str]),
+}
+
+fn hint(long: &str) -> Hint {
+    match long {
+        "source" | "destination" | "trash-dir" => Hint::Dir,
+        "db-path" => Hint::File,
+        "format" => Hint::Words(FORMATS),
+        "link-mode" => Hint::Words(&["hard", "soft", "copy"]),
+        _ => Hint::None,
+    }
+}
+
+struct Opt {
+    short: Option<char>,
+    long: String,
+    takes_value: bool,
+    repeated: bool,
+    desc: String,
+}
+
+struct Command {
+    // empty for the top level sync command
+    name: &'static str,
+    desc: String,
+    opts: Vec<Opt>,
+    // words completed for the first positional argument
+    positional: &'static [&'static str],
+}
+
+/// The --help output argh generates for a command, which completions are built
+/// from so they can't drift from the argh definitions.
+pub fn help<T: FromArgs>(cmd: &[&str]) -> String {
+    match T::from_args(cmd, &["--help"]) {
+        Err(exit) => exit.output,
+        Ok(_) => String::new(),
+    }
+}
+
+/// Print the completion script. `helps` lists the top level help first, then
+/// every subcommand with its help.
+pub fn run(args: CompletionsArgs, helps: &[(&'static str, String)]) -> Result<()> {
+    print!("{}", script(args.shell, helps));
+    Ok(())
+}
+
+fn script(shell: Shell, helps: &[(&'static str, String)]) -> String {
+    let commands: Vec<Command> = helps
+        .iter()
+        .map(|(name, help)| parse_help(name, help))
+        .collect();
+    match shell {
+        Shell::Bash => bash(&commands),
+        Shell::Zsh => zsh(&commands),
+        Shell::Fish => fish(&commands),
+    }
+}
+
+// the usage line says which options take values, the options section has both
+// names and the description of each
+fn parse_help(name: &'static str, help: &str) -> Command {
+    let mut lines = help.lines();
+    let usage = lines.next().unwrap_or_default();
+    let mut valued = Vec::new();
+    let mut last = "";
+    for token in usage.split_whitespace() {
+        let token = token.trim_start_matches('[').trim_end_matches(']');
+        if token.starts_with('-') {
+            last = token;
+        } else if token.starts_with('<') && !last.is_empty() {
+            valued.push((last, token.ends_with("...>")));
+            last = "";
+        }
+    }
+
+    let desc = lines
+        .by_ref()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or_default()
+        .to_string();
+    let mut opts: Vec<Opt> = Vec::new();
+    let mut in_options = false;
+    for line in lines {
+        if line == "Options:" {
+            in_options = true;
+            continue;
+        }
+        if !in_options || line.is_empty() {
+            in_options = in_options && !line.is_empty();
+            continue;
+        }
+        let text = line.trim_start();
+        // continuation of the previous description
+        if !text.starts_with('-') || line.len() - text.len() > 2 {
+            if let Some(opt) = opts.last_mut() {
+                if !opt.desc.is_empty() {
+                    opt.desc.push(' ');
+                }
+                opt.desc.push_str(text);
+            }
+            continue;
+        }
+        let (mut short, mut long) = (None, None);
+        let mut rest = text;
+        while rest.starts_with('-') || rest.starts_with("help") {
+            let end = rest.find([',', ' ']).unwrap_or(rest.len());
+            let name = &rest[..end];
+            if let Some(l) = name.strip_prefix("--") {
+                long = Some(l.to_string());
+            } else if let Some(s) = name.strip_prefix('-') {
+                short = s.chars().next();
+            }
+            rest = rest[end..].trim_start_matches(',').trim_start();
+        }
+        let Some(long) = long else { continue };
+        let value = valued.iter().find(|(used, _)| {
+            *used == format!("--{long}")
+                || short.is_some_and(|s| *used == format!("-{s}"))
+        });
+        opts.push(Opt {
+            short,
+            long,
+            takes_value: value.is_some(),
+            repeated: value.is_some_and(|(_, repeated)| *repeated),
+            desc: rest.to_string(),
+        });
+    }
+
+    let positional = match name {
+        "completions" => SHELLS,
+        _ => &[],
+    };
+    Command {
+        name,
+        desc,
+        opts,
+        positional,
+    }
+}
+
+fn bash(commands: &[Command]) -> String {
+    let mut s = String::new();
+    s.push_str("_sidechain() {\n");
+    s.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\"\n");
+    s.push_str("    local prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    s.push_str("    local sub=\"${COMP_WORDS[1]}\"\n");
+    s.push_str("    [[ $COMP_CWORD -eq 1 ]] && sub=\"\"\n");
+    s.push_str("    case \"$sub\" in\n");
+    for command in commands.iter().filter(|c| !c.name.is_empty()) {
+        bash_command(&mut s, command, command.name, "");
+    }
+    if let Some(top) = commands.iter().find(|c| c.name.is_empty()) {
+        let subcommands: Vec<&str> = commands
+            .iter()
+            .filter(|c| !c.name.is_empty())
+            .map(|c| c.name)
+            .collect();
+        bash_command(&mut s, top, "*", &subcommands.join(" "));
+    }
+    s.push_str("    esac\n");
+    s.push_str("}\n");
+    s.push_str("complete -o filenames -F _sidechain sidechain\n");
+    s
+}
+
+// subcommands are only offered as the first word
+fn bash_command(s: &mut String, command: &Command, pattern: &str, subcommands: &str) {
+    let _ = writeln!(s, "        {pattern})");
+    s.push_str("            case \"$prev\" in\n");
+    for opt in command.opts.iter().filter(|o| o.takes_value) {
+        let names = match opt.short {
+            Some(short) => format!("-{short}|--{}", opt.long),
+            None => format!("--{}", opt.long),
+        };
+        let action = match hint(&opt.long) {
+            Hint::None => "return".to_string(),
+            Hint::Dir => "COMPREPLY=($(compgen -d -- \"$cur\")); return".to_string(),
+            Hint::File => "COMPREPLY=($(compgen -f -- \"$cur\")); return".to_string(),
+            Hint::Words(words) => format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return",
+                words.join(" "),
+            ),
+        };
+        let _ = writeln!(s, "                {names}) {action} ;;");
+    }
+    s.push_str("            esac\n");
+    let mut words: Vec<String> = Vec::new();
+    for opt in &command.opts {
+        words.extend(opt.short.map(|short| format!("-{short}")));
+        words.push(format!("--{}", opt.long));
+    }
+    words.extend(command.positional.iter().map(|w| w.to_string()));
+    let _ = writeln!(s, "            local opts=\"{}\"", words.join(" "));
+    if !subcommands.is_empty() {
+        let _ = writeln!(
+            s,
+            "            [[ $COMP_CWORD -eq 1 ]] && opts=\"$opts {subcommands}\"",
+        );
+    }
+    s.push_str("            COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))\n");
+    s.push_str("            ;;\n");
+}
+
+fn zsh(commands: &[Command]) -> String {
+    let mut s = String::from("#compdef sidechain\n\n_sidechain() {\n");
+    s.push_str("    case $words[2] in\n");
+    for command in commands.iter().filter(|c| !c.name.is_empty()) {
+        let _ = writeln!(s, "        {})", command.name);
+        s.push_str("            shift words\n");
+        s.push_str("            (( CURRENT-- ))\n");
+        zsh_arguments(&mut s, command, command.positional);
+        s.push_str("            ;;\n");
+    }
+    if let Some(top) = commands.iter().find(|c| c.name.is_empty()) {
+        s.push_str("        *)\n");
+        let subcommands: Vec<String> = commands
+            .iter()
+            .filter(|c| !c.name.is_empty())
+            .map(|c| format!("{}\\:{}", c.name, zsh_word(&c.desc)))
+            .collect();
+        zsh_arguments_with(&mut s, top, &format!("(({}))", subcommands.join(" ")));
+        s.push_str("            ;;\n");
+    }
+    s.push_str("    esac\n}\n\n_sidechain \"$@\"\n");
+    s
+}
+
+fn zsh_arguments(s: &mut String, command: &Command, positional: &[&str]) {
+    let words = match positional {
+        [] => String::new(),
+        words => format!("({})", words.join(" ")),
+    };
+    zsh_arguments_with(s, command, &words);
+}
+
+fn zsh_arguments_with(s: &mut String, command: &Command, positional: &str) {
+    s.push_str("            _arguments -s");
+    for opt in &command.opts {
+        let desc = zsh_escape(&opt.desc);
+        let value = match (opt.takes_value, hint(&opt.long)) {
+            (false, _) => String::new(),
+            (true, Hint::None) => format!(":{}: ", opt.long),
+            (true, Hint::Dir) => format!(":{}:_files -/", opt.long),
+            (true, Hint::File) => format!(":{}:_files", opt.long),
+            (true, Hint::Words(words)) => {
+                format!(":{}:({})", opt.long, words.join(" "))
+            }
+        };
+        let repeat = if opt.repeated { "*" } else { "" };
+        let spec = match opt.short {
+            Some(short) if opt.repeated => {
+                format!("'{repeat}'{{-{short},--{}}}'[{desc}]{value}'", opt.long)
+            }
+            Some(short) => format!(
+                "'(-{short} --{long})'{{-{short},--{long}}}'[{desc}]{value}'",
+                long = opt.long,
+            ),
+            None => format!("'{repeat}--{}[{desc}]{value}'", opt.long),
+        };
+        let _ = write!(s, " \\\n                {spec}");
+    }
+    if !positional.is_empty() {
+        let _ = write!(s, " \\\n                '1: :{positional}'");
+    }
+    s.push('\n');
+}
+
+// descriptions go inside single quoted [..] specs
+fn zsh_escape(desc: &str) -> String {
+    desc.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+// descriptions in a ((word\:description ...)) list are split on spaces and
+// parsed as shell words
+fn zsh_word(desc: &str) -> String {
+    let mut escaped = String::new();
+    for c in zsh_escape(desc).chars() {
+        if matches!(c, ' ' | '(' | ')' | '`' | '<' | '>' | '$') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn fish(commands: &[Command]) -> String {
+    let names: Vec<&str> = commands
+        .iter()
+        .filter(|c| !c.name.is_empty())
+        .map(|c| c.name)
+        .collect();
+    let mut s = String::from("complete -c sidechain -f\n");
+    for command in commands {
+        let condition = match command.name {
+            "" => format!("not __fish_seen_subcommand_from {}", names.join(" ")),
+            name => format!("__fish_seen_subcommand_from {name}"),
+        };
+        if !command.name.is_empty() {
+            let _ = writeln!(
+                s,
+                "complete -c sidechain -n __fish_use_subcommand -a {} -d '{}'",
+                command.name,
+                fish_escape(&command.desc),
+            );
+        }
+        for opt in &command.opts {
+            let mut line = format!("complete -c sidechain -n '{condition}'");
+            if let Some(short) = opt.short {
+                let _ = write!(line, " -s {short}");
+            }
+            let _ = write!(line, " -l {}", opt.long);
+            if opt.takes_value {
+                match hint(&opt.long) {
+                    Hint::None => line.push_str(" -x"),
+                    Hint::Dir => {
+                        line.push_str(" -xa '(__fish_complete_directories)'")
+                    }
+                    Hint::File => line.push_str(" -rF"),
+                    Hint::Words(words) => {
+                        let _ = write!(line, " -xa '{}'", words.join(" "));
+                    }
+                }
+            }
+            let _ = writeln!(s, "{line} -d '{}'", fish_escape(&opt.desc));
+        }
+        if !command.positional.is_empty() {
+            let _ = writeln!(
+                s,
+                "complete -c sidechain -n '{condition}' -a '{}'",
+                command.positional.join(" "),
+            );
+        }
+    }
+    s
+}
+
+fn fish_escape(desc: &str) -> String {
+    desc.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every --option and -s the help of a command mentions, so a field missed by
+    // parse_help fails here
+    fn flags(help: &str) -> Vec<(Option<char>, String)> {
+        let mut flags = Vec::new();
+        for line in help.lines().map(str::trim_start) {
+            let mut short = None;
+            let words = line.split([' ', ',']).filter(|w| !w.is_empty());
+            for word in words.take_while(|w| w.starts_with('-')) {
+                match word.strip_prefix("--") {
+                    Some(long) => flags.push((short.take(), long.to_string())),
+                    None => short = word.chars().nth(1),
+                }
+            }
+        }
+        flags
+    }
+
+    #[test]
+    fn every_option_is_completed() {
+        let helps = crate::command_helps();
+        let bash = script(Shell::Bash, &helps);
+        let zsh = script(Shell::Zsh, &helps);
+        let fish = script(Shell::Fish, &helps);
+        for (name, help) in &helps {
+            let flags = flags(help);
+            assert!(!flags.is_empty(), "no options found for '{name}'");
+            for (short, long) in flags {
+                let what = format!("--{long} of '{name}'");
+                assert!(bash.contains(&format!(" --{long}")), "bash lacks {what}");
+                let zsh_spec = [format!("--{long}["), format!(",--{long}}}")];
+                assert!(zsh_spec.iter().any(|s| zsh.contains(s)), "zsh lacks {what}");
+                assert!(fish.contains(&format!(" -l {long} ")), "fish lacks {what}");
+                let Some(short) = short else { continue };
+                let what = format!("-{short} of '{name}'");
+                let pair = format!("-{short} --{long} ");
+                assert!(bash.contains(&pair), "bash lacks {what}");
+                let pair = format!("{{-{short},--{long}}}");
+                assert!(zsh.contains(&pair), "zsh lacks {what}");
+                let pair = format!(" -s {short} -l {long} ");
+                assert!(fish.contains(&pair), "fish lacks {what}");
+            }
+            if !name.is_empty() {
+                assert!(bash.contains(&format!(" {name})")), "bash lacks {name}");
+                assert!(zsh.contains(&format!("{name}\\:")), "zsh lacks {name}");
+                assert!(fish.contains(&format!(" -a {name} ")), "fish lacks {name}");
+            }
+        }
+    }
+}
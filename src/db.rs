@@ -6,7 +6,12 @@ use std::{
 use anyhow::{Context, Result};
 use rusqlite::{params, Connection};
 
-use crate::worker::{FileCache, FileInfo, FileStatus, ProcessedFile};
+use crate::{
+    missing::Reason,
+    probe::ProbeInfo,
+    settings::Settings,
+    worker::{CachedProbe, FileCache, FileInfo, FileStatus, ProbeCache, ProcessedFile},
+};
 
 /// Open a connection to the database.
 pub fn connect(db_path: &Path) -> Result<Connection> {
@@ -26,22 +31,133 @@ pub fn connect(db_path: &Path) -> Result<Connection> {
     Ok(conn)
 }
 
-/// Create the file table if it doesn't already exist.
-pub fn init(conn: &Connection) -> Result<()> {
-    conn.execute_batch(
-        "CREATE TABLE IF NOT EXISTS files (
-            id        INTEGER PRIMARY KEY,
-            src_path  TEXT NOT NULL UNIQUE,
-            dst_path  TEXT NOT NULL,
-            hash      TEXT NOT NULL,
-            mtime     INTEGER NOT NULL,
-            size      INTEGER NOT NULL,
-            config    TEXT NOT NULL -- e.g. 'opus:192', for change detection
-        );
-        CREATE INDEX IF NOT EXISTS idx_hash ON files(hash);",
-        // ^^^ index for rename detection (finding a hash regardless of path)
-    )
-    .context("failed to initialize database schema")?;
+/// Schema migrations, applied in order. Each entry brings the database to
+/// `user_version` = its index + 1.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS files (
+        id        INTEGER PRIMARY KEY,
+        src_path  TEXT NOT NULL UNIQUE,
+        dst_path  TEXT NOT NULL,
+        hash      TEXT NOT NULL,
+        mtime     INTEGER NOT NULL,
+        size      INTEGER NOT NULL,
+        config    TEXT NOT NULL -- e.g. 'opus:192', for change detection
+    );
+    CREATE INDEX IF NOT EXISTS idx_hash ON files(hash);",
+    // ^^^ index for rename detection (finding a hash regardless of path)
+    "CREATE TABLE IF NOT EXISTS probe (
+        src_path    TEXT PRIMARY KEY,
+        mtime       INTEGER NOT NULL, -- of the source when it was probed, the
+        size        INTEGER NOT NULL, -- row is stale once either changes
+        codec       TEXT NOT NULL,
+        bitrate     INTEGER, -- bits per second
+        sample_rate INTEGER,
+        channels    INTEGER NOT NULL,
+        bit_depth   INTEGER,
+        duration    REAL, -- seconds
+        has_art     INTEGER NOT NULL,
+        chapters    INTEGER NOT NULL,
+        tags        TEXT NOT NULL -- name=value lines
+    );",
+    // ^^^ what ffprobe reported about each source
+    "ALTER TABLE files ADD COLUMN last_synced INTEGER; -- unix timestamp
+     ALTER TABLE files ADD COLUMN last_status TEXT; -- how dst was last produced",
+    "CREATE TABLE IF NOT EXISTS trash (
+        path       TEXT PRIMARY KEY, -- location inside the trash dir
+        hash       TEXT NOT NULL,
+        size       INTEGER NOT NULL,
+        config     TEXT NOT NULL,
+        trashed_at INTEGER NOT NULL
+    );",
+    "ALTER TABLE files ADD COLUMN orphaned_at INTEGER; -- start of grace period",
+    "ALTER TABLE files ADD COLUMN detected_type TEXT; -- NULL if not detected",
+    "CREATE TABLE IF NOT EXISTS runs (
+        id              INTEGER PRIMARY KEY,
+        started_at      INTEGER NOT NULL,
+        finished_at     INTEGER, -- NULL if the run never finished
+        settings        TEXT NOT NULL, -- command line arguments
+        transcoded      INTEGER,
+        passed_through  INTEGER,
+        reclaimed       INTEGER,
+        skipped         INTEGER,
+        failed          INTEGER,
+        orphans_removed INTEGER,
+        bytes_written   INTEGER,
+        exit_status     TEXT -- 'success', or why the run failed
+    );",
+    "CREATE TABLE IF NOT EXISTS dirs (
+        path  TEXT PRIMARY KEY, -- source directory
+        mtime INTEGER -- nanoseconds, NULL if it must be read on the next run
+    );
+    ALTER TABLE runs ADD COLUMN fast_scan INTEGER NOT NULL DEFAULT 0;",
+    // ^^^ 1 if unchanged directories were skipped, for periodic full scans
+    "ALTER TABLE files ADD COLUMN path_template TEXT; -- NULL if dst is mirrored",
+    "CREATE TABLE IF NOT EXISTS art (
+        dst_path TEXT PRIMARY KEY, -- cover extracted by --extract-art
+        src_path TEXT NOT NULL -- file it was extracted from
+    );",
+    "ALTER TABLE files ADD COLUMN dst_size INTEGER; -- NULL if not recorded yet",
+    "CREATE TABLE IF NOT EXISTS snapshot (
+        src_path TEXT PRIMARY KEY,
+        dst_path TEXT NOT NULL,
+        dst_size INTEGER,
+        content  TEXT NOT NULL, -- prefix of the source hash, to pair up renames
+        config   TEXT NOT NULL
+    );",
+    // ^^^ the file table as of the last completed run, for the diff command
+    "CREATE TABLE IF NOT EXISTS settings (
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        key    TEXT NOT NULL, -- e.g. 'bitrate'
+        value  TEXT NOT NULL,
+        PRIMARY KEY (run_id, key)
+    );",
+    // ^^^ effective settings of each run, to explain what changed between runs
+    "ALTER TABLE files ADD COLUMN last_reason TEXT; -- why dst was last produced",
+    "CREATE TABLE IF NOT EXISTS transferred (
+        path           TEXT PRIMARY KEY, -- relative to the --staging destination
+        hash           TEXT NOT NULL, -- of the source, with config and size to
+        config         TEXT NOT NULL, -- tell whether the output changed since
+        dst_size       INTEGER,
+        transferred_at INTEGER NOT NULL
+    );",
+    // ^^^ outputs the --staging transfer got onto the device, and as what
+    "CREATE TABLE IF NOT EXISTS unsynced (
+        src_path TEXT PRIMARY KEY,
+        reason   TEXT NOT NULL, -- e.g. 'failed'
+        detail   TEXT -- the error, or the path in the way
+    );",
+    // ^^^ sources the last runs left without an output, for the missing command
+    "CREATE TABLE snapshot_by_id (
+        id       INTEGER PRIMARY KEY, -- of the files row
+        dst_path TEXT NOT NULL,
+        dst_size INTEGER,
+        content  TEXT NOT NULL,
+        config   TEXT NOT NULL
+    );
+    INSERT INTO snapshot_by_id (id, dst_path, dst_size, content, config)
+        SELECT coalesce(f.id, -s.rowid), s.dst_path, s.dst_size, s.content, s.config
+        FROM snapshot s LEFT JOIN files f ON f.src_path = s.src_path;
+    DROP TABLE snapshot;
+    ALTER TABLE snapshot_by_id RENAME TO snapshot;",
+    // ^^^ keyed by row id instead of source path, which halves its size. rows
+    // pruned since get negative ids, so they still show up as removed
+    "ALTER TABLE transferred ADD COLUMN dst_mtime INTEGER; -- nanoseconds",
+    // ^^^ changes with every re-encode, even one giving the same size. NULL for
+    // rows from before it was recorded
+];
+
+/// Create the file table if it doesn't already exist and apply pending migrations.
+pub fn init(conn: &mut Connection) -> Result<()> {
+    let version: i64 =
+        conn.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+
+    let tx = conn.transaction()?;
+    for (i, sql) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        tx.execute_batch(sql)
+            .with_context(|| format!("failed to apply schema migration {}", i + 1))?;
+        tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+    }
+    tx.commit().context("failed to initialize database schema")?;
 
     Ok(())
 }
@@ -52,8 +168,11 @@ pub fn load_cache(conn: &Connection) -> Result<FileCache> {
         conn.query_row("SELECT count(*) FROM files", [], |r| r.get(0))?;
     let mut cache = HashMap::with_capacity(count as usize);
 
-    let mut stmt = conn
-        .prepare("SELECT src_path, dst_path, hash, mtime, size, config FROM files")?;
+    let mut stmt = conn.prepare(
+        "SELECT src_path, dst_path, hash, mtime, size, config, orphaned_at,
+                detected_type, path_template, dst_size
+         FROM files",
+    )?;
 
     let iter = stmt.query_map([], |row| {
         let src_str: String = row.get(0)?;
@@ -62,6 +181,10 @@ pub fn load_cache(conn: &Connection) -> Result<FileCache> {
         let mtime = row.get(3)?;
         let size: i64 = row.get(4)?;
         let config = row.get(5)?;
+        let orphaned_at = row.get(6)?;
+        let detected_type = row.get(7)?;
+        let path_template = row.get(8)?;
+        let dst_size: Option<i64> = row.get(9)?;
         Ok((
             PathBuf::from(src_str),
             FileInfo {
@@ -70,6 +193,10 @@ pub fn load_cache(conn: &Connection) -> Result<FileCache> {
                 mtime,
                 size: size as u64,
                 config,
+                detected_type,
+                path_template,
+                dst_size: dst_size.map(|n| n as u64),
+                orphaned_at,
             },
         ))
     })?;
@@ -82,45 +209,119 @@ pub fn load_cache(conn: &Connection) -> Result<FileCache> {
     Ok(cache)
 }
 
-/// Batch upsert processed file records.
+/// Read the probe table into an in-memory cache.
+pub fn load_probes(conn: &Connection) -> Result<ProbeCache> {
+    let mut stmt = conn.prepare(
+        "SELECT src_path, mtime, size, codec, bitrate, sample_rate, channels,
+                bit_depth, duration, has_art, chapters, tags
+         FROM probe",
+    )?;
+    let probes = stmt
+        .query_map([], |row| {
+            let src: String = row.get(0)?;
+            let size: i64 = row.get(2)?;
+            let info = ProbeInfo {
+                codec: row.get(3)?,
+                bitrate: row.get(4)?,
+                sample_rate: row.get(5)?,
+                channels: row.get(6)?,
+                bit_depth: row.get(7)?,
+                duration: row.get(8)?,
+                has_embedded_art: row.get(9)?,
+                chapters: row.get(10)?,
+                tags: tags_from_db(&row.get::<_, String>(11)?),
+            };
+            let probe = CachedProbe { mtime: row.get(1)?, size: size as u64, info };
+            Ok((PathBuf::from(src), probe))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(probes)
+}
+
+// tags are stored one name=value per line, which is how ffprobe reports them
+fn tags_to_db(tags: &HashMap<String, String>) -> String {
+    let mut lines: Vec<String> =
+        tags.iter().map(|(key, value)| format!("{key}={value}")).collect();
+    lines.sort();
+    lines.join("\n")
+}
+
+fn tags_from_db(s: &str) -> HashMap<String, String> {
+    s.lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Batch upsert processed file records, stamping them with the sync time.
 pub fn ingest_results(
     conn: &mut Connection,
     results: impl Iterator<Item = ProcessedFile>,
+    synced_at: i64,
 ) -> Result<()> {
     const BATCH_SIZE: usize = 1000;
     let mut buf = Vec::with_capacity(BATCH_SIZE);
+    // unchanged cache hits only need their timestamp bumped
+    let mut touched = Vec::with_capacity(BATCH_SIZE);
 
     for file in results {
         match file.status {
             FileStatus::PassedThrough
             | FileStatus::Transcoded
             | FileStatus::Reclaimed => buf.push(file),
-            _ => {}
+            // skipped files are only fully written back if something was learned
+            FileStatus::Skipped
+                if file.record_changed
+                    || file.probed.is_some()
+                    || file.extracted_art.is_some() =>
+            {
+                buf.push(file)
+            }
+            FileStatus::Skipped => touched.push(file.src),
+            FileStatus::Conflict => {}
         }
-        if buf.len() >= BATCH_SIZE {
-            flush_batch(conn, &buf)?;
+        if buf.len() + touched.len() >= BATCH_SIZE {
+            flush_batch(conn, &buf, &touched, synced_at)?;
             buf.clear();
+            touched.clear();
         }
     }
-    if !buf.is_empty() {
-        flush_batch(conn, &buf)?;
+    if !buf.is_empty() || !touched.is_empty() {
+        flush_batch(conn, &buf, &touched, synced_at)?;
     }
 
     Ok(())
 }
 
-fn flush_batch(conn: &mut Connection, files: &[ProcessedFile]) -> Result<()> {
+fn flush_batch(
+    conn: &mut Connection,
+    files: &[ProcessedFile],
+    touched: &[PathBuf],
+    synced_at: i64,
+) -> Result<()> {
     let tx = conn.transaction()?;
     {
+        // last_status and last_reason are NULL for skipped files, keeping the
+        // previous ones
         let mut stmt = tx.prepare_cached(
-            "INSERT INTO files (src_path, dst_path, hash, mtime, size, config)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            "INSERT INTO files (
+                src_path, dst_path, hash, mtime, size, config, detected_type,
+                path_template, dst_size, last_synced, last_status, last_reason
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
              ON CONFLICT(src_path) DO UPDATE SET
                 dst_path = excluded.dst_path,
                 hash = excluded.hash,
                 mtime = excluded.mtime,
                 size = excluded.size,
-                config = excluded.config",
+                config = excluded.config,
+                detected_type = excluded.detected_type,
+                path_template = excluded.path_template,
+                dst_size = excluded.dst_size,
+                last_synced = excluded.last_synced,
+                last_status = coalesce(excluded.last_status, files.last_status),
+                last_reason = coalesce(excluded.last_reason, files.last_reason),
+                orphaned_at = NULL",
         )?;
         for file in files {
             stmt.execute(params![
@@ -130,14 +331,148 @@ fn flush_batch(conn: &mut Connection, files: &[ProcessedFile]) -> Result<()> {
                 file.info.mtime,
                 file.info.size as i64,
                 file.info.config,
+                file.info.detected_type,
+                file.info.path_template,
+                file.info.dst_size.map(|n| n as i64),
+                synced_at,
+                file.status.as_db_str(),
+                file.reason.map(|reason| reason.as_db_str()),
             ])?;
         }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR REPLACE INTO probe (
+                src_path, mtime, size, codec, bitrate, sample_rate, channels,
+                bit_depth, duration, has_art, chapters, tags
+             )
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+        for file in files {
+            if let Some(p) = &file.probed {
+                stmt.execute(params![
+                    file.src.to_string_lossy(),
+                    file.info.mtime,
+                    file.info.size as i64,
+                    p.codec,
+                    p.bitrate,
+                    p.sample_rate,
+                    p.channels,
+                    p.bit_depth,
+                    p.duration,
+                    p.has_embedded_art,
+                    p.chapters,
+                    tags_to_db(&p.tags),
+                ])?;
+            }
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "INSERT OR REPLACE INTO art (dst_path, src_path) VALUES (?1, ?2)",
+        )?;
+        for file in files {
+            if let Some(art) = &file.extracted_art {
+                let (art, src) = (art.to_string_lossy(), file.src.to_string_lossy());
+                stmt.execute(params![art, src])?;
+            }
+        }
+
+        let mut stmt = tx.prepare_cached(
+            "UPDATE files SET last_synced = ?1, orphaned_at = NULL
+             WHERE src_path = ?2",
+        )?;
+        for path in touched {
+            stmt.execute(params![synced_at, path.to_string_lossy()])?;
+        }
     }
     tx.commit()?;
 
     Ok(())
 }
 
+/// Summary of the sync history recorded in the file table.
+pub struct SyncStats {
+    pub total: i64,
+    pub never_synced: i64,
+    pub oldest: Option<i64>,
+    pub newest: Option<i64>,
+    pub median: Option<i64>,
+    pub per_status: Vec<(String, i64)>,
+    pub per_reason: Vec<(String, i64)>,
+}
+
+/// Aggregate last_synced, last_status and last_reason over the file table.
+pub fn sync_stats(conn: &Connection) -> Result<SyncStats> {
+    let (total, synced, oldest, newest) = conn.query_row(
+        "SELECT count(*), count(last_synced), min(last_synced), max(last_synced)
+         FROM files",
+        [],
+        |r| Ok((r.get::<_, i64>(0)?, r.get::<_, i64>(1)?, r.get(2)?, r.get(3)?)),
+    )?;
+
+    let median = if synced > 0 {
+        conn.query_row(
+            "SELECT last_synced FROM files WHERE last_synced IS NOT NULL
+             ORDER BY last_synced LIMIT 1 OFFSET ?",
+            params![(synced - 1) / 2],
+            |r| r.get(0),
+        )?
+    } else {
+        None
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(last_status, 'unknown'), count(*) FROM files
+         GROUP BY last_status ORDER BY count(*) DESC",
+    )?;
+    let per_status = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut stmt = conn.prepare(
+        "SELECT coalesce(last_reason, 'unknown'), count(*) FROM files
+         GROUP BY last_reason ORDER BY count(*) DESC",
+    )?;
+    let per_reason = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(SyncStats {
+        total,
+        never_synced: total - synced,
+        oldest,
+        newest,
+        median,
+        per_status,
+        per_reason,
+    })
+}
+
+/// Totals over the file and runs tables, for --metrics-file.
+pub struct Totals {
+    pub tracked: i64,
+    pub orphaned: i64,
+    pub source_bytes: i64,
+    pub runs: i64,
+    pub failed_files: i64,
+}
+
+/// Count tracked and orphaned files, and what every recorded run did.
+pub fn totals(conn: &Connection) -> Result<Totals> {
+    let (tracked, orphaned, source_bytes) = conn.query_row(
+        "SELECT count(*) - count(orphaned_at), count(orphaned_at),
+                coalesce(sum(size) FILTER (WHERE orphaned_at IS NULL), 0)
+         FROM files",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+    )?;
+    let (runs, failed_files) = conn.query_row(
+        "SELECT count(*), coalesce(sum(failed), 0) FROM runs",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    Ok(Totals { tracked, orphaned, source_bytes, runs, failed_files })
+}
+
 /// Prune deleted files from the file table.
 pub fn prune<'a>(
     conn: &mut Connection,
@@ -146,11 +481,676 @@ pub fn prune<'a>(
     let tx = conn.transaction()?;
     {
         let mut stmt = tx.prepare("DELETE FROM files WHERE src_path = ?")?;
+        let mut probe_stmt = tx.prepare("DELETE FROM probe WHERE src_path = ?")?;
         for path in to_delete {
             stmt.execute(params![path.to_string_lossy()])?;
+            probe_stmt.execute(params![path.to_string_lossy()])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Source and destination paths of tracked files, only those not synced since
+/// `not_synced_since` if given.
+pub fn load_paths(
+    conn: &Connection,
+    not_synced_since: Option<i64>,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut stmt = conn.prepare(
+        "SELECT src_path, dst_path FROM files
+         WHERE ?1 IS NULL OR last_synced IS NULL OR last_synced < ?1",
+    )?;
+    let paths = stmt
+        .query_map(params![not_synced_since], |r| {
+            let src: String = r.get(0)?;
+            let dst: String = r.get(1)?;
+            Ok((PathBuf::from(src), PathBuf::from(dst)))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(paths)
+}
+
+/// Make the next sync reprocess files even though their mtime and size match, by
+/// invalidating the stored mtime.
+pub fn mark_dirty<'a>(
+    conn: &mut Connection,
+    sources: impl Iterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE files SET mtime = -1 WHERE src_path = ?")?;
+        // the probe would still match the unchanged mtime and size
+        let mut probe_stmt = tx.prepare("DELETE FROM probe WHERE src_path = ?")?;
+        for src in sources {
+            stmt.execute(params![src.to_string_lossy()])?;
+            probe_stmt.execute(params![src.to_string_lossy()])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Whether any source was marked for reprocessing by `mark_dirty`.
+pub fn has_dirty(conn: &Connection) -> Result<bool> {
+    let dirty = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files WHERE mtime = -1)",
+        [],
+        |r| r.get(0),
+    )?;
+    Ok(dirty)
+}
+
+/// Replace the stored hash of sources, as (src, hash).
+pub fn update_hashes(
+    conn: &mut Connection,
+    hashes: &[(PathBuf, String)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("UPDATE files SET hash = ?2 WHERE src_path = ?1")?;
+        for (src, hash) in hashes {
+            stmt.execute(params![src.to_string_lossy(), hash])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Move rows to a new source and destination path, as (old src, new src, new dst).
+pub fn move_rows(
+    conn: &mut Connection,
+    moves: &[(PathBuf, PathBuf, PathBuf)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "UPDATE files SET src_path = ?2, dst_path = ?3 WHERE src_path = ?1",
+        )?;
+        let mut probe_stmt = tx.prepare(
+            "UPDATE OR REPLACE probe SET src_path = ?2 WHERE src_path = ?1",
+        )?;
+        for (old_src, new_src, new_dst) in moves {
+            stmt.execute(params![
+                old_src.to_string_lossy(),
+                new_src.to_string_lossy(),
+                new_dst.to_string_lossy(),
+            ])?;
+            probe_stmt.execute(params![
+                old_src.to_string_lossy(),
+                new_src.to_string_lossy(),
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Start the grace period for orphans whose source just went missing.
+pub fn mark_orphaned<'a>(
+    conn: &mut Connection,
+    srcs: impl Iterator<Item = &'a PathBuf>,
+    orphaned_at: i64,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt =
+            tx.prepare("UPDATE files SET orphaned_at = ?1 WHERE src_path = ?2")?;
+        for src in srcs {
+            stmt.execute(params![orphaned_at, src.to_string_lossy()])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Read the orphans currently quarantined in the trash directory, with the time
+/// each was trashed.
+pub fn load_trash(conn: &Connection) -> Result<Vec<(FileInfo, i64)>> {
+    let mut stmt =
+        conn.prepare("SELECT path, hash, size, config, trashed_at FROM trash")?;
+
+    let iter = stmt.query_map([], |row| {
+        let path: String = row.get(0)?;
+        let size: i64 = row.get(2)?;
+        Ok((
+            FileInfo {
+                dst: PathBuf::from(path),
+                hash: row.get(1)?,
+                size: size as u64,
+                config: row.get(3)?,
+                ..Default::default()
+            },
+            row.get(4)?,
+        ))
+    })?;
+
+    Ok(iter.collect::<rusqlite::Result<_>>()?)
+}
+
+/// Extracted covers, by path with the source they were extracted from.
+pub fn load_art(conn: &Connection) -> Result<HashMap<PathBuf, PathBuf>> {
+    let mut stmt = conn.prepare("SELECT dst_path, src_path FROM art")?;
+    let art = stmt
+        .query_map([], |r| {
+            let (dst, src): (String, String) = (r.get(0)?, r.get(1)?);
+            Ok((PathBuf::from(dst), PathBuf::from(src)))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(art)
+}
+
+/// Move extracted covers to a new path and source, as (old path, new path, new
+/// src).
+pub fn move_art(
+    conn: &mut Connection,
+    moves: &[(PathBuf, PathBuf, PathBuf)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "UPDATE OR REPLACE art SET dst_path = ?2, src_path = ?3
+             WHERE dst_path = ?1",
+        )?;
+        for (old, new, new_src) in moves {
+            stmt.execute(params![
+                old.to_string_lossy(),
+                new.to_string_lossy(),
+                new_src.to_string_lossy(),
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Forget extracted covers whose source file is no longer in the database,
+/// returning their paths so they can be deleted.
+pub fn take_orphaned_art(conn: &mut Connection) -> Result<Vec<PathBuf>> {
+    let tx = conn.transaction()?;
+    let paths: Vec<PathBuf> = {
+        let mut stmt = tx.prepare(
+            "SELECT dst_path FROM art
+             WHERE src_path NOT IN (SELECT src_path FROM files)",
+        )?;
+        stmt.query_map([], |r| r.get::<_, String>(0).map(PathBuf::from))?
+            .collect::<rusqlite::Result<_>>()?
+    };
+    {
+        let mut stmt = tx.prepare("DELETE FROM art WHERE dst_path = ?")?;
+        for path in &paths {
+            stmt.execute(params![path.to_string_lossy()])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(paths)
+}
+
+/// Record orphans that were moved into the trash directory.
+pub fn insert_trash<'a>(
+    conn: &mut Connection,
+    entries: impl Iterator<Item = &'a FileInfo>,
+    trashed_at: i64,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO trash (path, hash, size, config, trashed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for info in entries {
+            stmt.execute(params![
+                info.dst.to_string_lossy(),
+                info.hash,
+                info.size as i64,
+                info.config,
+                trashed_at,
+            ])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Forget trashed files that were purged or restored.
+pub fn remove_trash<'a>(
+    conn: &mut Connection,
+    paths: impl Iterator<Item = &'a PathBuf>,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare("DELETE FROM trash WHERE path = ?")?;
+        for path in paths {
+            stmt.execute(params![path.to_string_lossy()])?;
         }
     }
     tx.commit()?;
 
     Ok(())
 }
+
+/// Outcome of a finished run, for the runs table.
+pub struct RunRecord {
+    pub id: i64,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    pub settings: String,
+    pub transcoded: i64,
+    pub passed_through: i64,
+    pub reclaimed: i64,
+    pub skipped: i64,
+    pub failed: i64,
+    pub orphans_removed: i64,
+    pub bytes_written: i64,
+    pub exit_status: Option<String>,
+}
+
+/// Record the start of a run, returning its id. Runs that never finish keep a
+/// NULL finished_at.
+pub fn begin_run(
+    conn: &Connection,
+    started_at: i64,
+    settings: &str,
+    fast_scan: bool,
+) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO runs (started_at, settings, fast_scan) VALUES (?1, ?2, ?3)",
+        params![started_at, settings, fast_scan],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Record the outcome of a run started with `begin_run`.
+pub fn finish_run(conn: &Connection, run: &RunRecord) -> Result<()> {
+    conn.execute(
+        "UPDATE runs SET
+            finished_at = ?2, transcoded = ?3, passed_through = ?4, reclaimed = ?5,
+            skipped = ?6, failed = ?7, orphans_removed = ?8, bytes_written = ?9,
+            exit_status = ?10
+         WHERE id = ?1",
+        params![
+            run.id,
+            run.finished_at,
+            run.transcoded,
+            run.passed_through,
+            run.reclaimed,
+            run.skipped,
+            run.failed,
+            run.orphans_removed,
+            run.bytes_written,
+            run.exit_status,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Read the most recent runs, newest first.
+pub fn load_runs(conn: &Connection, limit: usize) -> Result<Vec<RunRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, started_at, finished_at, settings, transcoded, passed_through,
+                reclaimed, skipped, failed, orphans_removed, bytes_written,
+                exit_status
+         FROM runs ORDER BY id DESC LIMIT ?",
+    )?;
+    let runs = stmt
+        .query_map(params![limit as i64], |r| {
+            Ok(RunRecord {
+                id: r.get(0)?,
+                started_at: r.get(1)?,
+                finished_at: r.get(2)?,
+                settings: r.get(3)?,
+                transcoded: r.get::<_, Option<i64>>(4)?.unwrap_or(0),
+                passed_through: r.get::<_, Option<i64>>(5)?.unwrap_or(0),
+                reclaimed: r.get::<_, Option<i64>>(6)?.unwrap_or(0),
+                skipped: r.get::<_, Option<i64>>(7)?.unwrap_or(0),
+                failed: r.get::<_, Option<i64>>(8)?.unwrap_or(0),
+                orphans_removed: r.get::<_, Option<i64>>(9)?.unwrap_or(0),
+                bytes_written: r.get::<_, Option<i64>>(10)?.unwrap_or(0),
+                exit_status: r.get(11)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(runs)
+}
+
+/// Record the effective settings of a run started with `begin_run`.
+pub fn save_settings(
+    conn: &mut Connection,
+    run_id: i64,
+    settings: &Settings,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO settings (run_id, key, value) VALUES (?1, ?2, ?3)",
+        )?;
+        for (key, value) in settings {
+            stmt.execute(params![run_id, key, value])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Read the settings of the latest run before `before` (or of all runs) that
+/// recorded any, with its id.
+pub fn previous_settings(
+    conn: &Connection,
+    before: Option<i64>,
+) -> Result<Option<(i64, Settings)>> {
+    let run_id: Option<i64> = conn.query_row(
+        "SELECT max(run_id) FROM settings WHERE ?1 IS NULL OR run_id < ?1",
+        params![before],
+        |r| r.get(0),
+    )?;
+    let Some(run_id) = run_id else {
+        return Ok(None);
+    };
+    Ok(Some((run_id, load_settings(conn, run_id)?)))
+}
+
+/// Read the settings a run recorded, empty for runs before settings were.
+pub fn load_settings(conn: &Connection, run_id: i64) -> Result<Settings> {
+    let mut stmt = conn
+        .prepare("SELECT key, value FROM settings WHERE run_id = ? ORDER BY rowid")?;
+    let settings = stmt
+        .query_map(params![run_id], |r| Ok((r.get(0)?, r.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(settings)
+}
+
+/// A row of the file table as of the last completed run, or as it is now. Only
+/// what the diff command shows or compares is kept: the output path and size
+/// are printed for removed and renamed outputs, and can't be reduced to a hash.
+pub struct SnapshotRow {
+    pub dst: PathBuf,
+    pub dst_size: Option<u64>,
+    pub content: String,
+    pub config: String,
+}
+
+// source hash characters kept in the snapshot, plenty to pair up renames
+const SNAPSHOT_CONTENT_LEN: i64 = 16;
+
+// the tracked files in snapshot form, by row id. sources renamed in place (e.g.
+// by a directory move) keep their id
+const CURRENT_SNAPSHOT: &str =
+    "SELECT id, dst_path, dst_size, substr(hash, 1, ?1), config
+     FROM files WHERE orphaned_at IS NULL";
+
+/// Replace the snapshot with the tracked files as they are now.
+pub fn take_snapshot(conn: &mut Connection) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM snapshot", [])?;
+    tx.execute(
+        &format!(
+            "INSERT INTO snapshot (id, dst_path, dst_size, content, config)
+             {CURRENT_SNAPSHOT}"
+        ),
+        params![SNAPSHOT_CONTENT_LEN],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Read the snapshot taken by `take_snapshot`, keyed by row id.
+pub fn load_snapshot(conn: &Connection) -> Result<HashMap<i64, SnapshotRow>> {
+    let sql = "SELECT id, dst_path, dst_size, content, config FROM snapshot";
+    query_snapshot(conn, sql, [])
+}
+
+/// The tracked files as `take_snapshot` would record them now.
+pub fn current_snapshot(conn: &Connection) -> Result<HashMap<i64, SnapshotRow>> {
+    query_snapshot(conn, CURRENT_SNAPSHOT, params![SNAPSHOT_CONTENT_LEN])
+}
+
+fn query_snapshot(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<HashMap<i64, SnapshotRow>> {
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt
+        .query_map(params, |r| {
+            let dst: String = r.get(1)?;
+            let dst_size: Option<i64> = r.get(2)?;
+            let row = SnapshotRow {
+                dst: PathBuf::from(dst),
+                dst_size: dst_size.map(|n| n as u64),
+                content: r.get(3)?,
+                config: r.get(4)?,
+            };
+            Ok((r.get(0)?, row))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Number of runs that skipped unchanged directories since the last full scan.
+pub fn fast_scans_since_full(conn: &Connection) -> Result<u32> {
+    let count = conn.query_row(
+        "SELECT count(*) FROM runs
+         WHERE fast_scan = 1
+           AND id > coalesce((SELECT max(id) FROM runs WHERE fast_scan = 0), 0)",
+        [],
+        |r| r.get(0),
+    )?;
+    Ok(count)
+}
+
+/// Read the source directory mtimes stored by the last --fast-scan run.
+pub fn load_dirs(conn: &Connection) -> Result<HashMap<PathBuf, Option<i64>>> {
+    let mut stmt = conn.prepare("SELECT path, mtime FROM dirs")?;
+    let dirs = stmt
+        .query_map([], |r| {
+            let path: String = r.get(0)?;
+            Ok((PathBuf::from(path), r.get(1)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(dirs)
+}
+
+/// Replace the stored source directory mtimes.
+pub fn save_dirs(
+    conn: &mut Connection,
+    dirs: &[(PathBuf, Option<i64>)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM dirs", [])?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO dirs (path, mtime) VALUES (?1, ?2)")?;
+        for (path, mtime) in dirs {
+            stmt.execute(params![path.to_string_lossy(), mtime])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// An output as it was when the --staging transfer last got it onto the device.
+/// Playlists and extracted covers have no source, their hash is empty.
+pub struct TransferredRow {
+    pub hash: String,
+    pub config: String,
+    pub dst_size: Option<u64>,
+    pub dst_mtime: Option<i64>,
+}
+
+/// Outputs on the device by path relative to the staging destination.
+pub fn load_transferred(
+    conn: &Connection,
+) -> Result<HashMap<PathBuf, TransferredRow>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, config, dst_size, dst_mtime FROM transferred",
+    )?;
+    let rows = stmt
+        .query_map([], |r| {
+            let path: String = r.get(0)?;
+            let dst_size: Option<i64> = r.get(3)?;
+            let row = TransferredRow {
+                hash: r.get(1)?,
+                config: r.get(2)?,
+                dst_size: dst_size.map(|n| n as u64),
+                dst_mtime: r.get(4)?,
+            };
+            Ok((PathBuf::from(path), row))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Record that an output is on the device, or with `None` that it was deleted
+/// from it. Called for each entry as soon as it is done, so an interrupted
+/// transfer resumes where it stopped.
+pub fn set_transferred(
+    conn: &Connection,
+    path: &Path,
+    row: Option<&TransferredRow>,
+    transferred_at: i64,
+) -> Result<()> {
+    match row {
+        Some(row) => conn.execute(
+            "INSERT OR REPLACE INTO transferred
+                (path, hash, config, dst_size, transferred_at, dst_mtime)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                path.to_string_lossy(),
+                row.hash,
+                row.config,
+                row.dst_size.map(|n| n as i64),
+                transferred_at,
+                row.dst_mtime,
+            ],
+        )?,
+        None => conn.execute(
+            "DELETE FROM transferred WHERE path = ?",
+            params![path.to_string_lossy()],
+        )?,
+    };
+    Ok(())
+}
+
+/// A source a run left without an output, and why.
+pub struct UnsyncedRow {
+    pub src: PathBuf,
+    pub reason: Reason,
+    pub detail: Option<String>,
+}
+
+/// Record the sources a run left without an output. A complete run replaces what
+/// earlier runs recorded, an aborted one didn't get to every file and only adds.
+pub fn save_unsynced(
+    conn: &mut Connection,
+    rows: &[UnsyncedRow],
+    replace: bool,
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    if replace {
+        tx.execute("DELETE FROM unsynced", [])?;
+    }
+    {
+        let mut stmt = tx.prepare(
+            "INSERT OR REPLACE INTO unsynced (src_path, reason, detail)
+             VALUES (?1, ?2, ?3)",
+        )?;
+        for row in rows {
+            let src = row.src.to_string_lossy();
+            stmt.execute(params![src, row.reason.as_db_str(), row.detail])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Read the sources recorded by `save_unsynced`. Reasons this version doesn't
+/// know are left out.
+pub fn load_unsynced(conn: &Connection) -> Result<Vec<UnsyncedRow>> {
+    let mut stmt = conn.prepare("SELECT src_path, reason, detail FROM unsynced")?;
+    let rows = stmt
+        .query_map([], |r| {
+            let src: String = r.get(0)?;
+            let reason: String = r.get(1)?;
+            Ok((PathBuf::from(src), reason, r.get(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let rows = rows
+        .into_iter()
+        .filter_map(|(src, reason, detail)| {
+            let reason = Reason::from_db_str(&reason)?;
+            Some(UnsyncedRow { src, reason, detail })
+        })
+        .collect();
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn processed(i: usize, status: FileStatus) -> ProcessedFile {
+        let info = FileInfo {
+            dst: PathBuf::from(format!("/dst/{i}.opus")),
+            hash: format!("{i:064x}"),
+            size: 30_000_000,
+            config: "opus:128k".to_string(),
+            ..Default::default()
+        };
+        ProcessedFile::new(Path::new(&format!("/src/{i}.flac")), info, status)
+    }
+
+    // cargo test --release skip_updates_at_scale -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn skip_updates_at_scale() {
+        const FILES: usize = 100_000;
+        let tmp = TempDir::new();
+        let mut conn = connect(&tmp.path().join("db")).unwrap();
+        init(&mut conn).unwrap();
+        let run = |status: fn() -> FileStatus| {
+            (0..FILES).map(move |i| processed(i, status()))
+        };
+
+        let time = Instant::now();
+        ingest_results(&mut conn, run(|| FileStatus::Transcoded), 1).unwrap();
+        let cold = time.elapsed();
+
+        // a warm run, where every file is a cache hit
+        let time = Instant::now();
+        ingest_results(&mut conn, run(|| FileStatus::Skipped), 2).unwrap();
+        let warm = time.elapsed();
+
+        // the same updates, each in a transaction of its own
+        let time = Instant::now();
+        for i in 0..FILES / 10 {
+            conn.execute(
+                "UPDATE files SET last_synced = 3 WHERE src_path = ?1",
+                params![format!("/src/{i}.flac")],
+            )
+            .unwrap();
+        }
+        let unbatched = time.elapsed() * 10;
+
+        let synced: i64 = conn
+            .query_row("SELECT count(*) FROM files WHERE last_synced = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(synced as usize, FILES - FILES / 10);
+        println!(
+            "{FILES} files: {cold:?} written, {warm:?} touched in batches, about \
+             {unbatched:?} touched one by one",
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use argh::FromArgs;
+use rusqlite::Connection;
+
+use crate::{db, stats::format_bytes};
+
+/// Show what changed in the destination since the last sync that completed before
+/// the latest one: outputs added, re-encoded, renamed or removed.
+#[derive(FromArgs, Debug, Clone)]
+pub struct DiffArgs {
+    /// path to SQLite database
+    #[argh(option, short = 'd')]
+    pub db_path: PathBuf,
+
+    /// print the changes as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Destination changes between the snapshot and the file table.
+#[derive(Default)]
+pub struct Diff {
+    pub added: Vec<Output>,
+    // the source changed, or was encoded with different settings
+    pub changed: Vec<Output>,
+    // moved outputs with their old path, e.g. reclaimed for a renamed source
+    pub renamed: Vec<(PathBuf, Output)>,
+    pub removed: Vec<Output>,
+}
+
+pub struct Output {
+    pub dst: PathBuf,
+    pub bytes: u64,
+}
+
+impl Diff {
+    /// One line for the end of a sync, e.g.
+    /// "+214 files, ~38 re-encoded, -12 removed".
+    pub fn summary(&self) -> String {
+        let mut parts = vec![
+            format!("+{} files", self.added.len()),
+            format!("~{} re-encoded", self.changed.len()),
+        ];
+        if !self.renamed.is_empty() {
+            parts.push(format!("{} renamed", self.renamed.len()));
+        }
+        parts.push(format!("-{} removed", self.removed.len()));
+        parts.join(", ")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.changed.is_empty()
+            && self.renamed.is_empty()
+            && self.removed.is_empty()
+    }
+}
+
+pub fn run(args: DiffArgs) -> Result<()> {
+    let mut conn = db::connect(&args.db_path)?;
+    db::init(&mut conn)?;
+
+    let diff = compute(&conn)?;
+    if args.json {
+        println!("{}", to_json(&diff));
+        return Ok(());
+    }
+    if diff.is_empty() {
+        println!("no changes");
+        return Ok(());
+    }
+
+    let groups = [
+        ("added", &diff.added),
+        ("re-encoded", &diff.changed),
+        ("removed", &diff.removed),
+    ];
+    for (label, outputs) in groups {
+        if outputs.is_empty() {
+            continue;
+        }
+        let bytes = outputs.iter().map(|o| o.bytes).sum::<u64>();
+        let size = format_bytes(bytes as i64);
+        println!("{label} ({} files, {size}):", outputs.len());
+        for output in outputs {
+            println!("  {}", output.dst.display());
+        }
+    }
+    if !diff.renamed.is_empty() {
+        println!("renamed ({} files):", diff.renamed.len());
+        for (from, output) in &diff.renamed {
+            println!("  {} -> {}", from.display(), output.dst.display());
+        }
+    }
+    println!("{}", diff.summary());
+    Ok(())
+}
+
+/// Compare the file table against the snapshot, which is taken when a run starts
+/// after a completed one.
+pub fn compute(conn: &Connection) -> Result<Diff> {
+    let mut before = db::load_snapshot(conn)?;
+    let mut diff = Diff::default();
+
+    // sources new since the snapshot, by content, to pair up with removed ones
+    let mut new: HashMap<String, Vec<Output>> = HashMap::new();
+    let mut current: Vec<_> = db::current_snapshot(conn)?.into_iter().collect();
+    current.sort_by(|a, b| a.1.dst.cmp(&b.1.dst));
+    for (id, info) in current {
+        let content = info.content;
+        let output = Output { dst: info.dst, bytes: info.dst_size.unwrap_or(0) };
+        match before.remove(&id) {
+            None => new.entry(content).or_default().push(output),
+            Some(old) if old.content != content || old.config != info.config => {
+                diff.changed.push(output);
+            }
+            Some(old) if old.dst != output.dst => {
+                diff.renamed.push((old.dst, output));
+            }
+            Some(old) if old.dst_size.is_some_and(|n| Some(n) != info.dst_size) => {
+                diff.changed.push(output);
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut gone: Vec<_> = before.into_values().collect();
+    gone.sort_by(|a, b| a.dst.cmp(&b.dst));
+    for old in gone {
+        let moved = new.get_mut(&old.content).and_then(|outputs| outputs.pop());
+        match moved {
+            Some(output) => diff.renamed.push((old.dst, output)),
+            None => diff.removed.push(Output {
+                dst: old.dst,
+                bytes: old.dst_size.unwrap_or(0),
+            }),
+        }
+    }
+    let mut added: Vec<Output> =
+        new.into_values().flatten().collect();
+    added.sort_by(|a, b| a.dst.cmp(&b.dst));
+    diff.added = added;
+    diff.renamed.sort_by(|a, b| a.1.dst.cmp(&b.1.dst));
+    Ok(diff)
+}
+
+fn to_json(diff: &Diff) -> String {
+    let outputs = |outputs: &[Output]| {
+        let items: Vec<String> = outputs
+            .iter()
+            .map(|o| {
+                format!(
+                    "{{\"path\":{},\"bytes\":{}}}",
+                    json_string(&o.dst.to_string_lossy()),
+                    o.bytes,
+                )
+            })
+            .collect();
+        format!("[{}]", items.join(","))
+    };
+    let renamed: Vec<String> = diff
+        .renamed
+        .iter()
+        .map(|(from, o)| {
+            format!(
+                "{{\"from\":{},\"path\":{},\"bytes\":{}}}",
+                json_string(&from.to_string_lossy()),
+                json_string(&o.dst.to_string_lossy()),
+                o.bytes,
+            )
+        })
+        .collect();
+    format!(
+        "{{\"added\":{},\"changed\":{},\"renamed\":[{}],\"removed\":{}}}",
+        outputs(&diff.added),
+        outputs(&diff.changed),
+        renamed.join(","),
+        outputs(&diff.removed),
+    )
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
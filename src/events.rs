@@ -0,0 +1,331 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    WorkStats, sniff,
+    stats::format_bytes,
+    warnings::{self, group_thousands},
+    worker::{FileStatus, ProcessedFile, ReprocessReason},
+};
+
+/// Something that happened during a sync, for everything reporting on it.
+pub enum Event<'a> {
+    /// A source was processed, including cache hits and conflicts. `bytes` is the
+    /// size of the output if one was written.
+    FileFinished {
+        file: &'a ProcessedFile,
+        bytes: u64,
+        duration: Duration,
+    },
+    FileFailed {
+        src: &'a Path,
+        error: &'a anyhow::Error,
+    },
+    /// How far the encoding of a large source got.
+    Progress {
+        src: &'a Path,
+        percent: f32,
+    },
+    OrphanRemoved {
+        dst: &'a Path,
+        trashed: bool,
+    },
+    /// An orphan couldn't be deleted, or moved to the trash if `trashing`. It is
+    /// kept in the database for the next run to try again.
+    OrphanFailed {
+        dst: &'a Path,
+        trashing: bool,
+        error: &'a anyhow::Error,
+    },
+    /// The run ended, either completely or aborted.
+    RunFinished {
+        stats: &'a WorkStats,
+        started: Instant,
+    },
+}
+
+/// Reacts to events. Called on the thread that publishes them, so subscribers
+/// shouldn't block for long.
+pub trait Subscriber {
+    fn on_event(&self, event: &Event);
+}
+
+/// Hands every event to each subscriber in turn, so they all see the same events
+/// in the same order. Nothing is queued: a slow subscriber slows down the receiver,
+/// which slows down the workers through the bounded result channel.
+pub struct EventBus<'a> {
+    subscribers: Vec<&'a dyn Subscriber>,
+}
+
+impl<'a> EventBus<'a> {
+    pub fn new(subscribers: Vec<&'a dyn Subscriber>) -> Self {
+        EventBus { subscribers }
+    }
+
+    pub fn publish(&self, event: Event) {
+        for subscriber in &self.subscribers {
+            subscriber.on_event(&event);
+        }
+    }
+}
+
+/// Writes events to the log.
+pub struct Logger {
+    pub paranoid: bool,
+    pub reencode_config: bool,
+    pub max_reencodes: bool,
+}
+
+impl Subscriber for Logger {
+    fn on_event(&self, event: &Event) {
+        match *event {
+            Event::FileFinished {
+                file,
+                bytes,
+                duration,
+            } => {
+                file_finished(file, bytes, duration);
+            }
+            Event::FileFailed { src, error } => {
+                log::error!("failed to process {}: {error}", src.display());
+            }
+            Event::Progress { src, percent } => {
+                log::info!("encoding {} ({percent}%)", src.display());
+            }
+            Event::OrphanRemoved { dst, trashed: true } => {
+                log::info!("moved orphan {} to trash", dst.display());
+            }
+            Event::OrphanRemoved {
+                dst,
+                trashed: false,
+            } => {
+                log::info!("removed orphan {}", dst.display());
+            }
+            Event::OrphanFailed { dst, trashing, error } => {
+                let (kind, verb) = match trashing {
+                    true => ("failed to trash orphan", "trash"),
+                    false => ("failed to remove orphan", "remove"),
+                };
+                let dst = dst.display();
+                let message = format_args!("failed to {verb} orphan {dst}: {error}");
+                warnings::warn(kind, message);
+            }
+            Event::RunFinished { stats, .. } if stats.aborted.is_some() => {
+                log::error!(
+                    "processed {}/{} files successfully ({} cached) before aborting",
+                    stats.successes,
+                    stats.successes + stats.fails,
+                    stats.skips,
+                );
+                if stats.not_started > 0 {
+                    log::error!("{} files were not started", stats.not_started);
+                }
+                warnings::log_suppressed();
+            }
+            Event::RunFinished { stats, started } => {
+                self.run_finished(stats, started)
+            }
+        }
+    }
+}
+
+fn file_finished(file: &ProcessedFile, bytes: u64, duration: Duration) {
+    if let Some(detected) = &file.info.detected_type
+        && let Some(detected) = sniff::type_mismatch(&file.src, detected)
+    {
+        log::warn!(
+            "{} is actually {detected}, not what its extension says",
+            file.src.display(),
+        );
+    }
+    if !file.tag_issues.is_empty() {
+        log::warn!(
+            "tags of {} differ from the source: {}",
+            file.info.dst.display(),
+            file.tag_issues.join(", "),
+        );
+    }
+    let verb = match file.status {
+        FileStatus::PassedThrough => "passed through",
+        FileStatus::Transcoded => "transcoded",
+        FileStatus::Reclaimed => "reclaimed",
+        FileStatus::Skipped => {
+            log::trace!("skipped {}", file.src.display());
+            return;
+        }
+        FileStatus::Conflict => {
+            warnings::warn(
+                "destination already exists",
+                format_args!(
+                    "not writing {}, {} already exists",
+                    file.src.display(),
+                    file.info.dst.display(),
+                ),
+            );
+            return;
+        }
+    };
+    match file.reason {
+        Some(reason) => log::info!("{verb} {} ({reason})", file.src.display()),
+        None => log::info!("{verb} {}", file.src.display()),
+    }
+    log::debug!(
+        "{verb} {} in {:.2} seconds, {} written",
+        file.src.display(),
+        duration.as_secs_f32(),
+        format_bytes(bytes as i64),
+    );
+}
+
+impl Logger {
+    fn run_finished(&self, stats: &WorkStats, started: Instant) {
+        log::info!(
+            "operation took {:.2} seconds",
+            started.elapsed().as_secs_f32()
+        );
+        if let Some(first) = stats.first_output {
+            log::info!(
+                "first output was produced after {:.2} seconds",
+                (first - started).as_secs_f32(),
+            );
+        }
+        log::info!(
+            "processed {}/{} files successfully ({} cached)",
+            stats.successes,
+            stats.successes + stats.fails,
+            stats.skips,
+        );
+        if stats.conflicts > 0 {
+            log::warn!(
+                "{} files were not written because their destination already exists",
+                stats.conflicts,
+            );
+        }
+        if stats.repaired > 0 {
+            log::warn!(
+                "{} outputs were empty or truncated and were produced again",
+                stats.repaired,
+            );
+        }
+        if let Some(reasons) = describe_reasons(&stats.reasons) {
+            log::info!("produced because: {reasons}");
+        }
+        if self.max_reencodes {
+            log::info!("{}", reencode_progress(stats));
+        }
+        if self.reencode_config {
+            let forced = stats.reasons.get(&ReprocessReason::Forced).copied();
+            let forced = forced.unwrap_or(0);
+            let produced = stats.transcoded + stats.passed_through;
+            let other = produced.saturating_sub(forced);
+            log::info!(
+                "{forced} files were redone for --reencode-config, {other} for other \
+                 reasons",
+            );
+        }
+        if stats.orphans_failed > 0 {
+            log::warn!(
+                "{} orphans couldn't be removed and are kept in the database, to \
+                 retry next run",
+                stats.orphans_failed,
+            );
+        }
+        if stats.reclaims_rejected > 0 {
+            log::warn!(
+                "{} orphans failed --verify-reclaim and were processed again",
+                stats.reclaims_rejected,
+            );
+        }
+        if !stats.type_mismatches.is_empty() {
+            log::warn!(
+                "{} files have an extension that doesn't match their content:",
+                stats.type_mismatches.len(),
+            );
+            for (src, detected) in &stats.type_mismatches {
+                log::warn!("  {} (detected {detected})", src.display());
+            }
+        }
+        if !stats.tag_issues.is_empty() {
+            log::warn!(
+                "{} transcoded files lost or changed tags:",
+                stats.tag_issues.len(),
+            );
+            for (src, issues) in &stats.tag_issues {
+                log::warn!("  {}: {}", src.display(), issues.join(", "));
+            }
+        }
+        warnings::log_suppressed();
+        if self.paranoid {
+            let level = if stats.contradicted > 0 {
+                log::Level::Warn
+            } else {
+                log::Level::Info
+            };
+            log::log!(
+                level,
+                "{} cache entries contradicted by hash",
+                stats.contradicted,
+            );
+        }
+    }
+}
+
+/// Output counts by reason, e.g. "2,990 config changed, 10 new". None if nothing
+/// was produced.
+pub fn describe_reasons(
+    reasons: &BTreeMap<ReprocessReason, usize>,
+) -> Option<String> {
+    let mut counts: Vec<_> = reasons.iter().collect();
+    // most common first, ties in declaration order
+    counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let parts: Vec<String> = counts
+        .into_iter()
+        .map(|(reason, n)| format!("{} {reason}", group_thousands(*n)))
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// How far --max-reencodes got with the files whose settings changed, e.g.
+/// "re-encoded 500 of 41,200 stale files; 40,700 remaining".
+pub fn reencode_progress(stats: &WorkStats) -> String {
+    let done = stats.reasons.get(&ReprocessReason::ConfigChanged).copied();
+    let done = done.unwrap_or(0);
+    format!(
+        "re-encoded {} of {} stale files; {} remaining",
+        group_thousands(done),
+        group_thousands(done + stats.reencodes_deferred),
+        group_thousands(stats.reencodes_deferred),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    struct Recorder(&'static str, &'static Mutex<Vec<String>>);
+
+    impl Subscriber for Recorder {
+        fn on_event(&self, event: &Event) {
+            let Event::Progress { src, .. } = event else { return };
+            let line = format!("{} {}", self.0, src.display());
+            self.1.lock().unwrap().push(line);
+        }
+    }
+
+    #[test]
+    fn every_subscriber_sees_each_event_in_turn() {
+        static SEEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        let (first, second) = (Recorder("first", &SEEN), Recorder("second", &SEEN));
+        let bus = EventBus::new(vec![&first, &second]);
+        for src in ["a", "b"] {
+            bus.publish(Event::Progress { src: Path::new(src), percent: 0.0 });
+        }
+        let order = ["first a", "second a", "first b", "second b"];
+        assert_eq!(*SEEN.lock().unwrap(), order);
+    }
+}
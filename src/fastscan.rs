@@ -0,0 +1,61 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::worker::FileCache;
+
+/// Source directories as they were at the end of the last --fast-scan run, so
+/// directories whose mtime hasn't changed can be listed without being read.
+pub struct DirIndex {
+    mtimes: HashMap<PathBuf, Option<i64>>,
+    subdirs: HashMap<PathBuf, Vec<PathBuf>>,
+    files: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl DirIndex {
+    pub fn new(mtimes: HashMap<PathBuf, Option<i64>>, cache: &FileCache) -> Self {
+        let mut subdirs = HashMap::<PathBuf, Vec<PathBuf>>::new();
+        for dir in mtimes.keys() {
+            if let Some(parent) = dir.parent() {
+                subdirs.entry(parent.to_path_buf()).or_default().push(dir.clone());
+            }
+        }
+        let mut files = HashMap::<PathBuf, Vec<PathBuf>>::new();
+        for (src, info) in cache {
+            // orphans in their grace period are still cached, but don't exist
+            if info.orphaned_at.is_some() {
+                continue;
+            }
+            if let Some(parent) = src.parent() {
+                files.entry(parent.to_path_buf()).or_default().push(src.clone());
+            }
+        }
+        DirIndex { mtimes, subdirs, files }
+    }
+
+    /// The cached files and subdirectories of a directory, if its mtime is the
+    /// one stored. a directory's mtime only changes when entries are added,
+    /// removed or renamed, so edits to the files themselves go unnoticed.
+    pub fn unchanged(
+        &self,
+        dir: &Path,
+        mtime: Option<i64>,
+    ) -> Option<(&[PathBuf], &[PathBuf])> {
+        let stored = (*self.mtimes.get(dir)?)?;
+        if mtime != Some(stored) {
+            return None;
+        }
+        let files = self.files.get(dir).map_or(&[][..], |f| f.as_slice());
+        let subdirs = self.subdirs.get(dir).map_or(&[][..], |d| d.as_slice());
+        Some((files, subdirs))
+    }
+}
+
+/// Modification time of a directory in nanoseconds, None if it can't be read.
+pub fn dir_mtime(dir: &Path) -> Option<i64> {
+    let mtime = fs::metadata(dir).ok()?.modified().ok()?;
+    Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_nanos() as i64)
+}
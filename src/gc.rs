@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use argh::FromArgs;
+use rusqlite::Connection;
+
+use crate::db;
+
+/// Remove database rows whose source and destination no longer exist. relative
+/// paths are resolved against the current directory, like during a sync.
+#[derive(FromArgs, Debug, Clone)]
+pub struct GcArgs {
+    /// path to SQLite database
+    #[argh(option, short = 'd')]
+    pub db_path: PathBuf,
+
+    /// list the rows that would be removed without removing them
+    #[argh(switch)]
+    pub dry_run: bool,
+}
+
+pub fn run(args: GcArgs) -> Result<()> {
+    let mut conn = db::connect(&args.db_path)?;
+    db::init(&mut conn)?;
+
+    let (checked, stale) = find_stale(&conn, None)?;
+    if args.dry_run {
+        for src in &stale {
+            println!("{}", src.display());
+        }
+        println!("{} of {checked} rows would be removed", stale.len());
+        return Ok(());
+    }
+
+    remove(&mut conn, &stale)?;
+    println!("removed {} of {checked} rows", stale.len());
+    Ok(())
+}
+
+/// Find rows whose destination is gone and whose source is gone too, returning
+/// the number of rows checked and the sources of the stale ones. With
+/// `not_synced_since`, rows synced since then are skipped without a stat.
+pub fn find_stale(
+    conn: &Connection,
+    not_synced_since: Option<i64>,
+) -> Result<(usize, Vec<PathBuf>)> {
+    let paths = db::load_paths(conn, not_synced_since)?;
+    let checked = paths.len();
+    // symlink_metadata, so dangling passthrough links still count as present
+    let stale = paths
+        .into_iter()
+        .filter(|(src, dst)| dst.symlink_metadata().is_err() && !src.exists())
+        .map(|(src, _)| src)
+        .collect();
+    Ok((checked, stale))
+}
+
+/// Delete rows in batches, so a large gc doesn't hold one long write transaction.
+pub fn remove(conn: &mut Connection, stale: &[PathBuf]) -> Result<()> {
+    const BATCH_SIZE: usize = 1000;
+    for batch in stale.chunks(BATCH_SIZE) {
+        db::prune(conn, batch.iter())?;
+    }
+    Ok(())
+}
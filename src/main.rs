@@ -1,33 +1,99 @@
+mod art;
+mod breaker;
+mod completions;
 mod db;
+mod diff;
+mod events;
+mod fastscan;
+mod gc;
+mod metrics;
+mod missing;
+mod notify;
+mod output;
+mod playlists;
+mod probe;
+mod renames;
+mod rescan;
+mod settings;
+mod sniff;
+mod stats;
+mod tags;
+mod template;
+#[cfg(test)]
+mod testutil;
+mod transfer;
+mod trash;
 mod util;
+mod warnings;
 mod worker;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fs,
+    io::IsTerminal,
     path::{Path, PathBuf},
     process::Command,
-    sync::Arc,
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::{ensure, Context, Result};
-use argh::FromArgs;
+use anyhow::{bail, ensure, Context, Result};
+use argh::{EarlyExit, FromArgs};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rusqlite::Connection;
 use walkdir::WalkDir;
 
 use crate::{
-    util::{has_extension, is_dotfile, map_src_to_dst},
-    worker::{FileCache, FileStatus, OrphanCache, WorkerSettings},
+    art::ArtExtractor,
+    breaker::CircuitBreaker,
+    completions::CompletionsArgs,
+    db::UnsyncedRow,
+    diff::DiffArgs,
+    events::{Event, EventBus, Logger, Subscriber},
+    fastscan::{dir_mtime, DirIndex},
+    gc::GcArgs,
+    missing::{MissingArgs, Reason},
+    output::Pretty,
+    rescan::RescanArgs,
+    settings::{Change, Settings},
+    stats::StatsArgs,
+    tags::{TagSample, TagVerifier},
+    template::{DstClaims, PathTemplate},
+    transfer::Transfer,
+    trash::Trash,
+    util::{
+        bitrate_range, canonicalize_missing, free_space, has_extension, is_dotfile,
+        is_lossless_format, is_transcodable, map_src_to_dst, under_passthrough_marker,
+        unix_now, PASSTHROUGH_MARKER, BitrateRule, ByteSize, HumanDuration, LinkMode,
+        LOSSLESS_FORMATS,
+    },
+    worker::{
+        DstDirs, Encoding, FileCache, FileInfo, FileStatus, Orphan, OrphanCache,
+        ProbeCache, ReprocessReason, WorkerSettings, CHAPTER_SOURCES,
+    },
+    warnings::group_thousands,
 };
 
 /**
 Creates a lossy mirror of your lossless music collection.
 - To force a full rebuild, delete the destination directory and database file.
 - Symlinks in the input directory will be ignored.
-- All files that are not transcoded or ignored will be passed through (hardlinked or copied, depending on the --copy flag)
+- All files that are not transcoded or ignored will be passed through (hardlinked, symlinked or copied, depending on --link-mode)
+- With --strict, only --allowed and --passthrough extensions are synced.
+- A `.sidechain-passthrough` file passes its whole directory tree through as is.
+- Devices that can only be copied to (e.g. over MTP) are synced through --staging.
 - Non-UTF8 file names or paths are not supported.
 - Unexpected behaviour will occur on certain filesystems if your source folder contains name collisions in different cases (e.g. Song.flac vs song.flac). This scenario is NOT SUPPORTED.
+- Run `sidechain stats --help` for database statistics.
+- Run `sidechain gc --help` to drop database rows for files that are gone.
+- Run `sidechain rescan-hashes --help` to find sources that changed silently.
+- Run `sidechain diff --help` to see what the last sync changed.
+- Run `sidechain missing --help` to list sources that have no output.
+- Run `sidechain completions --help` to set up shell completions.
  */
 #[derive(FromArgs, Debug, Clone)]
 struct Args {
@@ -39,6 +105,10 @@ struct Args {
     #[argh(option, short = 'o')]
     destination: PathBuf,
 
+    /// create the destination directory and its parents if they don't exist
+    #[argh(switch)]
+    create_destination: bool,
+
     /// path to SQLite database (created if missing)
     #[argh(option, short = 'd')]
     db_path: PathBuf,
@@ -51,6 +121,15 @@ struct Args {
     #[argh(option, short = 'x', long = "ignored")]
     ignored_exts: Vec<String>,
 
+    /// only sync files with an --allowed or --passthrough extension, ignoring
+    /// everything else. conflicts with --ignored
+    #[argh(switch)]
+    strict: bool,
+
+    /// with --strict, file extensions to pass through (can provide multiple)
+    #[argh(option, long = "passthrough")]
+    passthrough_exts: Vec<String>,
+
     /// ignore dotfiles in the source directory
     #[argh(switch, short = 'H', long = "ignore-dotfiles")]
     ignore_dotfiles: bool,
@@ -59,61 +138,922 @@ struct Args {
     #[argh(option, short = 'f')]
     format: String,
 
-    /// bitrate of transcoded output files (in kbps)
+    /// classify files without an extension by their content, so they can be
+    /// transcoded if they match an allowed extension. otherwise they are always
+    /// passed through
+    #[argh(switch)]
+    sniff_extensionless: bool,
+
+    /// decide whether to transcode by each file's real format instead of its
+    /// extension, warning about files whose content doesn't match their extension
+    /// and lossy sources being transcoded. results are cached. may use ffprobe
+    #[argh(switch)]
+    detect_type: bool,
+
+    /// lowercase the extension of every output file (e.g. Cover.JPG becomes
+    /// Cover.jpg)
+    #[argh(switch)]
+    lowercase_extensions: bool,
+
+    /// replace characters FAT, exFAT and NTFS reject (e.g. : or ?) and strip
+    /// trailing dots and spaces in output file and directory names
+    #[argh(switch)]
+    sanitize_names: bool,
+
+    /// bitrate of transcoded output files (in kbps). required unless the format
+    /// is lossless (flac, wav, aiff, wv, tta)
     #[argh(option, short = 'b')]
-    bitrate: u32,
+    bitrate: Option<u32>,
+
+    /// bitrate per source channel (in kbps), overriding --bitrate. requires
+    /// ffprobe
+    #[argh(option)]
+    bitrate_per_channel: Option<u32>,
+
+    /// bitrate for sources with an exact channel count, overriding --bitrate and
+    /// --bitrate-per-channel (e.g. channels=6:256, can provide multiple). requires
+    /// ffprobe
+    #[argh(option, long = "bitrate-rule")]
+    bitrate_rules: Vec<BitrateRule>,
+
+    /// allow bitrates outside the range the format's codec handles well (e.g. 600
+    /// for opus)
+    #[argh(switch)]
+    allow_weird_bitrate: bool,
+
+    /// downmix sources with more than this many channels (e.g. 2 for stereo).
+    /// sources with fewer channels are never upmixed. requires ffprobe
+    #[argh(option)]
+    channels: Option<u32>,
+
+    /// downsample sources above this sample rate (e.g. 44100). lossless formats
+    /// only, requires ffprobe
+    #[argh(option)]
+    max_sample_rate: Option<u32>,
+
+    /// reduce sources above this bit depth (16 or 24). lossless formats only,
+    /// requires ffprobe
+    #[argh(option)]
+    bit_depth: Option<u32>,
 
     /// maximum number of threads to use (default=max(CORES - 1, 1))
     #[argh(option, short = 't')]
     max_threads: Option<usize>,
 
-    /// copy passed-through files instead of hardlinking. turn this on
-    /// if the filesystem your destination directory is on doesn't support
-    /// hardlinks (e.g. FAT32), or if your source and destination folders
-    /// are on different filesystems
+    /// treat source mtimes within this many seconds of the cached mtime as
+    /// unchanged, for filesystems with coarse timestamps like FAT/exFAT (default=0)
+    #[argh(option, default = "0")]
+    mtime_window: u64,
+
+    /// hash every source file even on cache hits, reprocessing files whose
+    /// content changed without changing mtime or size. this reads the entire
+    /// source directory and will be slow
+    #[argh(switch)]
+    paranoid: bool,
+
+    /// only hash the first and last 16 MiB and the length of files larger than
+    /// this (e.g. 1G), which is enough to detect renames. such hashes are never
+    /// compared against full ones, see rescan-hashes --upgrade-partial
+    #[argh(option)]
+    fast_hash: Option<ByteSize>,
+
+    /// re-encode files whose recorded config is this (e.g. opus:96, can provide
+    /// multiple) even if nothing else changed. the new outputs usually get the
+    /// same config, so this applies to every run it is passed to
+    #[argh(option, long = "reencode-config")]
+    reencode_configs: Vec<String>,
+
+    /// re-encode at most N files whose settings changed per run, in scan order.
+    /// the rest keep their old outputs for later runs to continue with. new and
+    /// modified sources aren't limited
+    #[argh(option)]
+    max_reencodes: Option<usize>,
+
+    /// check that an orphan is still intact before reclaiming it for a renamed
+    /// source, transcoding anew if it isn't. requires ffprobe
+    #[argh(switch)]
+    verify_reclaim: bool,
+
+    /// abort the run after the first failed file
+    #[argh(switch)]
+    fail_fast: bool,
+
+    /// abort the run after this many consecutive failed files (default=25)
+    #[argh(option, default = "25")]
+    max_consecutive_failures: usize,
+
+    /// abort the run when more than this percentage of the last 100 files
+    /// failed (default=50)
+    #[argh(option, default = "50")]
+    max_failure_rate: u32,
+
+    /// never abort the run because of failed files
+    #[argh(switch)]
+    keep_going: bool,
+
+    /// disable the colored per-file output on stdout, leaving only the log on
+    /// stderr
+    #[argh(switch)]
+    porcelain: bool,
+
+    /// start processing files while the source directory is still being
+    /// scanned. orphan cleanup still waits for the scan to finish, and renamed
+    /// source directories aren't moved in one go, their files are reclaimed one
+    /// by one
+    #[argh(switch)]
+    streaming_scan: bool,
+
+    /// don't read source directories whose mtime is unchanged since the last
+    /// run, and assume their files are unchanged too. trades accuracy for speed:
+    /// files edited in place don't change their directory's mtime, so edits are
+    /// only picked up by the next full scan. changed settings, --reencode-config,
+    /// --rebuild-destination and files marked by rescan-hashes force one
+    #[argh(switch)]
+    fast_scan: bool,
+
+    /// with --fast-scan, read every directory this run
+    #[argh(switch)]
+    full_scan: bool,
+
+    /// with --fast-scan, do a full scan every N runs, 0 to never (default=10)
+    #[argh(option, default = "10")]
+    full_scan_every: u32,
+
+    /// move orphaned outputs into a trash directory instead of deleting them.
+    /// trashed files are restored instead of re-encoded if their source returns
+    #[argh(switch)]
+    trash: bool,
+
+    /// trash directory, implies --trash (default=<destination>/.sidechain-trash)
+    #[argh(option)]
+    trash_dir: Option<PathBuf>,
+
+    /// how long trashed files are kept before being deleted (default=30d)
+    #[argh(option, default = "\"30d\".parse().unwrap()")]
+    trash_retention: HumanDuration,
+
+    /// keep the outputs of files missing from the source for this long before
+    /// deleting them (e.g. 30d). a source that reappears in time is restored
+    /// without any work
+    #[argh(option)]
+    orphan_grace: Option<HumanDuration>,
+
+    /// write an album.m3u8 playlist of the audio files in each destination
+    /// directory, in name order
+    #[argh(switch)]
+    generate_playlists: bool,
+
+    /// after the run, remove database rows that weren't synced and whose source
+    /// and destination are both gone (like the gc command)
+    #[argh(switch)]
+    auto_gc: bool,
+
+    /// never delete or move anything in the destination, only add files and
+    /// overwrite sidechain's own outputs. everything that would have been
+    /// deleted is listed at the end of the run
+    #[argh(switch)]
+    no_delete: bool,
+
+    /// regenerate every output after the destination was emptied or replaced
+    /// (e.g. a reformatted card), without removing or reclaiming old outputs.
+    /// unchanged sources aren't hashed again
+    #[argh(switch)]
+    rebuild_destination: bool,
+
+    /// stop starting new files once free space on the destination drops below
+    /// this size (e.g. 20G), letting files in progress finish
+    #[argh(option)]
+    min_free: Option<ByteSize>,
+
+    /// don't encode a test tone before the run to check the format and encoder
+    /// options, for ffmpeg builds without the lavfi input device
+    #[argh(switch)]
+    skip_preflight: bool,
+
+    /// tag transcoded files with the hash and path of their source and the
+    /// settings used (SIDECHAIN_SRC_HASH, SIDECHAIN_SRC_PATH, SIDECHAIN_CONFIG).
+    /// toggling this re-encodes everything
+    #[argh(switch)]
+    embed_provenance: bool,
+
+    /// compare the tags of this many transcoded files per run (or all) against
+    /// their source, warning about lost or changed tags. requires ffprobe
+    #[argh(option)]
+    verify_tags: Option<TagSample>,
+
+    /// tag compared by --verify-tags (can provide multiple, default=title, artist,
+    /// album, track, disc, date)
+    #[argh(option, long = "verify-tag-key")]
+    verify_tag_keys: Vec<String>,
+
+    /// place transcoded files by their tags instead of mirroring the source, e.g.
+    /// "{albumartist}/{album}/{track:02} {title}". files missing a tag keep their
+    /// mirrored path, passed-through files always do. requires ffprobe
+    #[argh(option)]
+    path_template: Option<PathTemplate>,
+
+    /// extract the embedded cover of a transcoded file to cover.jpg in each
+    /// destination directory without one, unless its source directory has
+    /// images of its own
+    #[argh(switch)]
+    extract_art: bool,
+
+    /// treat the destination as a local staging copy of a device that can't be
+    /// synced to directly (e.g. over MTP). after each sync, what changed since the
+    /// last transfer to the device is written to a manifest, and applied by
+    /// --transfer-command if given
+    #[argh(switch)]
+    staging: bool,
+
+    /// with --staging, where to write the manifest of "add", "update" and
+    /// "delete" lines (default=the database path with a .transfer extension)
+    #[argh(option)]
+    transfer_manifest: Option<PathBuf>,
+
+    /// with --staging, command copying an added or changed output to the device,
+    /// e.g. "adb push {src} /sdcard/Music/{path}". {src} is the output in the
+    /// destination, {path} its path relative to it. not run through a shell
+    #[argh(option)]
+    transfer_command: Option<String>,
+
+    /// with --staging, command deleting an output from the device, e.g.
+    /// "adb shell rm /sdcard/Music/{path}"
+    #[argh(option)]
+    transfer_delete_command: Option<String>,
+
+    /// with --staging, record the whole manifest as transferred, after applying
+    /// it some other way
+    #[argh(switch)]
+    mark_transferred: bool,
+
+    /// write metrics about the run and the database to this file when the run
+    /// finishes, in the Prometheus text format (e.g. for node_exporter's textfile
+    /// collector). replaced atomically
+    #[argh(option)]
+    metrics_file: Option<PathBuf>,
+
+    /// don't ask for confirmation before a settings change re-transcodes most of
+    /// the library, or before filling a newly created destination the database
+    /// has outputs for. required for such runs when not attached to a terminal
+    #[argh(switch, short = 'y')]
+    yes: bool,
+
+    /// send a desktop notification when the run finishes
+    #[argh(switch)]
+    notify: bool,
+
+    /// only notify for runs that took at least this long (default=1m)
+    #[argh(option, default = "\"1m\".parse().unwrap()")]
+    notify_min_duration: HumanDuration,
+
+    /// how passed-through files are placed in the destination: hard (default),
+    /// soft for relative symlinks, or copy. use copy if the filesystem your
+    /// destination directory is on doesn't support hardlinks (e.g. FAT32), or if
+    /// your source and destination folders are on different filesystems
+    #[argh(option)]
+    link_mode: Option<LinkMode>,
+
+    /// same as --link-mode copy
     #[argh(switch, short = 'c')]
     copy: bool,
 }
 
+impl Args {
+    fn trash_dir(&self) -> Option<PathBuf> {
+        match &self.trash_dir {
+            Some(dir) => Some(dir.clone()),
+            None if self.trash => Some(self.destination.join(".sidechain-trash")),
+            None => None,
+        }
+    }
+
+    fn transcodes(&self, path: &Path) -> bool {
+        is_transcodable(path, &self.allowed_exts, self.sniff_extensionless)
+    }
+
+    // whether a source is left out of the sync: listed by --ignored, or with
+    // --strict neither transcoded nor listed by --passthrough
+    fn ignores(&self, path: &Path) -> bool {
+        if self.strict {
+            !self.transcodes(path) && !has_extension(path, &self.passthrough_exts)
+        } else {
+            has_extension(path, &self.ignored_exts)
+        }
+    }
+
+    fn link_mode(&self) -> LinkMode {
+        match self.link_mode {
+            Some(mode) => mode,
+            None if self.copy => LinkMode::Copy,
+            None => LinkMode::Hard,
+        }
+    }
+
+    // whether any option requires probing source files with ffprobe
+    fn needs_probe(&self) -> bool {
+        self.bitrate_per_channel.is_some()
+            || !self.bitrate_rules.is_empty()
+            || self.channels.is_some()
+            || self.max_sample_rate.is_some()
+            || self.bit_depth.is_some()
+            || self.verify_tags.is_some()
+            || self.detect_type
+            || self.verify_reclaim
+            || self.path_template.is_some()
+            // transcoded sources are checked for chapters
+            || self.allowed_exts.iter().any(|ext| {
+                CHAPTER_SOURCES.iter().any(|c| c.eq_ignore_ascii_case(ext))
+            })
+    }
+}
+
+enum Mode {
+    Sync(Box<Args>),
+    Stats(StatsArgs),
+    Gc(GcArgs),
+    RescanHashes(RescanArgs),
+    Diff(DiffArgs),
+    Missing(MissingArgs),
+    Completions(CompletionsArgs),
+}
+
+// argh can't have an optional subcommand without making every sync option
+// optional, so the subcommand is picked off the front of argv by hand
+fn parse_mode() -> Mode {
+    let argv: Vec<String> = std::env::args().collect();
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let cmd = argv.first().copied().unwrap_or("sidechain");
+
+    match argv.get(1).copied() {
+        Some("stats") => Mode::Stats(parse_or_exit(&[cmd, "stats"], &argv[2..])),
+        Some("gc") => Mode::Gc(parse_or_exit(&[cmd, "gc"], &argv[2..])),
+        Some("rescan-hashes") => {
+            Mode::RescanHashes(parse_or_exit(&[cmd, "rescan-hashes"], &argv[2..]))
+        }
+        Some("diff") => Mode::Diff(parse_or_exit(&[cmd, "diff"], &argv[2..])),
+        Some("missing") => {
+            Mode::Missing(parse_or_exit(&[cmd, "missing"], &argv[2..]))
+        }
+        Some("completions") => {
+            Mode::Completions(parse_or_exit(&[cmd, "completions"], &argv[2..]))
+        }
+        _ => {
+            let rest = argv.get(1..).unwrap_or_default();
+            Mode::Sync(Box::new(parse_or_exit(&[cmd], rest)))
+        }
+    }
+}
+
+// share of tracked files a settings change may re-transcode before the run asks
+// for confirmation, to catch typos like --bitrate 16 for 160
+const RETRANSCODE_CONFIRM_RATIO: f64 = 0.3;
+
+// outputs checked at startup for a destination that was emptied or replaced, and
+// how many of them have to be missing for that
+const DST_SAMPLE_SIZE: usize = 200;
+const DST_SAMPLE_MIN: usize = 10;
+const DST_MISSING_RATIO: f64 = 0.9;
+
+// everything that decides what ends up in the destination, recorded with each run
+// so the next one can tell what changed
+fn effective_settings(args: &Args) -> Settings {
+    let value = |v: Option<String>| v.unwrap_or_else(|| "none".to_string());
+    let switch = |on: bool| if on { "on" } else { "off" }.to_string();
+    let rules = args
+        .bitrate_rules
+        .iter()
+        .map(|r| format!("channels={}:{}", r.channels, r.bitrate))
+        .collect::<Vec<_>>();
+    let link_mode = match args.link_mode() {
+        LinkMode::Hard => "hard",
+        LinkMode::Soft => "soft",
+        LinkMode::Copy => "copy",
+    };
+    [
+        ("allowed", settings::list(&args.allowed_exts)),
+        ("ignored", settings::list(&args.ignored_exts)),
+        ("strict", switch(args.strict)),
+        ("passthrough", settings::list(&args.passthrough_exts)),
+        ("ignore-dotfiles", switch(args.ignore_dotfiles)),
+        ("format", args.format.to_lowercase()),
+        ("sniff-extensionless", switch(args.sniff_extensionless)),
+        ("detect-type", switch(args.detect_type)),
+        ("lowercase-extensions", switch(args.lowercase_extensions)),
+        ("sanitize-names", switch(args.sanitize_names)),
+        ("bitrate", value(args.bitrate.map(|b| b.to_string()))),
+        (
+            "bitrate-per-channel",
+            value(args.bitrate_per_channel.map(|b| b.to_string())),
+        ),
+        ("bitrate-rules", value(Some(rules.join(",")).filter(|r| !r.is_empty()))),
+        ("channels", value(args.channels.map(|c| c.to_string()))),
+        ("max-sample-rate", value(args.max_sample_rate.map(|r| r.to_string()))),
+        ("bit-depth", value(args.bit_depth.map(|d| d.to_string()))),
+        ("embed-provenance", switch(args.embed_provenance)),
+        (
+            "path-template",
+            value(args.path_template.as_ref().map(|t| t.as_str().to_string())),
+        ),
+        ("extract-art", switch(args.extract_art)),
+        ("generate-playlists", switch(args.generate_playlists)),
+        ("link-mode", link_mode.to_string()),
+        ("no-delete", switch(args.no_delete)),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect()
+}
+
+// counts the cached outputs the current settings invalidate, by old and new config,
+// from the cache alone. files whose config can't be predicted without probing
+// aren't counted
+fn predict_retranscodes<'a>(
+    args: &Args,
+    cache: &'a FileCache,
+    probes: &ProbeCache,
+) -> HashMap<(&'a str, String), usize> {
+    let orphans = OrphanCache::default();
+    let settings = worker_settings(args, None, None, None, &orphans, cache, probes);
+    let mut changes: HashMap<(&str, String), usize> = HashMap::new();
+    for (src, info) in cache {
+        if info.orphaned_at.is_some()
+            || info.config.starts_with("passthrough")
+            || !args.transcodes(src)
+        {
+            continue;
+        }
+        if let Some(config) = worker::predicted_config(&settings, Some(src), info)
+            && config != info.config
+        {
+            *changes.entry((&info.config, config)).or_default() += 1;
+        }
+    }
+    changes
+}
+
+// what a settings change does to the files tracked so far, one line per category
+fn settings_effects(
+    args: &Args,
+    cache: &FileCache,
+    changes: &[Change],
+    retranscodes: usize,
+) -> Vec<String> {
+    let (mut ignored, mut to_transcode, mut to_pass) = (0, 0, 0);
+    for (src, info) in cache.iter().filter(|(_, i)| i.orphaned_at.is_none()) {
+        let passed_through = info.config.starts_with("passthrough");
+        if args.ignores(src) {
+            ignored += 1;
+        } else if passed_through
+            && args.transcodes(src)
+            && !under_passthrough_marker(src, &args.source)
+        {
+            to_transcode += 1;
+        } else if !passed_through && !args.transcodes(src) {
+            to_pass += 1;
+        }
+    }
+
+    let mut effects = Vec::new();
+    for (n, what) in [
+        (retranscodes, "re-encoded with the new settings"),
+        (to_transcode, "transcoded instead of passed through"),
+        (to_pass, "passed through instead of transcoded"),
+        (ignored, "newly ignored, their outputs go away"),
+    ] {
+        if n > 0 {
+            effects.push(format!("{} files will be {what}", group_thousands(n)));
+        }
+    }
+    // ignored files were never tracked, so only their extensions are known
+    if let Some(change) = changes.iter().find(|c| c.key == "ignored") {
+        let old = change.old.as_deref().map(settings::parse_list);
+        let included: Vec<String> = old
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|ext| {
+                !args.ignored_exts.iter().any(|e| e.eq_ignore_ascii_case(ext))
+            })
+            .map(|ext| format!(".{ext}"))
+            .collect();
+        if !included.is_empty() {
+            effects.push(format!(
+                "{} files are no longer ignored and will be added",
+                included.join(", "),
+            ));
+        }
+    }
+    effects
+}
+
+fn log_settings_changes(run_id: i64, changes: &[Change], effects: &[String]) {
+    log::info!("settings changed since run {run_id}:");
+    for change in changes {
+        log::info!("  {change}");
+    }
+    for effect in effects {
+        log::info!("  {effect}");
+    }
+}
+
+fn confirm_mass_retranscode(
+    args: &Args,
+    cache: &FileCache,
+    retranscodes: &HashMap<(&str, String), usize>,
+    changes: &[Change],
+) -> Result<()> {
+    let tracked = cache.values().filter(|i| i.orphaned_at.is_none()).count();
+    let changed: usize = retranscodes.values().sum();
+    let changed = args.max_reencodes.map_or(changed, |max| changed.min(max));
+    if changed == 0 || changed as f64 <= tracked as f64 * RETRANSCODE_CONFIRM_RATIO {
+        return Ok(());
+    }
+
+    let ((from, to), _) = retranscodes
+        .iter()
+        .max_by_key(|(_, n)| **n)
+        .context("no config changes")?;
+    let summary = format!(
+        "this will re-transcode {} of {} files: config changing from {from} to {to}",
+        group_thousands(changed),
+        group_thousands(tracked),
+    );
+    let details: Vec<String> = changes.iter().map(Change::to_string).collect();
+    confirm(args, &summary, &details)
+}
+
+// a destination that was just created holds none of the outputs the database
+// knows about, which usually means it is the wrong directory, e.g. an unmounted
+// card's mount point
+fn confirm_empty_destination(args: &Args, cache: &FileCache) -> Result<()> {
+    let tracked = cache.values().filter(|i| i.orphaned_at.is_none()).count();
+    if tracked == 0 {
+        return Ok(());
+    }
+    let summary = format!(
+        "the destination was just created, but the database tracks {} outputs \
+         in it. they will all be produced again",
+        group_thousands(tracked),
+    );
+    let details = [format!("destination: {}", args.destination.display())];
+    confirm(args, &summary, &details)
+}
+
+// warns if most of a sample of the outputs are missing, e.g. after the card was
+// reformatted. the cache's iteration order differs between runs, so different
+// outputs are sampled each time
+fn diagnose_destination(args: &Args, cache: &FileCache) {
+    let sampled: Vec<&FileInfo> = cache
+        .values()
+        .filter(|info| info.orphaned_at.is_none())
+        .take(DST_SAMPLE_SIZE)
+        .collect();
+    if sampled.len() < DST_SAMPLE_MIN {
+        return;
+    }
+    let missing = sampled.iter().filter(|info| !exists_no_follow(&info.dst)).count();
+    if (missing as f64) < sampled.len() as f64 * DST_MISSING_RATIO {
+        return;
+    }
+    log::warn!(
+        "destination appears empty or replaced; {missing} of {} sampled outputs \
+         missing",
+        sampled.len(),
+    );
+    if !args.rebuild_destination {
+        log::warn!(
+            "pass --rebuild-destination to regenerate every output without looking \
+             for the old ones",
+        );
+    }
+}
+
+// asks on the terminal whether to go on, unless --yes was passed
+fn confirm(args: &Args, summary: &str, details: &[String]) -> Result<()> {
+    if args.yes {
+        log::warn!("{summary}");
+        return Ok(());
+    }
+    ensure!(
+        std::io::stdin().is_terminal(),
+        "{summary}. pass --yes to confirm when not running interactively",
+    );
+    let bold = anstyle::Style::new().bold();
+    let mut prompt = format!("{bold}{summary}{bold:#}\n");
+    for detail in details {
+        prompt.push_str(&format!("  {detail}\n"));
+    }
+    anstream::eprint!("{prompt}continue? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => bail!("cancelled, nothing was changed"),
+    }
+}
+
+// every command with its help text, the shell completions are generated from
+// these. a subcommand added to parse_mode must be added here too
+fn command_helps() -> Vec<(&'static str, String)> {
+    use completions::help;
+    let cmd = "sidechain";
+    vec![
+        ("", help::<Args>(&[cmd])),
+        ("stats", help::<StatsArgs>(&[cmd, "stats"])),
+        ("gc", help::<GcArgs>(&[cmd, "gc"])),
+        ("rescan-hashes", help::<RescanArgs>(&[cmd, "rescan-hashes"])),
+        ("diff", help::<DiffArgs>(&[cmd, "diff"])),
+        ("missing", help::<MissingArgs>(&[cmd, "missing"])),
+        ("completions", help::<CompletionsArgs>(&[cmd, "completions"])),
+    ]
+}
+
+// same behaviour as argh::from_env
+fn parse_or_exit<T: FromArgs>(cmd: &[&str], args: &[&str]) -> T {
+    T::from_args(cmd, args).unwrap_or_else(|EarlyExit { output, status }| {
+        match status {
+            Ok(()) => {
+                println!("{output}");
+                std::process::exit(0)
+            }
+            Err(()) => {
+                let cmd = cmd.join(" ");
+                eprintln!("{output}\nRun {cmd} --help for more information.");
+                std::process::exit(1)
+            }
+        }
+    })
+}
+
+// exit status when --min-free stopped the run, distinct from failures (1)
+const EXIT_LOW_SPACE: i32 = 3;
+
 fn main() -> Result<()> {
     env_logger::init();
 
-    let args: Args = argh::from_env();
+    let args = match parse_mode() {
+        Mode::Sync(args) => *args,
+        Mode::Stats(args) => return stats::run(args),
+        Mode::Gc(args) => return gc::run(args),
+        Mode::RescanHashes(args) => return rescan::run(args),
+        Mode::Diff(args) => return diff::run(args),
+        Mode::Missing(args) => return missing::run(args),
+        Mode::Completions(args) => return completions::run(args, &command_helps()),
+    };
+
+    let notify_after = args.notify.then_some(args.notify_min_duration);
+    let metrics_file = args.metrics_file.clone();
+    let db_path = args.db_path.clone();
+    let time = Instant::now();
+    let result = sync(args, time);
+    // also for runs that failed validation or setup, so a dashboard doesn't keep
+    // showing the last success
+    if let Some(path) = &metrics_file {
+        metrics::write(path, &db_path, &result, unix_now(), time.elapsed());
+    }
+    if let Some(min) = notify_after
+        && time.elapsed().as_secs() >= min.secs()
+    {
+        notify::run_finished(&result, time.elapsed());
+    }
+
+    let stats = result?;
+    if let Some(reason) = &stats.aborted {
+        if stats.low_space {
+            // finished files are recorded, so the next run picks up where this
+            // one stopped
+            eprintln!(
+                "Error: stopped because {reason}, {} files left to process. free up \
+                 space and run again to continue",
+                stats.not_started,
+            );
+            std::process::exit(EXIT_LOW_SPACE);
+        }
+        // state is untrustworthy, so nothing was deleted or pruned
+        bail!("aborted because {reason}, skipped orphan cleanup");
+    }
+    Ok(())
+}
+
+// the most channels the encoders take, 7.1
+const MAX_ENCODER_CHANNELS: u32 = 8;
+
+// fails on bitrates the codec doesn't handle well, which ffmpeg often accepts with
+// nothing but a warning, e.g. 600 for opus. every bitrate the options can produce
+// is checked: --bitrate for mono and stereo, per-channel bitrates for every channel
+// count up to --channels, or up to what the encoders take without it
+fn check_bitrates(args: &Args) -> Result<()> {
+    let check = |bitrate: u32, channels: u32, option: &str| -> Result<()> {
+        let Some((codec, range)) = bitrate_range(&args.format, channels) else {
+            return Ok(());
+        };
+        let layout = match channels {
+            1 => "mono".to_string(),
+            2 => "stereo".to_string(),
+            n => format!("{n} channels"),
+        };
+        ensure!(
+            range.contains(&bitrate),
+            "{option} means {bitrate} kbps for {layout}, but {codec} handles {}-{} \
+             kbps. pass --allow-weird-bitrate to use it anyway",
+            range.start(),
+            range.end(),
+        );
+        Ok(())
+    };
+    if let Some(bitrate) = args.bitrate {
+        // applied unchanged to mono sources, which some codecs cap lower
+        check(bitrate, 1, "--bitrate")?;
+        check(bitrate, 2, "--bitrate")?;
+    }
+    let max_channels = args.channels.unwrap_or(MAX_ENCODER_CHANNELS);
+    if let Some(per_channel) = args.bitrate_per_channel {
+        for channels in 1..=max_channels {
+            check(per_channel * channels, channels, "--bitrate-per-channel")?;
+        }
+    }
+    for rule in &args.bitrate_rules {
+        // downmixed sources are encoded with fewer channels
+        let channels = rule.channels.min(args.channels.unwrap_or(rule.channels));
+        let option = format!("--bitrate-rule channels={}", rule.channels);
+        check(rule.bitrate, channels, &option)?;
+    }
+    Ok(())
+}
+
+// creates a missing destination if --create-destination allows it, before
+// anything else looks at it. returns whether it was created
+fn create_destination(args: &Args) -> Result<bool> {
+    if !args.create_destination || args.destination.exists() {
+        return Ok(false);
+    }
+    let source_canon = fs::canonicalize(&args.source)
+        .context("failed to canonicalize source path")?;
+    let dest_canon = canonicalize_missing(&args.destination)
+        .context("failed to canonicalize destination path")?;
+    ensure!(
+        !dest_canon.starts_with(&source_canon),
+        "refusing to create the destination inside the source directory",
+    );
+    fs::create_dir_all(&args.destination)
+        .context("failed to create destination directory")?;
+    log::info!("created destination {}", args.destination.display());
+    Ok(true)
+}
+
+// returns the resolved destination
+fn check_not_nested(source: &Path, destination: &Path) -> Result<PathBuf> {
+    // resolved, so symlinks and relative paths can't hide that they overlap
+    let source_canon =
+        fs::canonicalize(source).context("failed to canonicalize source path")?;
+    let dest_canon = fs::canonicalize(destination)
+        .context("failed to canonicalize destination path")?;
+    ensure!(
+        source_canon != dest_canon,
+        "--source and --destination must be different directories",
+    );
+    ensure!(
+        !dest_canon.starts_with(&source_canon),
+        "--destination is inside --source, every run would pick up the outputs of \
+         the last one as new sources",
+    );
+    ensure!(
+        !source_canon.starts_with(&dest_canon),
+        "--source is inside --destination, outputs could overwrite sources and \
+         orphan cleanup could delete them",
+    );
+    Ok(dest_canon)
+}
+
+fn sync(mut args: Args, time: Instant) -> Result<WorkStats> {
     ensure!(
         args.source.is_dir(),
         "--source argument must be a directory",
     );
+    let created_destination = create_destination(&args)?;
+    ensure!(
+        args.destination.exists(),
+        "--destination {} doesn't exist, pass --create-destination to create it",
+        args.destination.display(),
+    );
     ensure!(
         args.destination.is_dir(),
         "--destination argument must be a directory",
     );
+    let dest_canon = check_not_nested(&args.source, &args.destination)?;
     ensure!(
-        args.source != args.destination,
-        "--source and --destination must be different directories",
+        args.staging
+            || (args.transfer_manifest.is_none()
+                && args.transfer_command.is_none()
+                && args.transfer_delete_command.is_none()
+                && !args.mark_transferred),
+        "the transfer options only apply with --staging",
+    );
+    ensure!(
+        !args.mark_transferred
+            || (args.transfer_command.is_none()
+                && args.transfer_delete_command.is_none()),
+        "--mark-transferred conflicts with the transfer commands",
     );
     ensure!(
         !args.allowed_exts.is_empty(),
         "at least one allowed extension must be provided (e.g. -a flac)",
     );
+    ensure!(
+        !args.strict || args.ignored_exts.is_empty(),
+        "--ignored and --strict are mutually exclusive, --strict ignores everything \
+         not given by --allowed or --passthrough",
+    );
+    ensure!(
+        args.strict || args.passthrough_exts.is_empty(),
+        "--passthrough only applies with --strict",
+    );
+    ensure!(
+        !(args.fail_fast && args.keep_going),
+        "--fail-fast and --keep-going are mutually exclusive",
+    );
+    ensure!(
+        !args.copy || args.link_mode.is_none_or(|mode| mode == LinkMode::Copy),
+        "--copy conflicts with --link-mode",
+    );
     ensure!(
         args.format.chars().all(char::is_alphanumeric),
         "invalid format '{}', must be alphanumeric",
         args.format,
     );
+    if is_lossless_format(&args.format) {
+        ensure!(
+            args.bitrate.is_none()
+                && args.bitrate_per_channel.is_none()
+                && args.bitrate_rules.is_empty(),
+            "bitrate options don't apply to lossless format '{}'",
+            args.format,
+        );
+        ensure!(
+            args.bit_depth.is_none_or(|depth| depth == 16 || depth == 24),
+            "--bit-depth must be 16 or 24",
+        );
+    } else {
+        ensure!(
+            args.bitrate.is_some(),
+            "--bitrate is required unless the format is lossless ({})",
+            LOSSLESS_FORMATS.join(", "),
+        );
+        ensure!(
+            args.max_sample_rate.is_none() && args.bit_depth.is_none(),
+            "--max-sample-rate and --bit-depth only apply to lossless formats",
+        );
+        if !args.allow_weird_bitrate {
+            check_bitrates(&args)?;
+        }
+    }
 
     Command::new("ffmpeg")
         .arg("-version")
         .output()
         .context("ffmpeg not executable")?;
+    if args.needs_probe() {
+        Command::new("ffprobe")
+            .arg("-version")
+            .output()
+            .context("ffprobe not executable")?;
+    }
+    if let Some(min_free) = args.min_free {
+        let free = free_space(&args.destination)
+            .context("failed to check free space on the destination")?;
+        ensure!(
+            free >= min_free.bytes(),
+            "only {} free on the destination, less than --min-free",
+            stats::format_bytes(free as i64),
+        );
+    }
+    if !args.skip_preflight {
+        let encoding = match args.bitrate {
+            Some(bitrate) => Encoding::Lossy { bitrate },
+            None => Encoding::Lossless {
+                sample_rate: args.max_sample_rate,
+                bit_depth: args.bit_depth,
+            },
+        };
+        worker::preflight(&args.format, &encoding, args.channels)
+            .context("preflight test encode failed")?;
+    }
 
-    let time = Instant::now();
+    if args.paranoid {
+        log::warn!("paranoid mode enabled, hashing every source file will be slow");
+    }
+
+    let started_at = unix_now();
+    let pretty = Pretty::new(&args.source, args.porcelain);
 
     init_thread_pool(args.max_threads)?;
 
     let (mut conn, cache) = init_db(&args.db_path)?;
+    if created_destination {
+        confirm_empty_destination(&args, &cache)?;
+    } else {
+        diagnose_destination(&args, &cache);
+    }
 
-    let dest_canon = fs::canonicalize(&args.destination)
-        .context("failed to canonicalize destination path")?;
     let db_path_canon = fs::canonicalize(&args.db_path)
         .context("failed to canonicalize database path")?;
     ensure!(
@@ -121,37 +1061,397 @@ fn main() -> Result<()> {
         "database file cannot be located inside the destination directory",
     );
 
-    let files = find_src_files(&args, &db_path_canon)?;
-    let (orphans, to_prune) = find_orphans(&cache, &files);
+    let settings = effective_settings(&args);
+    let previous = db::previous_settings(&conn, None)?;
+    let changes = previous
+        .as_ref()
+        .map_or_else(Vec::new, |(_, old)| settings::compare(old, &settings));
+    let retranscodes = predict_retranscodes(&args, &cache, &db::load_probes(&conn)?);
+    if let Some((previous_run, _)) = previous
+        && !changes.is_empty()
+    {
+        let changed = retranscodes.values().sum();
+        let effects = settings_effects(&args, &cache, &changes, changed);
+        log_settings_changes(previous_run, &changes, &effects);
+    }
+    confirm_mass_retranscode(&args, &cache, &retranscodes, &changes)?;
+
+    // recorded before anything is touched, so crashed runs show up as incomplete
+    let argv = std::env::args().skip(1).collect::<Vec<_>>().join(" ");
+    if args.fast_scan
+        && !args.full_scan
+        && let Some(reason) = full_scan_reason(&args, &conn, &changes)?
+    {
+        log::info!("{reason}, doing a full scan");
+        args.full_scan = true;
+    }
+    let fast_scan = args.fast_scan && !args.full_scan;
+    // after an incomplete run the old snapshot is kept, so diffs still start from
+    // the last complete state of the destination
+    let last_run = db::load_runs(&conn, 1)?;
+    if last_run.first().is_none_or(|r| r.exit_status.as_deref() == Some("success")) {
+        db::take_snapshot(&mut conn)?;
+    }
+    let run_id = db::begin_run(&conn, started_at, &argv, fast_scan)?;
+    db::save_settings(&mut conn, run_id, &settings)?;
 
-    let orphans = Arc::new(orphans);
-    let dst_root = args.destination.clone(); // clone for later use cus we move args
-    let stats = spawn_workers(&mut conn, files, orphans.clone(), cache, args)?;
+    let transfer = args.staging.then(|| Transfer {
+        staging: args.destination.clone(),
+        manifest: args
+            .transfer_manifest
+            .clone()
+            .unwrap_or_else(|| args.db_path.with_extension("transfer")),
+        command: args.transfer_command.clone(),
+        delete_command: args.transfer_delete_command.clone(),
+        mark_transferred: args.mark_transferred,
+    });
+    let start = RunStart { at: started_at, time };
+    let result = run_sync(args, &mut conn, cache, db_path_canon, start, &pretty, &[]);
 
-    // cleanup
-    for candidates in orphans.values() {
-        for info in candidates {
-            // if it still exists, no worker claimed it; it is safe to delete
-            if info.dst.exists() {
-                log::info!("removing orphan {}", info.dst.display());
-                _ = std::fs::remove_file(&info.dst);
+    if result.is_ok() {
+        match diff::compute(&conn) {
+            Ok(diff) => {
+                log::info!("changes: {}", diff.summary());
+                pretty.summary(&diff.summary());
             }
+            Err(e) => log::warn!("failed to compare against the snapshot: {e:#}"),
         }
     }
-    db::prune(&mut conn, to_prune.iter())?;
-    remove_empty_dirs(&dst_root)?;
 
-    let duration = Instant::now() - time;
+    let stats = result.as_ref().ok();
+    let count = |n: fn(&WorkStats) -> usize| stats.map_or(0, |s| n(s) as i64);
+    let exit_status = match &result {
+        Ok(WorkStats { aborted: Some(reason), .. }) => format!("aborted: {reason}"),
+        Ok(_) => "success".to_string(),
+        Err(e) => format!("failed: {e:#}"),
+    };
+    let run = db::RunRecord {
+        id: run_id,
+        started_at,
+        finished_at: Some(unix_now()),
+        settings: argv,
+        transcoded: count(|s| s.transcoded),
+        passed_through: count(|s| s.passed_through),
+        reclaimed: count(|s| s.reclaimed),
+        skipped: count(|s| s.skips),
+        failed: count(|s| s.fails),
+        orphans_removed: count(|s| s.orphans_removed),
+        bytes_written: stats.map_or(0, |s| s.bytes_written as i64),
+        exit_status: Some(exit_status),
+    };
+    if let Err(e) = db::finish_run(&conn, &run) {
+        log::warn!("failed to record run history: {e}");
+    }
+    // only a complete sync leaves the staging destination ready to transfer
+    if let Some(transfer) = &transfer
+        && result.as_ref().is_ok_and(|stats| stats.aborted.is_none())
+    {
+        transfer::run(&conn, transfer)?;
+    }
 
-    log::info!("operation took {:.2} seconds", duration.as_secs_f32());
-    log::info!(
-        "processed {}/{} files successfully ({} cached)",
-        stats.successes,
-        stats.successes + stats.fails,
-        stats.skips,
-    );
+    result
+}
 
-    Ok(())
+// why --fast-scan has to read every directory this run. files in skipped
+// directories are never looked at, so whatever changes their outputs without
+// touching the source needs a full scan to reach them
+fn full_scan_reason(
+    args: &Args,
+    conn: &Connection,
+    changes: &[Change],
+) -> Result<Option<String>> {
+    if !changes.is_empty() {
+        return Ok(Some("the settings changed since the last run".to_string()));
+    }
+    if !args.reencode_configs.is_empty() {
+        return Ok(Some("--reencode-config is given".to_string()));
+    }
+    if args.rebuild_destination {
+        return Ok(Some("--rebuild-destination is given".to_string()));
+    }
+    if db::has_dirty(conn)? {
+        return Ok(Some("files are marked for reprocessing".to_string()));
+    }
+    if args.full_scan_every > 0 {
+        let fast_scans = db::fast_scans_since_full(conn)?;
+        if fast_scans + 1 >= args.full_scan_every {
+            return Ok(Some(format!("{fast_scans} runs since the last full scan")));
+        }
+    }
+    Ok(None)
+}
+
+// when the run started, as recorded in the database and for timing it
+#[derive(Clone, Copy)]
+struct RunStart {
+    at: i64,
+    time: Instant,
+}
+
+// everything after setup, so its outcome can be recorded in the runs table.
+// subscribers get the events besides the log and the pretty output
+fn run_sync(
+    args: Args,
+    conn: &mut Connection,
+    cache: FileCache,
+    db_path_canon: PathBuf,
+    start: RunStart,
+    pretty: &Pretty,
+    subscribers: &[&dyn Subscriber],
+) -> Result<WorkStats> {
+    let RunStart { at: started_at, time } = start;
+    let logger = Logger {
+        paranoid: args.paranoid,
+        reencode_config: !args.reencode_configs.is_empty(),
+        max_reencodes: args.max_reencodes.is_some(),
+    };
+    let mut all: Vec<&dyn Subscriber> = vec![&logger, pretty];
+    all.extend(subscribers);
+    let bus = EventBus::new(all);
+    let trash = match args.trash_dir() {
+        Some(dir) => Some(Trash::open(&dir, &args.destination)?),
+        None => None,
+    };
+    if let Some(trash) = &trash {
+        let source_canon = fs::canonicalize(&args.source)
+            .context("failed to canonicalize source path")?;
+        let trash_canon = fs::canonicalize(&trash.dir)
+            .context("failed to canonicalize trash path")?;
+        ensure!(
+            !trash_canon.starts_with(&source_canon),
+            "trash directory cannot be located inside the source directory",
+        );
+        ensure!(
+            !source_canon.starts_with(&trash_canon),
+            "source directory cannot be located inside the trash directory",
+        );
+    }
+    // trashed files are offered to the workers alongside this run's orphans
+    let trashed = match &trash {
+        Some(_) if args.no_delete => Vec::new(),
+        Some(trash) => {
+            trash.purge_expired(conn, args.trash_retention.secs(), started_at)?
+        }
+        None => Vec::new(),
+    };
+
+    // a full scan starts from nothing, but still records mtimes for the next run
+    let dir_index = match args.fast_scan {
+        true if args.full_scan => Some(DirIndex::new(HashMap::new(), &cache)),
+        true => Some(DirIndex::new(db::load_dirs(conn)?, &cache)),
+        false => None,
+    };
+
+    let mut cache = Arc::new(cache);
+    let probes = Arc::new(db::load_probes(conn)?);
+    let dirs = DstDirs::default();
+    let (orphan_tx, orphans) = std::sync::mpsc::channel();
+    // clone for later use cus the worker thread takes ownership of args
+    let (mut stats, dir_files, scanned_dirs) = if args.streaming_scan {
+        use rayon::iter::ParallelBridge;
+
+        // workers already go through the cache while the walk runs, so there is
+        // no point at which directories could be moved underneath them
+        if !args.no_delete && args.path_template.is_none() {
+            log::info!(
+                "--streaming-scan doesn't detect renamed directories, their files \
+                 are reclaimed one by one",
+            );
+        }
+
+        let (job_tx, job_rx) = std::sync::mpsc::channel();
+        let scan = {
+            let (args, cache, probes) = (args.clone(), cache.clone(), probes.clone());
+            let trashed = trashed.clone();
+            std::thread::spawn(move || {
+                streaming_scan(
+                    &args,
+                    &db_path_canon,
+                    dir_index.as_ref(),
+                    (&cache, &probes),
+                    &trashed,
+                    (job_tx, orphan_tx),
+                )
+            })
+        };
+        // the workers create destination directories as the walk finds them
+        let files = job_rx.into_iter().par_bridge();
+        let work = Work { files, orphans, trash: trash.clone(), probes };
+        let (cache, args) = (cache.clone(), args.clone());
+        let mut stats =
+            spawn_workers(conn, work, cache, args, dirs, started_at, &bus)?;
+        let (dir_files, scanned_dirs, collisions) =
+            scan.join().expect("scan thread panicked")?;
+        stats.unsynced.extend(collisions);
+        (stats, dir_files, scanned_dirs)
+    } else {
+        use rayon::prelude::*;
+
+        let SrcFiles { files, unchanged, passthrough, scanned_dirs, collisions } =
+            find_src_files(&args, &db_path_canon, dir_index.as_ref())?;
+        // rendered outputs aren't laid out like the source, so moving a renamed
+        // source directory's outputs to its mirrored path would scatter them
+        if !args.no_delete && args.path_template.is_none() {
+            // nothing else holds the cache yet, so this doesn't clone it
+            let moved = renames::move_renamed_dirs(
+                conn,
+                Arc::make_mut(&mut cache),
+                &files,
+                |src| {
+                    map_src_to_dst(
+                        src,
+                        &args.source,
+                        &args.destination,
+                        &args.format,
+                        args.transcodes(src) && !passthrough.contains(src),
+                        args.lowercase_extensions,
+                        args.sanitize_names,
+                    )
+                },
+            )?;
+            if moved > 0 {
+                log::info!("moved {moved} renamed directories");
+            }
+        }
+        // rendered paths are only known once the workers read the tags
+        if args.path_template.is_none() {
+            create_dst_dirs(&args, &cache, &files, &dirs)?;
+        }
+        let (reclaimable, to_prune) =
+            find_orphans(&args, &cache, &probes, &files, &trashed);
+        _ = orphan_tx.send(to_prune);
+        let reclaimable = Arc::new(reclaimable);
+        let dir_files = count_dirs(&files);
+        // handed out in scan order, so what is limited per run (--max-reencodes)
+        // goes to the first files
+        let files = files
+            .into_iter()
+            .filter(move |src| !unchanged.contains(src))
+            .map(move |src| {
+                let marked = passthrough.contains(&src);
+                (src, marked, reclaimable.clone())
+            })
+            .par_bridge();
+        let work = Work { files, orphans, trash: trash.clone(), probes };
+        let (cache, args) = (cache.clone(), args.clone());
+        let mut stats =
+            spawn_workers(conn, work, cache, args, dirs, started_at, &bus)?;
+        stats.unsynced.extend(collisions);
+        (stats, dir_files, scanned_dirs)
+    };
+    report_failed_dirs(&args, &stats, &dir_files, pretty);
+    // an aborted run didn't get to every file, so what earlier runs found is kept
+    db::save_unsynced(conn, &stats.unsynced, stats.aborted.is_none())?;
+
+    // an aborted run keeps the old mtimes, so the directories it didn't get to
+    // still look changed next time
+    if args.fast_scan && stats.aborted.is_none() {
+        let dirs: Vec<_> = scanned_dirs
+            .into_iter()
+            .map(|(dir, mtime)| {
+                // read again next run, so the file is retried or reported again
+                let mtime = mtime.filter(|_| !stats.unsettled_dirs.contains(&dir));
+                (dir, mtime)
+            })
+            .collect();
+        db::save_dirs(conn, &dirs)?;
+    } else if !args.fast_scan {
+        // runs without --fast-scan don't keep the mtimes up to date
+        db::save_dirs(conn, &[])?;
+    }
+
+    if stats.aborted.is_some() {
+        bus.publish(Event::RunFinished { stats: &stats, started: time });
+        return Ok(stats);
+    }
+
+    if args.no_delete {
+        bus.publish(Event::RunFinished { stats: &stats, started: time });
+        if !stats.would_delete.is_empty() {
+            log::warn!(
+                "--no-delete kept {} files that would have been deleted:",
+                stats.would_delete.len(),
+            );
+            for path in &stats.would_delete {
+                log::warn!("  {}", path.display());
+            }
+        }
+        return Ok(stats);
+    }
+
+    // what the workers did with the orphans. the failed ones are kept, so the next
+    // run tries again
+    db::prune(conn, stats.pruned.iter())?;
+    // covers go with the files they were extracted from
+    for art in db::take_orphaned_art(conn)? {
+        if exists_no_follow(&art) {
+            log::info!("removing extracted art {}", art.display());
+            if let Err(e) = fs::remove_file(&art) {
+                log::warn!("failed to remove extracted art {}: {e}", art.display());
+            }
+        }
+        if let Some(dir) = art.parent() {
+            stats.changed_dirs.insert(dir.to_path_buf());
+        }
+    }
+    db::mark_orphaned(conn, stats.newly_orphaned.iter(), started_at)?;
+    if let Some(trash) = &trash {
+        db::insert_trash(conn, stats.newly_trashed.iter(), started_at)?;
+        // trashed files that were reclaimed this run
+        let restored = trashed.iter().map(|info| &info.dst).filter(|p| !p.exists());
+        db::remove_trash(conn, restored)?;
+        remove_empty_dirs(&trash.dir)?;
+    }
+    if args.generate_playlists {
+        // directories where something changed, and ones that never got a playlist
+        let mut dirs = std::mem::take(&mut stats.changed_dirs);
+        let cached_dirs: HashSet<_> =
+            cache.values().filter_map(|info| info.dst.parent()).collect();
+        for dir in cached_dirs {
+            if !dirs.contains(dir) && !dir.join(playlists::PLAYLIST_NAME).exists() {
+                dirs.insert(dir.to_path_buf());
+            }
+        }
+        let written = playlists::update(dirs.iter(), &args.format)?;
+        if written > 0 {
+            log::info!("updated {written} playlists");
+        }
+    }
+    remove_empty_dirs(&args.destination)?;
+    if args.auto_gc {
+        // rows seen this run are known to be live, so only the rest are checked
+        let (_, stale) = gc::find_stale(conn, Some(started_at))?;
+        gc::remove(conn, &stale)?;
+        if !stale.is_empty() {
+            log::info!("removed {} stale database rows", stale.len());
+        }
+    }
+
+    bus.publish(Event::RunFinished { stats: &stats, started: time });
+    if stats.orphans_in_grace > 0 {
+        log::info!(
+            "kept {} orphans during their grace period",
+            stats.orphans_in_grace,
+        );
+    }
+
+    Ok(stats)
+}
+
+// whether an orphan's output should be kept for now, because its source may
+// come back
+fn orphan_in_grace(args: &Args, info: &FileInfo, now: i64) -> bool {
+    let Some(grace) = args.orphan_grace else {
+        return false;
+    };
+    let orphaned_at = info.orphaned_at.unwrap_or(now);
+    now.saturating_sub(orphaned_at) < grace.secs() as i64
+}
+
+// passthrough symlinks dangle once their source is gone, but are still orphans.
+// removing them with remove_file never touches the target
+fn exists_no_follow(path: &Path) -> bool {
+    path.symlink_metadata().is_ok()
 }
 
 fn init_thread_pool(threads: Option<usize>) -> Result<()> {
@@ -175,8 +1475,8 @@ fn init_thread_pool(threads: Option<usize>) -> Result<()> {
 }
 
 fn init_db(db_path: &Path) -> Result<(Connection, FileCache)> {
-    let conn = db::connect(db_path)?;
-    db::init(&conn)?;
+    let mut conn = db::connect(db_path)?;
+    db::init(&mut conn)?;
 
     let cache = db::load_cache(&conn)?;
 
@@ -185,159 +1485,837 @@ fn init_db(db_path: &Path) -> Result<(Connection, FileCache)> {
     Ok((conn, cache))
 }
 
-// db_path_canon should be canonicalized
-fn find_src_files(args: &Args, db_path_canon: &Path) -> Result<Vec<PathBuf>> {
-    log::info!("scanning source directory {}", args.source.display());
+// the sources a scan found, unchanged and passthrough are subsets of files
+struct SrcFiles {
+    files: Vec<PathBuf>,
+    // in directories --fast-scan skipped
+    unchanged: HashSet<PathBuf>,
+    // below a passthrough marker
+    passthrough: HashSet<PathBuf>,
+    scanned_dirs: ScannedDirs,
+    // skipped because another source maps to the same output
+    collisions: Vec<UnsyncedRow>,
+}
 
-    // path and size, for sorting
+// db_path_canon should be canonicalized
+fn find_src_files(
+    args: &Args,
+    db_path_canon: &Path,
+    dir_index: Option<&DirIndex>,
+) -> Result<SrcFiles> {
     let mut files = Vec::<PathBuf>::new();
+    let mut unchanged = HashSet::new();
+    let mut passthrough = HashSet::new();
+    let on_file = |path: PathBuf, skipped, marked| {
+        if skipped {
+            unchanged.insert(path.clone());
+        }
+        if marked {
+            passthrough.insert(path.clone());
+        }
+        files.push(path);
+    };
+    let (scanned_dirs, collisions) =
+        scan_src_files(args, db_path_canon, dir_index, on_file)?;
+    Ok(SrcFiles { files, unchanged, passthrough, scanned_dirs, collisions })
+}
 
-    // track allocated destinations to detect collisions (dst -> src)
-    let mut dst_map = HashMap::<PathBuf, PathBuf>::new();
+// source directories with their mtime, if --fast-scan is recording them
+type ScannedDirs = Vec<(PathBuf, Option<i64>)>;
 
-    // we never push ignored files to the list, we don't need them later
-    // ignored files don't produce output, no collision is possible
-    let walker = WalkDir::new(&args.source).into_iter().filter_entry(|e| {
-        !args.ignore_dotfiles || !is_dotfile(e)
-    });
-    for entry in walker {
-        let entry = entry?;
-        if !entry.file_type().is_file() {
-            log::trace!(
-                "skipping {}; not a normal file",
-                entry.path().to_string_lossy()
-            );
-            continue;
+// sends files to the workers as the walk discovers them. files already in the
+// cache go out immediately, but new files are held back until the walk is done:
+// they may be renames, and orphans can only be known once the full file set is.
+// the orphans are sent on the second channel then
+fn streaming_scan(
+    args: &Args,
+    db_path_canon: &Path,
+    dir_index: Option<&DirIndex>,
+    (cache, probes): (&FileCache, &ProbeCache),
+    trashed: &[FileInfo],
+    (jobs, orphans): (Sender<Job>, Sender<Vec<PathBuf>>),
+) -> Result<(DirCounts, ScannedDirs, Vec<UnsyncedRow>)> {
+    let no_orphans = Arc::new(OrphanCache::default());
+    let mut files = Vec::new();
+    let mut new_files = Vec::new();
+
+    let on_file = |path: PathBuf, unchanged, marked| {
+        // files --fast-scan skipped aren't processed, but still keep their
+        // outputs from being orphaned
+        if unchanged {
+            // nothing to send
+        } else if cache.contains_key(&path) {
+            _ = jobs.send((path.clone(), marked, no_orphans.clone()));
+        } else {
+            new_files.push((path.clone(), marked));
         }
+        files.push(path);
+    };
+    let (scanned_dirs, collisions) =
+        scan_src_files(args, db_path_canon, dir_index, on_file)?;
 
-        let path = entry.path();
+    let (reclaimable, to_prune) = find_orphans(args, cache, probes, &files, trashed);
+    _ = orphans.send(to_prune);
+    let reclaimable = Arc::new(reclaimable);
+    for (path, marked) in new_files {
+        _ = jobs.send((path, marked, reclaimable.clone()));
+    }
+
+    Ok((count_dirs(&files), scanned_dirs, collisions))
+}
+
+// number of files in each source directory
+type DirCounts = HashMap<PathBuf, usize>;
+
+fn count_dirs(files: &[PathBuf]) -> DirCounts {
+    let mut counts = DirCounts::new();
+    for file in files {
+        if let Some(dir) = file.parent() {
+            *counts.entry(dir.to_path_buf()).or_default() += 1;
+        }
+    }
+    counts
+}
 
-        if path.file_name() == db_path_canon.file_name() {
+// a whole album failing is one problem, not a dozen scattered errors, so
+// failures are also listed per directory with the worst ones first
+fn report_failed_dirs(
+    args: &Args,
+    stats: &WorkStats,
+    dir_files: &DirCounts,
+    pretty: &Pretty,
+) {
+    if stats.failed.is_empty() {
+        return;
+    }
+    let mut failed = HashMap::<&Path, usize>::new();
+    for src in &stats.failed {
+        if let Some(dir) = src.parent() {
+            *failed.entry(dir).or_default() += 1;
+        }
+    }
+    let mut dirs: Vec<_> = failed.into_iter().collect();
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    log::error!("failures by directory:");
+    for (dir, fails) in dirs {
+        let total = dir_files.get(dir).copied().unwrap_or(fails);
+        let rel = match dir.strip_prefix(&args.source) {
+            Ok(rel) if rel.as_os_str().is_empty() => Path::new("."),
+            Ok(rel) => rel,
+            Err(_) => dir,
+        };
+        let mut line = format!("{} — {fails}/{total} files failed", rel.display());
+        if fails == total {
+            line.push_str(" (entire directory)");
+        }
+        log::error!("  {line}");
+        pretty.failed_dir(&line);
+    }
+}
+
+// calls on_file for every file that should be synced, in walk order, with whether
+// it is in a directory --fast-scan skipped and whether it is below a passthrough
+// marker. returns the directories scanned and the sources skipped for colliding
+fn scan_src_files(
+    args: &Args,
+    db_path_canon: &Path,
+    dir_index: Option<&DirIndex>,
+    mut on_file: impl FnMut(PathBuf, bool, bool),
+) -> Result<(ScannedDirs, Vec<UnsyncedRow>)> {
+    log::info!("scanning source directory {}", args.source.display());
+
+    let mut count = 0;
+    let mut unchanged_count = 0;
+
+    // the database and the sidecar files sqlite keeps next to it, which change
+    // constantly and must never be mirrored
+    let db_files: Vec<PathBuf> = ["", "-wal", "-shm", "-journal"]
+        .iter()
+        .map(|suffix| {
+            let mut path = db_path_canon.as_os_str().to_owned();
+            path.push(suffix);
+            PathBuf::from(path)
+        })
+        .collect();
+
+    // track allocated destinations to detect collisions (dst -> src)
+    let mut dst_map = HashMap::<PathBuf, PathBuf>::new();
+    let mut collisions = Vec::new();
+
+    let mut accept = |path: PathBuf, unchanged: bool, marked: bool| -> Result<()> {
+        if db_files.iter().any(|f| path.file_name() == f.file_name()) {
             // only canonicalize if names match (reduce number of syscalls)
             // don't include db in indexed files if it is in the same dir
-            if let Ok(entry_canon) = fs::canonicalize(entry.path()) {
-                if entry_canon == db_path_canon {
-                    continue;
-                }
+            if let Ok(path_canon) = fs::canonicalize(&path)
+                && db_files.contains(&path_canon)
+            {
+                return Ok(());
             }
         }
 
-        if has_extension(path, &args.ignored_exts) {
-            continue;
+        if args.ignores(&path) {
+            return Ok(());
         }
 
-        // collision detection
+        // collision detection. types found by --detect-type are only known to the
+        // workers, so this goes by extension
         let dst = map_src_to_dst(
-            path,
+            &path,
             &args.source,
             &args.destination,
             &args.format,
-            has_extension(path, &args.allowed_exts),
+            args.transcodes(&path) && !marked,
+            args.lowercase_extensions,
+            args.sanitize_names,
         )?;
         if let Some(existing_src) = dst_map.get(&dst) {
-            log::warn!(
-                "collision detected: '{}' and '{}' both map to '{}', skipping '{}'",
-                existing_src.display(),
-                path.display(),
-                dst.display(),
-                path.display(),
+            warnings::warn(
+                "collision detected",
+                format_args!(
+                    "collision detected: '{}' and '{}' both map to '{}', skipping \
+                     '{}'",
+                    existing_src.display(),
+                    path.display(),
+                    dst.display(),
+                    path.display(),
+                ),
             );
+            collisions.push(UnsyncedRow {
+                src: path,
+                reason: Reason::Collision,
+                detail: Some(existing_src.display().to_string()),
+            });
+            return Ok(());
+        }
+
+        dst_map.insert(dst, path.clone());
+        on_file(path, unchanged, marked);
+        count += 1;
+        if unchanged {
+            unchanged_count += 1;
+        }
+        Ok(())
+    };
+
+    // directories are read one at a time, so --fast-scan can list unchanged ones
+    // from the database instead
+    let mut scanned_dirs = ScannedDirs::new();
+    // directories to read, and whether they are below a passthrough marker
+    let mut pending = vec![(args.source.clone(), false)];
+    while let Some((dir, marked)) = pending.pop() {
+        let marked = marked || dir.join(PASSTHROUGH_MARKER).is_file();
+        // taken before reading, so entries added meanwhile are seen next run
+        let mtime = dir_index.and_then(|_| dir_mtime(&dir));
+        let unchanged = dir_index.and_then(|index| index.unchanged(&dir, mtime));
+        if let Some((files, subdirs)) = unchanged {
+            pending.extend(subdirs.iter().map(|d| (d.clone(), marked)));
+            for file in files {
+                accept(file.clone(), true, marked)?;
+            }
+            scanned_dirs.push((dir, mtime));
             continue;
         }
 
-        dst_map.insert(dst, path.to_path_buf());
-        files.push(entry.into_path());
+        // we never push ignored files to the list, we don't need them later
+        // ignored files don't produce output, no collision is possible
+        let walker = WalkDir::new(&dir)
+            .min_depth(1)
+            .max_depth(1)
+            .into_iter()
+            .filter_entry(|e| !args.ignore_dotfiles || !is_dotfile(e));
+        for entry in walker {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                pending.push((entry.into_path(), marked));
+                continue;
+            }
+            if entry.file_name() == PASSTHROUGH_MARKER {
+                continue;
+            }
+            if !entry.file_type().is_file() {
+                log::trace!(
+                    "skipping {}; not a normal file",
+                    entry.path().to_string_lossy()
+                );
+                continue;
+            }
+            accept(entry.into_path(), false, marked)?;
+        }
+        scanned_dirs.push((dir, mtime));
+    }
+
+    if unchanged_count > 0 {
+        log::info!(
+            "found {count} files, {unchanged_count} of them in unchanged directories"
+        );
+    } else {
+        log::info!("found {count} files");
     }
 
-    log::info!("found {} files", files.len());
+    Ok((scanned_dirs, collisions))
+}
+
+// creates the destination directories of new and moved sources before any worker
+// starts, so one that can't be created fails the run instead of each of its files.
+// directories of cached outputs exist already
+fn create_dst_dirs(
+    args: &Args,
+    cache: &FileCache,
+    files: &[PathBuf],
+    dirs: &DstDirs,
+) -> Result<()> {
+    // sorted, so parents come before the directories inside them
+    let mut parents = BTreeSet::new();
+    for src in files {
+        let dst = map_src_to_dst(
+            src,
+            &args.source,
+            &args.destination,
+            &args.format,
+            args.transcodes(src),
+            args.lowercase_extensions,
+            args.sanitize_names,
+        )?;
+        let Some(parent) = dst.parent() else {
+            continue;
+        };
+        if cache.get(src).is_none_or(|hit| hit.dst.parent() != Some(parent)) {
+            parents.insert(parent.to_path_buf());
+        }
+    }
+    for dir in &parents {
+        dirs.ensure(dir)?;
+    }
+    Ok(())
+}
+
+/// What cleanup did with an orphan no worker claimed.
+enum OrphanRemoval {
+    Removed,
+    Trashed(FileInfo),
+    // reclaimed by a worker after all, or removed by hand
+    Gone,
+    // its source may still come back
+    InGrace,
+    // would have been removed, but --no-delete keeps it
+    Kept,
+}
 
-    Ok(files)
+// the error says whether the orphan was being trashed
+fn remove_orphan(
+    args: &Args,
+    trash: Option<&Trash>,
+    info: &FileInfo,
+    now: i64,
+) -> Result<OrphanRemoval, (bool, anyhow::Error)> {
+    if !exists_no_follow(&info.dst) {
+        return Ok(OrphanRemoval::Gone);
+    }
+    // still there, so no worker claimed it
+    if orphan_in_grace(args, info, now) {
+        return Ok(OrphanRemoval::InGrace);
+    }
+    if args.no_delete {
+        return Ok(OrphanRemoval::Kept);
+    }
+    // a relative symlink would dangle once moved, and is free to recreate
+    match trash.filter(|_| !info.dst.is_symlink()) {
+        Some(trash) => {
+            trash.quarantine(info).map(OrphanRemoval::Trashed).map_err(|e| (true, e))
+        }
+        None => match fs::remove_file(&info.dst) {
+            Ok(()) => Ok(OrphanRemoval::Removed),
+            Err(e) => Err((false, e.into())),
+        },
+    }
 }
 
 // second return is a list of orphans for db pruning
-fn find_orphans(cache: &FileCache, files: &[PathBuf]) -> (OrphanCache, Vec<PathBuf>) {
+// trashed files are candidates for reclaiming too, but are never pruned. only
+// orphans that still exist and that the settings could produce again are offered
+// to the workers, the rest are just deleted
+fn find_orphans(
+    args: &Args,
+    cache: &FileCache,
+    probes: &ProbeCache,
+    files: &[PathBuf],
+    trashed: &[FileInfo],
+) -> (OrphanCache, Vec<PathBuf>) {
+    use rayon::prelude::*;
+
+    let no_orphans = OrphanCache::default();
+    let settings =
+        worker_settings(args, None, None, None, &no_orphans, cache, probes);
     let active_set: HashSet<&PathBuf> = files.iter().collect();
-    let mut map: OrphanCache = HashMap::new();
     let mut to_prune = Vec::new();
-
+    // with their source, which trashed files no longer have
+    let mut candidates: Vec<(&FileInfo, Option<&Path>)> =
+        trashed.iter().map(|info| (info, None)).collect();
     for (src, info) in cache {
         if !active_set.contains(src) {
             // missing from src
-            map.entry(info.hash.clone()).or_default().push(info.clone());
+            candidates.push((info, Some(src)));
             to_prune.push(src.clone());
         }
     }
 
+    let total = candidates.len();
+    candidates.retain(|(info, src)| worker::may_reclaim(&settings, *src, info));
+    let reclaimable = candidates.len();
+    // one stat per orphan, in parallel, instead of one per candidate in each worker
+    let existing: Vec<&FileInfo> = candidates
+        .into_par_iter()
+        .map(|(info, _)| info)
+        .filter(|info| info.dst.exists())
+        .collect();
+
+    let mut map = OrphanCache::default();
+    for info in existing {
+        let orphan = Orphan {
+            dst: info.dst.clone(),
+            size: info.size,
+            config: info.config.clone(),
+        };
+        map.insert(&info.hash, orphan);
+    }
+    if total > 0 {
+        log::debug!(
+            "{} of {total} orphans can be reclaimed, {} can't under the current \
+             settings and {} no longer exist",
+            map.len(),
+            total - reclaimable,
+            reclaimable - map.len(),
+        );
+    }
+
     (map, to_prune)
 }
 
 #[derive(Default)]
 struct WorkStats {
     successes: usize,
+    transcoded: usize,
+    passed_through: usize,
+    reclaimed: usize,
+    // size of the outputs written this run
+    bytes_written: u64,
+    orphans_removed: usize,
+    // orphans that couldn't be deleted or trashed, kept in the database
+    orphans_failed: usize,
+    // orphans kept by --orphan-grace
+    orphans_in_grace: usize,
+    // sources of the orphans that are gone, for pruning from the database
+    pruned: Vec<PathBuf>,
+    // sources of the orphans whose grace period started this run
+    newly_orphaned: Vec<PathBuf>,
+    // orphans moved to the trash this run
+    newly_trashed: Vec<FileInfo>,
+    // destination directories whose contents changed
+    changed_dirs: HashSet<PathBuf>,
     skips: usize,
     fails: usize,
+    // source directories of files that failed, conflicted or were deferred
+    unsettled_dirs: HashSet<PathBuf>,
+    // sources that failed, for grouping by directory
+    failed: Vec<PathBuf>,
+    // sources left without an output and why, for the missing command
+    unsynced: Vec<UnsyncedRow>,
+    contradicted: usize,
+    // files processed anew because --verify-reclaim rejected their orphan
+    reclaims_rejected: usize,
+    // files processed anew because their output was empty or cut short
+    repaired: usize,
+    // outputs produced this run by why they were
+    reasons: BTreeMap<ReprocessReason, usize>,
+    // outputs with changed settings that --max-reencodes left for later runs
+    reencodes_deferred: usize,
+    // why the run was stopped early, if it was
+    aborted: Option<String>,
+    // whether the abort was --min-free's
+    low_space: bool,
+    // files skipped by workers after an abort
+    not_started: usize,
+    // when the first file that needed work finished
+    first_output: Option<Instant>,
+    conflicts: usize,
+    // files --no-delete kept around
+    would_delete: Vec<PathBuf>,
+    // sources whose content disagrees with their extension, and the real type
+    type_mismatches: Vec<(PathBuf, String)>,
+    // sources whose output lost or changed tags, found by --verify-tags
+    tag_issues: Vec<(PathBuf, Vec<String>)>,
+}
+
+// a source, whether it is below a passthrough marker, and the orphans it may reclaim
+type Job = (PathBuf, bool, Arc<OrphanCache>);
+
+// what the workers go through: the files, then the orphans none of them claimed
+struct Work<J> {
+    files: J,
+    // sources of the orphans, sent once the scan knows them, which a streaming
+    // scan only does at the end. never removed by an aborted run
+    orphans: Receiver<Vec<PathBuf>>,
+    trash: Option<Trash>,
+    probes: Arc<ProbeCache>,
+}
+
+// sent by workers to the receiver, which records results in the database. nearly
+// every event is Done, boxing it would only add an allocation per file
+#[allow(clippy::large_enum_variant)]
+enum WorkerEvent {
+    // with how long the file took
+    Done(Result<worker::ProcessedFile, (PathBuf, anyhow::Error)>, Duration),
+    // fraction of a large file encoded so far
+    Progress { src: PathBuf, fraction: f32 },
+    // every file is done, the orphans can be removed unless the run aborted
+    FilesDone,
+    // an orphan was removed or kept, with its source
+    Orphan(PathBuf, Result<OrphanRemoval, (bool, anyhow::Error)>),
 }
 
 // returns number of succeeded and failed files
 fn spawn_workers(
     conn: &mut Connection,
-    files: Vec<PathBuf>,
-    orphans: Arc<OrphanCache>,
-    cache: FileCache,
+    work: Work<impl ParallelIterator<Item = Job> + 'static>,
+    cache: Arc<FileCache>,
     args: Args,
+    dirs: DstDirs,
+    started_at: i64,
+    bus: &EventBus,
 ) -> Result<WorkStats> {
-    let (tx, rx) = std::sync::mpsc::channel();
+    // bounded so that a slow database applies backpressure to the workers instead
+    // of letting results pile up in memory. blocking a rayon task on send can't
+    // deadlock: the receiver below runs on this (non-rayon) thread and never
+    // submits work to the pool, so it always makes progress on its own
+    const CHANNEL_CAPACITY: usize = 4096;
+    let (tx, rx) = std::sync::mpsc::sync_channel(CHANNEL_CAPACITY);
 
-    std::thread::spawn(move || {
-        use rayon::prelude::*;
+    let mut breaker = if args.fail_fast {
+        CircuitBreaker::new(Some(1), None)
+    } else if args.keep_going {
+        CircuitBreaker::new(None, None)
+    } else {
+        CircuitBreaker::new(
+            Some(args.max_consecutive_failures),
+            Some(args.max_failure_rate),
+        )
+    };
+
+    // set by the receiver to stop workers from picking up new files
+    let abort = Arc::new(AtomicBool::new(false));
+    let worker_abort = abort.clone();
+    let not_started = Arc::new(AtomicUsize::new(0));
+    let worker_not_started = not_started.clone();
+    let (min_free, destination) = (args.min_free, args.destination.clone());
+
+    // shared by all workers, so the sample size applies to the whole run
+    let tag_verifier = args
+        .verify_tags
+        .map(|sample| TagVerifier::new(sample, &args.verify_tag_keys));
 
-        files.into_par_iter().for_each_with(tx, |tx, src| {
-            let settings = WorkerSettings {
-                src_root: &args.source,
-                dst_root: &args.destination,
-                allowed_exts: &args.allowed_exts,
-                target_ext: &args.format,
-                bitrate: args.bitrate,
-                should_copy: args.copy,
-                orphans: &orphans,
-                cache: &cache,
+    // rendered paths and detected types can collide in ways the scan can't see
+    let claims = (args.path_template.is_some() || args.detect_type)
+        .then(|| DstClaims::new(&cache));
+    let art = args.extract_art.then(ArtExtractor::default);
+    let reencode_budget = args.max_reencodes.map(AtomicUsize::new);
+    let Work { files, orphans, trash, probes } = work;
+    // handed on by the receiver once it knows the run didn't abort
+    let (orphan_tx, orphan_rx) = std::sync::mpsc::channel::<Vec<PathBuf>>();
+    let orphan_cache = cache.clone();
+
+    std::thread::spawn(move || {
+        files.for_each_with(tx.clone(), |tx, (src, passthrough, orphans)| {
+            if worker_abort.load(Ordering::Relaxed) {
+                worker_not_started.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            let report = |fraction| {
+                _ = tx.send(WorkerEvent::Progress { src: src.clone(), fraction });
             };
+            let mut settings = worker_settings(
+                &args,
+                tag_verifier.as_ref(),
+                claims.as_ref(),
+                art.as_ref(),
+                &orphans,
+                &cache,
+                &probes,
+            );
+            settings.force_passthrough = passthrough;
+            settings.progress = Some(&report);
+            settings.dirs = Some(&dirs);
+            settings.reencode_budget = reencode_budget.as_ref();
+            let started = Instant::now();
             let raw_res = worker::process_file(&src, settings);
-            _ = tx.send(raw_res.map_err(|e| (src, e)));
+            let res = raw_res.map_err(|e| (src, e));
+            _ = tx.send(WorkerEvent::Done(res, started.elapsed()));
+        });
+        _ = tx.send(WorkerEvent::FilesDone);
+        let Ok(orphans) = orphan_rx.recv() else { return };
+        orphans.into_par_iter().for_each_with(tx, |tx, src| {
+            let info = &cache[&src];
+            let removal = remove_orphan(&args, trash.as_ref(), info, started_at);
+            _ = tx.send(WorkerEvent::Orphan(src, removal));
         });
     });
 
     let mut stats = WorkStats::default();
+    let mut received = 0;
 
-    let stream = rx.into_iter().inspect(|res| match &res {
-        Ok(file) => match file.status {
-            FileStatus::PassedThrough => {
-                log::info!("passed through {}", file.src.display());
-                stats.successes += 1;
+    // counting stays here, the run depends on it. everything reporting on files
+    // subscribes to the bus instead
+    let mut orphan_tx = Some(orphan_tx);
+    let stream = rx.into_iter().filter_map(|event| {
+        let (res, duration) = match event {
+            WorkerEvent::Progress { src, fraction } => {
+                let percent = (fraction * 100.0).round();
+                bus.publish(Event::Progress { src: &src, percent });
+                return None;
             }
-            FileStatus::Transcoded => {
-                log::info!("transcoded {}", file.src.display());
-                stats.successes += 1;
+            WorkerEvent::FilesDone => {
+                // the state of an aborted run is untrustworthy, so nothing is
+                // deleted. dropping the sender tells the workers
+                if let Some(tx) = orphan_tx.take()
+                    && stats.aborted.is_none()
+                    && let Ok(orphans) = orphans.recv()
+                {
+                    _ = tx.send(orphans);
+                }
+                return None;
             }
-            FileStatus::Reclaimed => {
-                log::info!("reclaimed {}", file.src.display());
-                stats.successes += 1;
+            WorkerEvent::Orphan(src, removal) => {
+                let info = &orphan_cache[&src];
+                orphan_removed(&mut stats, bus, src, info, removal);
+                return None;
             }
-            FileStatus::Skipped => {
-                log::trace!("skipped {}", file.src.display());
-                stats.skips += 1;
+            WorkerEvent::Done(res, duration) => (res, duration),
+        };
+        match &res {
+            Ok(file) => {
+                if file.hash_contradicted {
+                    stats.contradicted += 1;
+                }
+                if file.deferred {
+                    stats.reencodes_deferred += 1;
+                    // --fast-scan has to read the directory again to get to it
+                    if let Some(dir) = file.src.parent() {
+                        stats.unsettled_dirs.insert(dir.to_path_buf());
+                    }
+                }
+                if file.reclaim_rejected {
+                    stats.reclaims_rejected += 1;
+                }
+                if let Some(reason) = file.reason
+                    && !matches!(file.status, FileStatus::Conflict)
+                {
+                    *stats.reasons.entry(reason).or_default() += 1;
+                }
+                if file.repaired {
+                    stats.repaired += 1;
+                }
+                if !matches!(file.status, FileStatus::Skipped) {
+                    stats.first_output.get_or_insert_with(Instant::now);
+                }
+                if let Some(path) = &file.would_delete {
+                    stats.would_delete.push(path.clone());
+                }
+                if let Some(detected) = &file.info.detected_type
+                    && let Some(detected) = sniff::type_mismatch(&file.src, detected)
+                {
+                    let mismatch = (file.src.clone(), detected.to_string());
+                    stats.type_mismatches.push(mismatch);
+                }
+                if !file.tag_issues.is_empty() {
+                    let issues = file.tag_issues.clone();
+                    stats.tag_issues.push((file.src.clone(), issues));
+                }
+                let written = matches!(
+                    file.status,
+                    FileStatus::PassedThrough | FileStatus::Transcoded,
+                );
+                if !matches!(file.status, FileStatus::Skipped | FileStatus::Conflict)
+                    && let Some(dir) = file.info.dst.parent()
+                {
+                    stats.changed_dirs.insert(dir.to_path_buf());
+                }
+                let bytes = match fs::symlink_metadata(&file.info.dst) {
+                    Ok(meta) if written => meta.len(),
+                    _ => 0,
+                };
+                stats.bytes_written += bytes;
+                match file.status {
+                    FileStatus::PassedThrough => {
+                        stats.successes += 1;
+                        stats.passed_through += 1;
+                    }
+                    FileStatus::Transcoded => {
+                        stats.successes += 1;
+                        stats.transcoded += 1;
+                    }
+                    FileStatus::Reclaimed => {
+                        stats.successes += 1;
+                        stats.reclaimed += 1;
+                    }
+                    FileStatus::Skipped => stats.skips += 1,
+                    FileStatus::Conflict => {
+                        stats.conflicts += 1;
+                        stats.unsynced.push(UnsyncedRow {
+                            src: file.src.clone(),
+                            reason: Reason::Conflict,
+                            detail: Some(file.info.dst.display().to_string()),
+                        });
+                        if let Some(dir) = file.src.parent() {
+                            stats.unsettled_dirs.insert(dir.to_path_buf());
+                        }
+                    }
+                }
+                bus.publish(Event::FileFinished { file, bytes, duration });
             }
-        },
-        Err((src, e)) => {
-            log::error!("failed to process {}: {e}", src.display());
-            stats.fails += 1;
+            Err((src, e)) => {
+                stats.fails += 1;
+                stats.failed.push(src.clone());
+                stats.unsynced.push(UnsyncedRow {
+                    src: src.clone(),
+                    reason: Reason::Failed,
+                    detail: Some(format!("{e:#}")),
+                });
+                if let Some(dir) = src.parent() {
+                    stats.unsettled_dirs.insert(dir.to_path_buf());
+                }
+                bus.publish(Event::FileFailed { src, error: e });
+            }
+        }
+        if let Some(reason) = breaker.record(res.is_err())
+            && stats.aborted.is_none()
+        {
+            log::error!("{reason}, aborting");
+            abort.store(true, Ordering::Relaxed);
+            stats.aborted = Some(reason);
         }
+
+        // statvfs is cheap, but there's no need to call it for every file
+        const FREE_SPACE_INTERVAL: usize = 16;
+        received += 1;
+        if let Some(min_free) = min_free
+            && received % FREE_SPACE_INTERVAL == 0
+            && stats.aborted.is_none()
+        {
+            match free_space(&destination) {
+                Ok(free) if free < min_free.bytes() => {
+                    let free = stats::format_bytes(free as i64);
+                    let reason =
+                        format!("free space on the destination fell to {free}");
+                    log::error!("{reason}, finishing files in progress");
+                    abort.store(true, Ordering::Relaxed);
+                    stats.aborted = Some(reason);
+                    stats.low_space = true;
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("failed to check free space: {e:#}"),
+            }
+        }
+        Some(res)
     });
-    db::ingest_results(conn, stream.flatten())?;
+    db::ingest_results(conn, stream.flatten(), started_at)?;
+    stats.not_started = not_started.load(Ordering::Relaxed);
 
     Ok(stats)
 }
 
+// counts what became of an orphan. the ones that are gone are pruned, the failed
+// ones kept for the next run to try again
+fn orphan_removed(
+    stats: &mut WorkStats,
+    bus: &EventBus,
+    src: PathBuf,
+    info: &FileInfo,
+    removal: Result<OrphanRemoval, (bool, anyhow::Error)>,
+) {
+    let dst = &info.dst;
+    match removal {
+        Ok(OrphanRemoval::InGrace) => {
+            if info.orphaned_at.is_none() {
+                stats.newly_orphaned.push(src);
+            }
+            stats.orphans_in_grace += 1;
+            return;
+        }
+        Ok(OrphanRemoval::Kept) => {
+            stats.would_delete.push(dst.clone());
+            return;
+        }
+        Ok(OrphanRemoval::Gone) => {}
+        Ok(OrphanRemoval::Removed) => {
+            bus.publish(Event::OrphanRemoved { dst, trashed: false });
+            stats.orphans_removed += 1;
+        }
+        Ok(OrphanRemoval::Trashed(trashed)) => {
+            bus.publish(Event::OrphanRemoved { dst, trashed: true });
+            stats.newly_trashed.push(trashed);
+            stats.orphans_removed += 1;
+        }
+        Err((trashing, error)) => {
+            let error = &error;
+            bus.publish(Event::OrphanFailed { dst, trashing, error });
+            stats.orphans_failed += 1;
+            return;
+        }
+    }
+    if let Some(dir) = dst.parent() {
+        stats.changed_dirs.insert(dir.to_path_buf());
+    }
+    stats.pruned.push(src);
+}
+
+// what a worker needs to process one job. orphans and cache are per job
+fn worker_settings<'a>(
+    args: &'a Args,
+    tag_verifier: Option<&'a TagVerifier>,
+    claims: Option<&'a DstClaims>,
+    art: Option<&'a ArtExtractor>,
+    orphans: &'a OrphanCache,
+    cache: &'a FileCache,
+    probes: &'a ProbeCache,
+) -> WorkerSettings<'a> {
+    WorkerSettings {
+        src_root: &args.source,
+        dst_root: &args.destination,
+        allowed_exts: &args.allowed_exts,
+        target_ext: &args.format,
+        lowercase_ext: args.lowercase_extensions,
+        sanitize_names: args.sanitize_names,
+        sniff_extensionless: args.sniff_extensionless,
+        detect_type: args.detect_type,
+        bitrate: args.bitrate,
+        bitrate_per_channel: args.bitrate_per_channel,
+        bitrate_rules: &args.bitrate_rules,
+        max_channels: args.channels,
+        max_sample_rate: args.max_sample_rate,
+        bit_depth: args.bit_depth,
+        mtime_window: args.mtime_window,
+        paranoid: args.paranoid,
+        fast_hash: args.fast_hash.map(|size| size.bytes()),
+        reencode_configs: &args.reencode_configs,
+        verify_reclaim: args.verify_reclaim,
+        embed_provenance: args.embed_provenance,
+        no_delete: args.no_delete,
+        rebuild: args.rebuild_destination,
+        link_mode: args.link_mode(),
+        tag_verifier,
+        path_template: args.path_template.as_ref(),
+        claims,
+        art,
+        orphans,
+        force_passthrough: false,
+        cache,
+        probes,
+        progress: None,
+        dirs: None,
+        reencode_budget: None,
+    }
+}
+
 fn remove_empty_dirs(root: &Path) -> Result<()> {
     // traverse leaf to root to delete nested empty dirs
     for entry in WalkDir::new(root).contents_first(true) {
@@ -354,8 +2332,612 @@ fn remove_empty_dirs(root: &Path) -> Result<()> {
             continue;
         };
         if e.kind() != std::io::ErrorKind::DirectoryNotEmpty {
-            log::warn!("failed to remove dir {}: {}", entry.path().display(), e);
+            warnings::warn(
+                "failed to remove dir",
+                format_args!("failed to remove dir {}: {e}", entry.path().display()),
+            );
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    pub(crate) fn args(extra: &[&str]) -> Args {
+        let mut all = vec!["-i", "/src", "-o", "/dst", "-d", "/db", "-a", "flac"];
+        all.extend(extra);
+        Args::from_args(&["sidechain"], &all).expect("valid arguments")
+    }
+
+    #[test]
+    fn sanitized_names_collide() {
+        let tmp = TempDir::new();
+        let src = tmp.path().join("src");
+        tmp.file("src/What Is...?/Vol. 2./a:b.flac", b"1");
+        tmp.file("src/What Is...?/Vol. 2./a?b.flac", b"2");
+        tmp.file("src/What Is...?/Vol. 2./c.flac", b"3");
+        let dst = tmp.path().join("dst");
+        let mut args = args(&["-f", "opus", "--sanitize-names"]);
+        (args.source, args.destination) = (src.clone(), dst);
+
+        let mut found = Vec::new();
+        let on_file = |path: PathBuf, _, _| found.push(path);
+        let db = tmp.path().join("db");
+        let (_, collisions) = scan_src_files(&args, &db, None, on_file).unwrap();
+        assert_eq!(found.len(), 2, "found {found:?}");
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].reason, Reason::Collision);
+        let winner = collisions[0].detail.as_deref().unwrap();
+        assert!(found.iter().any(|f| f.to_str() == Some(winner)));
+        assert!(!found.contains(&collisions[0].src));
+    }
+
+    fn nesting_error(tmp: &TempDir, source: &str, destination: &str) -> String {
+        let source = tmp.path().join(source);
+        match check_not_nested(&source, &tmp.path().join(destination)) {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn nested_source_and_destination_refused() {
+        let tmp = TempDir::new();
+        for dir in ["music/opus", "music-opus", "dst/music"] {
+            fs::create_dir_all(tmp.path().join(dir)).unwrap();
+        }
+        let error = |source, destination| nesting_error(&tmp, source, destination);
+        assert!(error("music", "music/opus").contains("inside --source"));
+        assert!(error("dst/music", "dst").contains("inside --destination"));
+        assert!(error("music", "music/.").contains("different"));
+        // a shared prefix isn't nesting
+        assert_eq!(error("music", "music-opus"), "");
+        assert_eq!(error("music-opus", "music"), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nesting_through_symlinks_refused() {
+        let tmp = TempDir::new();
+        for dir in ["music/opus", "dst/music"] {
+            fs::create_dir_all(tmp.path().join(dir)).unwrap();
+        }
+        let link = |target: &str, name: &str| {
+            let (target, name) = (tmp.path().join(target), tmp.path().join(name));
+            std::os::unix::fs::symlink(target, name).unwrap();
+        };
+        link("dst/music", "music-link");
+        link("music", "dst-link");
+        let error = |source, destination| nesting_error(&tmp, source, destination);
+        assert!(error("music-link", "dst").contains("inside --destination"));
+        assert!(error("music", "dst-link/opus").contains("inside --source"));
+        assert!(error("music", "dst-link").contains("different"));
+        assert_eq!(error("music-link", "music"), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn missing_destination_not_created_inside_source() {
+        let tmp = TempDir::new();
+        fs::create_dir_all(tmp.path().join("music")).unwrap();
+        std::os::unix::fs::symlink(tmp.path().join("music"), tmp.path().join("link"))
+            .unwrap();
+        let create = |destination: &str| {
+            let mut args = args(&["-f", "opus", "--create-destination"]);
+            args.source = tmp.path().join("music");
+            args.destination = tmp.path().join(destination);
+            create_destination(&args).map_err(|e| e.to_string())
+        };
+        for inside in ["music/opus/new", "link/opus"] {
+            let error = create(inside).unwrap_err();
+            assert!(error.contains("inside the source"), "{inside}: {error}");
+            assert!(!tmp.path().join(inside).exists());
+        }
+        assert_eq!(create("opus/new"), Ok(true));
+        assert_eq!(create("opus/new"), Ok(false));
+    }
+
+    // the events of a run, in order, as e.g. "finished a.jpg"
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl Subscriber for Recorder {
+        fn on_event(&self, event: &Event) {
+            let name = |path: &Path| path.file_name().unwrap().display().to_string();
+            let line = match event {
+                Event::FileFinished { file, .. } => {
+                    format!("finished {}", name(&file.src))
+                }
+                Event::FileFailed { src, .. } => format!("failed {}", name(src)),
+                Event::Progress { .. } => return,
+                Event::OrphanRemoved { dst, .. } => format!("orphan {}", name(dst)),
+                Event::OrphanFailed { dst, .. } => format!("orphan {}", name(dst)),
+                Event::RunFinished { .. } => "run finished".to_string(),
+            };
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    // syncs tmp/src to tmp/dst, passing everything through so ffmpeg isn't needed
+    pub(crate) fn run_recorded(
+        tmp: &TempDir,
+        extra: &[&str],
+    ) -> (WorkStats, Vec<String>) {
+        run_observed(tmp, extra, &[])
+    }
+
+    // like run_recorded, with more subscribers after the recorder
+    fn run_observed(
+        tmp: &TempDir,
+        extra: &[&str],
+        more: &[&dyn Subscriber],
+    ) -> (WorkStats, Vec<String>) {
+        let common = ["-f", "opus", "-b", "128", "--copy", "--porcelain"];
+        let mut args = args(&[&common, extra].concat());
+        args.source = tmp.path().join("src");
+        args.destination = tmp.path().join("dst");
+        args.db_path = tmp.path().join("db");
+        let (mut conn, cache) = init_db(&args.db_path).unwrap();
+        let start = RunStart { at: unix_now(), time: Instant::now() };
+        let pretty = Pretty::new(&args.source, true);
+        let recorder = Recorder::default();
+        let mut subscribers: Vec<&dyn Subscriber> = vec![&recorder];
+        subscribers.extend(more);
+        let db = fs::canonicalize(&args.db_path).unwrap();
+        let observed = &subscribers;
+        let stats = run_sync(args, &mut conn, cache, db, start, &pretty, observed);
+        (stats.unwrap(), recorder.0.into_inner().unwrap())
+    }
+
+    #[test]
+    fn orphan_events_come_between_files_and_run_end() {
+        let tmp = TempDir::new();
+        for name in ["a", "b", "c", "d"] {
+            tmp.file(&format!("src/{name}.jpg"), name.as_bytes());
+        }
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        fs::remove_file(tmp.path().join("src/b.jpg")).unwrap();
+        fs::remove_file(tmp.path().join("src/c.jpg")).unwrap();
+        // removing a directory with remove_file fails, even as root
+        fs::remove_file(tmp.path().join("dst/c.jpg")).unwrap();
+        tmp.file("dst/c.jpg/keep", b"");
+
+        let (stats, events) = run_recorded(&tmp, &[]);
+        assert_eq!((stats.orphans_removed, stats.orphans_failed), (1, 1));
+        assert_eq!(stats.pruned, [tmp.path().join("src/b.jpg")]);
+        let (files, rest) = events.split_at(2);
+        files.iter().for_each(|e| assert!(e.starts_with("finished "), "{events:?}"));
+        let mut orphans = rest[..2].to_vec();
+        orphans.sort();
+        assert_eq!(orphans, ["orphan b.jpg", "orphan c.jpg"]);
+        assert_eq!(rest[2..], ["run finished"]);
+    }
+
+    #[test]
+    fn aborted_run_removes_no_orphans() {
+        let tmp = TempDir::new();
+        tmp.file("src/a.jpg", b"a");
+        tmp.file("src/b.jpg", b"b");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        fs::remove_file(tmp.path().join("src/b.jpg")).unwrap();
+        // a file where the workers have to create a directory
+        tmp.file("src/album/x.jpg", b"x");
+        tmp.file("dst/album", b"");
+
+        let extra = ["--streaming-scan", "--fail-fast"];
+        let (stats, events) = run_recorded(&tmp, &extra);
+        assert!(stats.aborted.is_some());
+        assert!(events.contains(&"failed x.jpg".to_string()), "{events:?}");
+        assert!(!events.iter().any(|e| e.starts_with("orphan ")), "{events:?}");
+        assert_eq!(events.last().map(String::as_str), Some("run finished"));
+        assert!(tmp.path().join("dst/b.jpg").exists());
+    }
+
+    #[test]
+    fn truncated_outputs_are_regenerated() {
+        let tmp = TempDir::new();
+        tmp.file("src/cut.mp3", b"the whole file");
+        tmp.file("src/empty.mp3", b"also whole");
+        tmp.file("src/fine.mp3", b"untouched");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        // as a power loss in the middle of writing would leave them
+        let dst = |rel: &str| tmp.path().join("dst").join(rel);
+        fs::write(dst("cut.mp3"), b"the wh").unwrap();
+        fs::write(dst("empty.mp3"), b"").unwrap();
+
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!((stats.repaired, stats.skips), (2, 1));
+        assert_eq!(stats.reasons.get(&ReprocessReason::DstMissing), Some(&2));
+        assert_eq!(fs::read(dst("cut.mp3")).unwrap(), b"the whole file");
+        assert_eq!(fs::read(dst("empty.mp3")).unwrap(), b"also whole");
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!((stats.repaired, stats.skips), (0, 3));
+    }
+
+    // cargo test --release orphan_cache_at_scale -- --ignored --nocapture
+    #[test]
+    #[ignore = "benchmark"]
+    fn orphan_cache_at_scale() {
+        const ORPHANS: usize = 100_000;
+        let tmp = TempDir::new();
+        let args = args(&["-f", "opus", "-b", "128"]);
+        let (none, probes, empty) =
+            (OrphanCache::default(), ProbeCache::new(), FileCache::new());
+        let settings =
+            worker_settings(&args, None, None, None, &none, &empty, &probes);
+        let current = worker::predicted_config(&settings, None, &FileInfo::default());
+        let current = current.unwrap();
+
+        // a whole genre removed: a tenth of the outputs are still there, another
+        // tenth were made with older settings and the rest were deleted already
+        let mut cache = FileCache::new();
+        for i in 0..ORPHANS {
+            let dst = tmp.path().join(format!("{i}.opus"));
+            if i % 10 < 2 {
+                fs::write(&dst, b"").unwrap();
+            }
+            let config = match i % 10 {
+                1 => "opus:64k".to_string(),
+                _ => current.clone(),
+            };
+            let hash = format!("{i:064x}");
+            let info = FileInfo { dst, hash, config, ..Default::default() };
+            cache.insert(PathBuf::from(format!("/src/{i}.flac")), info);
+        }
+        let hashes: Vec<&str> = cache.values().map(|i| i.hash.as_str()).collect();
+
+        // as it was: every orphan offered, and each lookup stats its candidates
+        let time = Instant::now();
+        let mut old = HashMap::<String, Vec<FileInfo>>::new();
+        for info in cache.values() {
+            old.entry(info.hash.clone()).or_default().push(info.clone());
+        }
+        let reclaimed_before = hashes
+            .iter()
+            .filter(|&&hash| {
+                old[hash]
+                    .iter()
+                    .any(|info| info.dst.exists() && info.config == current)
+            })
+            .count();
+        let before = time.elapsed();
+
+        let time = Instant::now();
+        let (orphans, pruned) = find_orphans(&args, &cache, &probes, &[], &[]);
+        let reclaimed = hashes
+            .iter()
+            .filter(|&&hash| orphans.get(hash).iter().any(|o| o.config == current))
+            .count();
+        let now = time.elapsed();
+
+        assert_eq!((reclaimed_before, reclaimed), (ORPHANS / 10, ORPHANS / 10));
+        assert_eq!((orphans.len(), pruned.len()), (ORPHANS / 10, ORPHANS));
+        println!("{ORPHANS} orphans looked up: {before:?} before, {now:?} now");
+    }
+
+    #[test]
+    fn unchanged_sources_keep_their_hash() {
+        let tmp = TempDir::new();
+        let src = tmp.file("src/a.mp3", b"a");
+        let meta = fs::metadata(&src).unwrap();
+        let mtime = meta.modified().unwrap().duration_since(UNIX_EPOCH);
+        let mtime = mtime.unwrap().as_secs() as i64;
+        let mut args = args(&["-f", "opus", "-b", "128", "--copy"]);
+        args.source = tmp.path().join("src");
+        args.destination = tmp.path().join("dst");
+        let hit = FileInfo {
+            dst: tmp.path().join("dst/a.mp3"),
+            // not the real hash, so a reused one can be told from a new one
+            hash: "cached".to_string(),
+            mtime,
+            size: meta.len(),
+            config: "passthrough".to_string(),
+            ..Default::default()
+        };
+        let process = |hit: FileInfo| {
+            let cache = FileCache::from([(src.clone(), hit)]);
+            let (orphans, probes) = (OrphanCache::default(), ProbeCache::new());
+            let settings =
+                worker_settings(&args, None, None, None, &orphans, &cache, &probes);
+            let hashed = worker::HASHED.get();
+            let file = worker::process_file(&src, settings).unwrap();
+            (file.info.hash, worker::HASHED.get() - hashed)
+        };
+
+        // symlinked before, copied now
+        let config = "passthrough:symlink".to_string();
+        let changed = FileInfo { config, ..hit.clone() };
+        assert_eq!(process(changed), ("cached".to_string(), 0));
+        // the destination moved
+        let dst = tmp.path().join("old/a.mp3");
+        let moved = FileInfo { dst, ..hit.clone() };
+        assert_eq!(process(moved), ("cached".to_string(), 0));
+        let modified = FileInfo { size: 2, ..hit };
+        assert_eq!(process(modified), (worker::compute_hash(&src).unwrap(), 1));
+    }
+
+    // holds up the receiver at its first event, until the workers fill the channel
+    struct Stall(std::sync::Once);
+
+    impl Subscriber for Stall {
+        fn on_event(&self, _: &Event) {
+            self.0.call_once(|| std::thread::sleep(Duration::from_millis(200)));
+        }
+    }
+
+    #[test]
+    fn full_result_channel_doesnt_deadlock() {
+        // more files than the result channel holds, and then as many orphans
+        const FILES: usize = 5000;
+        let tmp = TempDir::new();
+        for i in 0..FILES {
+            let content = i.to_string();
+            tmp.file(&format!("src/{}/{i}.txt", i % 50), content.as_bytes());
+        }
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let stall = Stall(std::sync::Once::new());
+            let (_, events) = run_observed(&tmp, &[], &[&stall]);
+            let files = events.iter().filter(|e| e.starts_with("finished ")).count();
+            for dir in 1..50 {
+                fs::remove_dir_all(tmp.path().join(format!("src/{dir}"))).unwrap();
+            }
+            let stall = Stall(std::sync::Once::new());
+            let (stats, _) = run_observed(&tmp, &[], &[&stall]);
+            _ = done_tx.send((files, stats.orphans_removed));
+        });
+        let done = done_rx.recv_timeout(Duration::from_secs(60));
+        assert_eq!(done.expect("sync deadlocked"), (FILES, FILES - FILES / 50));
+    }
+
+    #[test]
+    fn reclaimed_outputs_are_recorded_like_new_ones() {
+        let tmp = TempDir::new();
+        tmp.file("src/A/x.mp3", b"x");
+        tmp.file("src/A/y.mp3", b"y");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        let dst = tmp.path().join("dst/B/x.mp3");
+        let old = tmp.path().join("dst/A/x.mp3");
+        let old = fs::File::options().write(true).open(old);
+        old.unwrap().set_modified(UNIX_EPOCH).unwrap();
+        fs::create_dir(tmp.path().join("src/B")).unwrap();
+        fs::rename(tmp.path().join("src/A/x.mp3"), tmp.path().join("src/B/x.mp3"))
+            .unwrap();
+        let recorded = || {
+            let (_, cache) = init_db(&tmp.path().join("db")).unwrap();
+            let info = &cache[&tmp.path().join("src/B/x.mp3")];
+            let modified = fs::metadata(&dst).unwrap().modified().unwrap();
+            (format!("{info:?}"), modified > UNIX_EPOCH)
+        };
+
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!(stats.reasons.get(&ReprocessReason::Renamed), Some(&1));
+        let reclaimed = recorded();
+        fs::remove_file(&dst).unwrap();
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!(stats.reasons.get(&ReprocessReason::DstMissing), Some(&1));
+        assert_eq!(reclaimed, recorded());
+        assert!(reclaimed.1, "reclaimed output kept its old mtime");
+    }
+
+    #[test]
+    fn moved_outputs_are_recorded_like_new_ones() {
+        let tmp = TempDir::new();
+        tmp.file("src/A/x.MP3", b"x");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        let old = tmp.path().join("dst/A/x.MP3");
+        let old = fs::File::options().write(true).open(old);
+        old.unwrap().set_modified(UNIX_EPOCH).unwrap();
+        // same source, new output path, so the old output is moved
+        let lowercase = ["--lowercase-extensions"];
+        let dst = tmp.path().join("dst/A/x.mp3");
+        let recorded = || {
+            let (_, cache) = init_db(&tmp.path().join("db")).unwrap();
+            let info = &cache[&tmp.path().join("src/A/x.MP3")];
+            let modified = fs::metadata(&dst).unwrap().modified().unwrap();
+            (format!("{info:?}"), modified > UNIX_EPOCH)
+        };
+
+        let (stats, _) = run_recorded(&tmp, &lowercase);
+        assert_eq!(stats.reasons.get(&ReprocessReason::Renamed), Some(&1));
+        assert!(!tmp.path().join("dst/A/x.MP3").exists());
+        let moved = recorded();
+        fs::remove_file(&dst).unwrap();
+        let (stats, _) = run_recorded(&tmp, &lowercase);
+        assert_eq!(stats.reasons.get(&ReprocessReason::DstMissing), Some(&1));
+        assert_eq!(moved, recorded());
+        assert!(moved.1, "moved output kept its old mtime");
+    }
+
+    fn finished(events: &[String], name: &str) -> bool {
+        events.contains(&format!("finished {name}"))
+    }
+
+    #[test]
+    fn fast_scan_sees_changes_below_skipped_dirs() {
+        let tmp = TempDir::new();
+        for file in ["A/a.jpg", "A/B/b.jpg", "A/B/c.jpg", "C/d.jpg", "E/F/e.jpg"] {
+            tmp.file(&format!("src/{file}"), file.as_bytes());
+        }
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &["--fast-scan"]);
+
+        let src = |rel: &str| tmp.path().join("src").join(rel);
+        // A and E keep their mtime, so they are skipped
+        tmp.file("src/A/B/new.jpg", b"new");
+        fs::remove_file(src("A/B/c.jpg")).unwrap();
+        fs::rename(src("C/d.jpg"), src("A/B/d.jpg")).unwrap();
+        fs::rename(src("E/F"), src("E/G")).unwrap();
+        let (stats, events) = run_recorded(&tmp, &["--fast-scan"]);
+
+        assert!(!finished(&events, "a.jpg"), "{events:?}");
+        assert!(finished(&events, "b.jpg") && finished(&events, "new.jpg"));
+        assert!(finished(&events, "d.jpg") && finished(&events, "e.jpg"));
+        let dst = |rel: &str| tmp.path().join("dst").join(rel);
+        let kept = ["A/a.jpg", "A/B/b.jpg", "A/B/new.jpg", "A/B/d.jpg", "E/G/e.jpg"];
+        for kept in kept {
+            assert!(dst(kept).exists(), "{kept} missing");
+        }
+        for gone in ["A/B/c.jpg", "C/d.jpg", "E/F/e.jpg"] {
+            assert!(!dst(gone).exists(), "{gone} kept");
+        }
+        // E/F was moved as a whole before the workers started
+        assert_eq!(stats.reasons.get(&ReprocessReason::Renamed), Some(&1));
+
+        // edits in place don't change the mtime of skipped directories
+        fs::write(src("A/a.jpg"), b"edited").unwrap();
+        let (_, events) = run_recorded(&tmp, &["--fast-scan"]);
+        assert!(!finished(&events, "a.jpg"), "{events:?}");
+        let (_, events) = run_recorded(&tmp, &["--fast-scan", "--full-scan"]);
+        assert!(finished(&events, "a.jpg"), "{events:?}");
+        assert_eq!(fs::read(dst("A/a.jpg")).unwrap(), b"edited");
+    }
+
+    #[test]
+    fn fast_scan_reads_everything_when_outputs_may_change() {
+        let tmp = TempDir::new();
+        tmp.file("src/A/a.jpg", b"a");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &["--fast-scan"]);
+        let (conn, _) = init_db(&tmp.path().join("db")).unwrap();
+
+        let reason = |extra: &[&str], changes: &[Change]| {
+            let common = ["-f", "opus", "-b", "128", "--fast-scan"];
+            let args = args(&[&common, extra].concat());
+            full_scan_reason(&args, &conn, changes).unwrap()
+        };
+        assert_eq!(reason(&[], &[]), None);
+        assert!(reason(&["--reencode-config", "opus:96"], &[]).is_some());
+        assert!(reason(&["--rebuild-destination"], &[]).is_some());
+        assert!(reason(&["--full-scan-every", "1"], &[]).is_some());
+        let old = vec![("bitrate".to_string(), "96".to_string())];
+        let new = vec![("bitrate".to_string(), "128".to_string())];
+        assert!(reason(&[], &settings::compare(&old, &new)).is_some());
+
+        let (mut conn, _) = init_db(&tmp.path().join("db")).unwrap();
+        db::mark_dirty(&mut conn, [tmp.path().join("src/A/a.jpg")].iter()).unwrap();
+        let args = args(&["-f", "opus", "-b", "128", "--fast-scan"]);
+        let reason = full_scan_reason(&args, &conn, &[]).unwrap();
+        assert!(reason.is_some_and(|r| r.contains("marked")));
+    }
+
+    #[test]
+    fn detected_types_claim_their_outputs() {
+        let tmp = TempDir::new();
+        // a flac named like an mp3 is transcoded to the output of the real opus
+        tmp.file("src/a.mp3", b"fLaC not really");
+        tmp.file("src/a.opus", b"OggS not really");
+        tmp.file("src/b.jpg", b"b");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+
+        let (stats, _) = run_recorded(&tmp, &["--detect-type"]);
+        let collided: Vec<_> = stats
+            .unsynced
+            .iter()
+            .filter(|row| row.detail.as_ref().is_some_and(|d| d.contains("collides")))
+            .collect();
+        assert_eq!(collided.len(), 1, "{:?}", stats.failed);
+        assert!(tmp.path().join("dst/b.jpg").exists());
+    }
+
+    #[test]
+    fn renamed_dir_moves_with_its_playlist_and_art() {
+        let tmp = TempDir::new();
+        tmp.file("src/Old/a.txt", b"a");
+        tmp.file("src/Old/b.txt", b"b");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        // as --generate-playlists and --extract-art would have left them
+        tmp.file("dst/Old/album.m3u8", b"#EXTM3U\n");
+        let cover = tmp.file("dst/Old/cover.jpg", b"cover");
+        let (conn, _) = init_db(&tmp.path().join("db")).unwrap();
+        let from = tmp.path().join("src/Old/a.txt");
+        conn.execute(
+            "INSERT INTO art (dst_path, src_path) VALUES (?1, ?2)",
+            rusqlite::params![cover.to_string_lossy(), from.to_string_lossy()],
+        )
+        .unwrap();
+
+        let src = tmp.path().join("src");
+        fs::rename(src.join("Old"), src.join("New")).unwrap();
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!((stats.reclaimed, stats.passed_through), (0, 0));
+        for name in ["a.txt", "b.txt", "album.m3u8", "cover.jpg"] {
+            assert!(tmp.path().join("dst/New").join(name).exists(), "{name} missing");
+        }
+        assert!(!tmp.path().join("dst/Old").exists());
+        let art = db::load_art(&conn).unwrap();
+        let moved = tmp.path().join("dst/New/cover.jpg");
+        assert_eq!(art.get(&moved), Some(&src.join("New/a.txt")));
+        assert_eq!(art.len(), 1);
+    }
+
+    #[test]
+    fn renamed_dir_with_untracked_files_is_reclaimed_per_file() {
+        let tmp = TempDir::new();
+        tmp.file("src/Old/a.txt", b"a");
+        fs::create_dir(tmp.path().join("dst")).unwrap();
+        run_recorded(&tmp, &[]);
+        tmp.file("dst/Old/cover.jpg", b"not extracted by sidechain");
+
+        let src = tmp.path().join("src");
+        fs::rename(src.join("Old"), src.join("New")).unwrap();
+        let (stats, _) = run_recorded(&tmp, &[]);
+        assert_eq!(stats.reclaimed, 1);
+        assert!(tmp.path().join("dst/Old/cover.jpg").exists());
+        assert!(tmp.path().join("dst/New/a.txt").exists());
+    }
+
+    fn bitrates_ok(extra: &[&str]) -> bool {
+        check_bitrates(&args(extra)).is_ok()
+    }
+
+    #[test]
+    fn bitrate_checked_for_mono_and_stereo() {
+        assert!(bitrates_ok(&["-f", "opus", "-b", "255"]));
+        // fine for stereo, too much for a mono source
+        assert!(!bitrates_ok(&["-f", "opus", "-b", "300"]));
+        assert!(!bitrates_ok(&["-f", "opus", "-b", "511"]));
+        assert!(!bitrates_ok(&["-f", "mp3", "-b", "321"]));
+        assert!(bitrates_ok(&["-f", "flac", "-b", "9000"]));
+    }
+
+    #[test]
+    fn bitrate_per_channel_checked_up_to_channels() {
+        let per_channel = &["-f", "opus", "--bitrate-per-channel", "255"];
+        assert!(bitrates_ok(per_channel));
+        assert!(bitrates_ok(&[per_channel as &[_], &["--channels", "6"]].concat()));
+        assert!(!bitrates_ok(&["-f", "opus", "--bitrate-per-channel", "256"]));
+        // mono is checked too, even though it's below the stereo default
+        assert!(!bitrates_ok(&["-f", "opus", "--bitrate-per-channel", "5"]));
+        // mp3 doesn't scale with channels, so 6 channels of 64 is too much
+        let mp3 = &["-f", "mp3", "--bitrate-per-channel", "64"];
+        assert!(bitrates_ok(&[mp3 as &[_], &["--channels", "2"]].concat()));
+        assert!(!bitrates_ok(&[mp3 as &[_], &["--channels", "6"]].concat()));
+        // without --channels a 5.1 or 7.1 source keeps all of them
+        assert!(!bitrates_ok(mp3));
+        assert!(bitrates_ok(&["-f", "mp3", "--bitrate-per-channel", "40"]));
+    }
+
+    #[test]
+    fn bitrate_rule_checked_after_downmix() {
+        let rule = &["-f", "opus", "--bitrate-rule", "channels=6:600"];
+        assert!(bitrates_ok(rule));
+        // downmixed to stereo, 600 is more than opus takes for two channels
+        assert!(!bitrates_ok(&[rule as &[_], &["--channels", "2"]].concat()));
+        assert!(bitrates_ok(&[rule as &[_], &["--channels", "4"]].concat()));
+        assert!(!bitrates_ok(&["-f", "opus", "--bitrate-rule", "channels=1:256"]));
+    }
+}
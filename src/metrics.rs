@@ -0,0 +1,204 @@
+use std::{fmt::Write, fs, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use crate::{
+    WorkStats,
+    db::{self, Totals},
+};
+
+/// Write the outcome of a run and the totals of the database as a Prometheus
+/// textfile, for node_exporter's textfile collector. Written for every run, also
+/// ones that failed before syncing anything. Failures are only logged, so they
+/// never change the run's exit status.
+pub fn write(
+    path: &Path,
+    db_path: &Path,
+    result: &Result<WorkStats>,
+    finished_at: i64,
+    duration: Duration,
+) {
+    // a run that failed during setup may not have left a usable database, its
+    // outcome is still reported
+    let library = match db_path.exists() {
+        true => read_library(db_path)
+            .inspect_err(|e| log::warn!("failed to read totals for metrics: {e:#}"))
+            .ok(),
+        false => None,
+    };
+    match render(library.as_ref(), result, finished_at, duration)
+        .and_then(|text| write_atomic(path, &text))
+    {
+        Ok(()) => log::debug!("wrote metrics to {}", path.display()),
+        Err(e) => log::warn!("failed to write metrics to {}: {e:#}", path.display()),
+    }
+}
+
+// metric names are part of the interface, dashboards break if they change:
+//   sidechain_last_run_timestamp_seconds   when the last run finished
+//   sidechain_last_run_duration_seconds    how long it took
+//   sidechain_last_run_success             0 if it failed or was aborted
+//   sidechain_last_run_files{result=...}   files per outcome, result is one of
+//                                          transcoded, passed_through, reclaimed,
+//                                          cached, failed or conflict
+//   sidechain_last_run_orphans_removed     orphaned outputs removed
+//   sidechain_last_run_bytes_written       size of the outputs written
+//   sidechain_files                        tracked source files
+//   sidechain_orphaned_files               outputs in their --orphan-grace period
+//   sidechain_source_bytes                 size of the tracked sources
+//   sidechain_destination_bytes            size of their outputs
+//   sidechain_runs_total                   runs recorded in the database
+//   sidechain_failed_files_total           failed files summed over those runs
+fn render(
+    library: Option<&Library>,
+    result: &Result<WorkStats>,
+    finished_at: i64,
+    duration: Duration,
+) -> Result<String> {
+    let mut m = Metrics::default();
+    m.gauge(
+        "sidechain_last_run_timestamp_seconds",
+        "Unix time the last sync finished.",
+        finished_at as f64,
+    );
+    m.gauge(
+        "sidechain_last_run_duration_seconds",
+        "Duration of the last sync.",
+        duration.as_secs_f64(),
+    );
+    let success = matches!(result, Ok(stats) if stats.aborted.is_none());
+    m.gauge(
+        "sidechain_last_run_success",
+        "Whether the last sync finished without failing or aborting.",
+        success as u8 as f64,
+    );
+    if let Ok(stats) = result {
+        m.labelled_gauge(
+            "sidechain_last_run_files",
+            "Files handled by the last sync, by outcome.",
+            "result",
+            &[
+                ("transcoded", stats.transcoded as f64),
+                ("passed_through", stats.passed_through as f64),
+                ("reclaimed", stats.reclaimed as f64),
+                ("cached", stats.skips as f64),
+                ("failed", stats.fails as f64),
+                ("conflict", stats.conflicts as f64),
+            ],
+        );
+        m.gauge(
+            "sidechain_last_run_orphans_removed",
+            "Orphaned outputs removed by the last sync.",
+            stats.orphans_removed as f64,
+        );
+        m.gauge(
+            "sidechain_last_run_bytes_written",
+            "Size of the outputs written by the last sync.",
+            stats.bytes_written as f64,
+        );
+    }
+    if let Some(library) = library {
+        library_metrics(&mut m, library);
+    }
+    Ok(m.text)
+}
+
+// what the database tracks, independent of the last run
+struct Library {
+    totals: Totals,
+    destination_bytes: u64,
+}
+
+fn read_library(db_path: &Path) -> Result<Library> {
+    let conn = db::connect(db_path)?;
+    let totals = db::totals(&conn)?;
+    // symlink_metadata, so symlinked outputs count as links and not sources
+    let destination_bytes: u64 = db::load_paths(&conn, None)?
+        .iter()
+        .filter_map(|(_, dst)| dst.symlink_metadata().ok())
+        .map(|meta| meta.len())
+        .sum();
+    Ok(Library { totals, destination_bytes })
+}
+
+fn library_metrics(m: &mut Metrics, library: &Library) {
+    let Library { totals, destination_bytes } = library;
+    m.gauge(
+        "sidechain_files",
+        "Source files tracked in the database.",
+        totals.tracked as f64,
+    );
+    m.gauge(
+        "sidechain_orphaned_files",
+        "Outputs whose source is gone, kept for their grace period.",
+        totals.orphaned as f64,
+    );
+    m.gauge(
+        "sidechain_source_bytes",
+        "Size of the tracked source files.",
+        totals.source_bytes as f64,
+    );
+    m.gauge(
+        "sidechain_destination_bytes",
+        "Size of the outputs of the tracked source files.",
+        *destination_bytes as f64,
+    );
+    m.counter(
+        "sidechain_runs_total",
+        "Syncs recorded in the database.",
+        totals.runs as f64,
+    );
+    m.counter(
+        "sidechain_failed_files_total",
+        "Failed files summed over the recorded syncs.",
+        totals.failed_files as f64,
+    );
+}
+
+#[derive(Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    // one sample per value of a single label
+    fn labelled_gauge(
+        &mut self,
+        name: &str,
+        help: &str,
+        label: &str,
+        samples: &[(&str, f64)],
+    ) {
+        self.header(name, help, "gauge");
+        for (value_of_label, value) in samples {
+            let _ =
+                writeln!(self.text, "{name}{{{label}=\"{value_of_label}\"}} {value}");
+        }
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.text, "{name} {value}");
+    }
+
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.text, "# HELP {name} {help}");
+        let _ = writeln!(self.text, "# TYPE {name} {kind}");
+    }
+}
+
+// the collector may read at any moment, so the file is swapped in whole
+fn write_atomic(path: &Path, text: &str) -> Result<()> {
+    let name = path.file_name().context("metrics file has no file name")?;
+    let tmp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    fs::write(&tmp, text)?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use argh::FromArgs;
+use rusqlite::Connection;
+
+use crate::{db, diff::json_string, warnings::group_thousands};
+
+/// List the sources that have no output in the destination, by why and by
+/// directory: they failed or collided in the last sync, an existing file was in
+/// the way, or their output is gone. Sources added since the last sync aren't
+/// known yet. A healthy library has none.
+#[derive(FromArgs, Debug, Clone)]
+pub struct MissingArgs {
+    /// path to SQLite database
+    #[argh(option, short = 'd')]
+    pub db_path: PathBuf,
+
+    /// print the missing files as JSON
+    #[argh(switch)]
+    pub json: bool,
+}
+
+/// Why a source has no output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    Failed,
+    /// Maps to the same output as another source, which got it.
+    Collision,
+    /// Something not from sidechain is at the output path, and --no-delete kept
+    /// it.
+    Conflict,
+    /// The output was deleted from the destination since it was produced.
+    OutputMissing,
+}
+
+impl Reason {
+    /// Value stored in the unsynced table.
+    pub fn as_db_str(&self) -> &'static str {
+        match self {
+            Reason::Failed => "failed",
+            Reason::Collision => "collision",
+            Reason::Conflict => "conflict",
+            Reason::OutputMissing => "output_missing",
+        }
+    }
+
+    pub fn from_db_str(s: &str) -> Option<Reason> {
+        match s {
+            "failed" => Some(Reason::Failed),
+            "collision" => Some(Reason::Collision),
+            "conflict" => Some(Reason::Conflict),
+            "output_missing" => Some(Reason::OutputMissing),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Reason::Failed => "failed",
+            Reason::Collision => "collides with another source",
+            Reason::Conflict => "destination already exists",
+            Reason::OutputMissing => "output missing from the destination",
+        })
+    }
+}
+
+/// A source without an output. `detail` is the error, the other source or the
+/// output path, depending on the reason.
+pub struct Missing {
+    pub src: PathBuf,
+    pub reason: Reason,
+    pub detail: Option<String>,
+}
+
+pub fn run(args: MissingArgs) -> Result<()> {
+    let mut conn = db::connect(&args.db_path)?;
+    db::init(&mut conn)?;
+
+    let missing = find(&conn)?;
+    if args.json {
+        println!("{}", to_json(&missing));
+        return Ok(());
+    }
+    if missing.is_empty() {
+        println!("no missing files");
+        return Ok(());
+    }
+
+    for (reason, dirs) in group(&missing) {
+        let files = dirs.values().map(Vec::len).sum::<usize>();
+        println!("{reason} ({} files):", group_thousands(files));
+        for (dir, files) in dirs {
+            println!("  {}:", dir.display());
+            for file in files {
+                let name = file.src.file_name().unwrap_or_default().to_string_lossy();
+                match &file.detail {
+                    Some(detail) => println!("    {name}: {detail}"),
+                    None => println!("    {name}"),
+                }
+            }
+        }
+    }
+    println!("{} source files have no output", group_thousands(missing.len()));
+    Ok(())
+}
+
+/// The sources without an output, sorted by path. Recorded entries are dropped
+/// once their source is gone or has an output after all.
+pub fn find(conn: &Connection) -> Result<Vec<Missing>> {
+    let cache = db::load_cache(conn)?;
+    // symlink_metadata, so dangling passthrough links still count as outputs
+    let has_output = |src: &Path| {
+        cache.get(src).is_some_and(|info| {
+            info.orphaned_at.is_none() && info.dst.symlink_metadata().is_ok()
+        })
+    };
+
+    let mut missing: Vec<Missing> = db::load_unsynced(conn)?
+        .into_iter()
+        .filter(|row| row.src.exists() && !has_output(&row.src))
+        .map(|row| Missing { src: row.src, reason: row.reason, detail: row.detail })
+        .collect();
+    let listed: HashSet<PathBuf> = missing.iter().map(|m| m.src.clone()).collect();
+    // outputs deleted behind sidechain's back. the next sync produces them again
+    for (src, info) in &cache {
+        if info.orphaned_at.is_none()
+            && !listed.contains(src)
+            && info.dst.symlink_metadata().is_err()
+            && src.exists()
+        {
+            missing.push(Missing {
+                src: src.clone(),
+                reason: Reason::OutputMissing,
+                detail: Some(info.dst.display().to_string()),
+            });
+        }
+    }
+    missing.sort_by(|a, b| a.src.cmp(&b.src));
+    Ok(missing)
+}
+
+// by reason, then by source directory
+fn group(missing: &[Missing]) -> BTreeMap<Reason, BTreeMap<&Path, Vec<&Missing>>> {
+    let mut groups = BTreeMap::<Reason, BTreeMap<&Path, Vec<&Missing>>>::new();
+    for file in missing {
+        let dir = file.src.parent().unwrap_or(Path::new(""));
+        let dirs = groups.entry(file.reason).or_default();
+        dirs.entry(dir).or_default().push(file);
+    }
+    groups
+}
+
+// {"total":..,"by_reason":{"failed":..},"files":[{"path":..,"reason":..,..}]}
+fn to_json(missing: &[Missing]) -> String {
+    let mut counts = BTreeMap::<Reason, usize>::new();
+    for file in missing {
+        *counts.entry(file.reason).or_default() += 1;
+    }
+    let counts: Vec<String> = counts
+        .into_iter()
+        .map(|(reason, n)| format!("\"{}\":{n}", reason.as_db_str()))
+        .collect();
+    let files: Vec<String> = missing
+        .iter()
+        .map(|file| {
+            let detail =
+                file.detail.as_deref().map_or("null".to_string(), json_string);
+            format!(
+                "{{\"path\":{},\"reason\":\"{}\",\"detail\":{detail}}}",
+                json_string(&file.src.to_string_lossy()),
+                file.reason.as_db_str(),
+            )
+        })
+        .collect();
+    format!(
+        "{{\"total\":{},\"by_reason\":{{{}}},\"files\":[{}]}}",
+        missing.len(),
+        counts.join(","),
+        files.join(","),
+    )
+}
@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use notify_rust::Notification;
+
+use crate::WorkStats;
+
+/// Send a desktop notification summarizing a finished run. Failures are only
+/// logged, since not every environment has a notification service.
+pub fn run_finished(result: &Result<WorkStats>, duration: Duration) {
+    let duration = format_duration(duration);
+    #[cfg_attr(not(all(unix, not(target_os = "macos"))), allow(unused_variables))]
+    let (body, failed) = match result {
+        Ok(stats) => {
+            let mut body = format!(
+                "{} files synced, {} failures, {duration}",
+                stats.successes, stats.fails,
+            );
+            if let Some(reason) = &stats.aborted {
+                body = format!("aborted because {reason}\n{body}");
+            }
+            (body, stats.fails > 0 || stats.aborted.is_some())
+        }
+        Err(e) => (format!("sync failed after {duration}: {e}"), true),
+    };
+
+    let mut notification = Notification::new();
+    notification.appname("sidechain").summary("sidechain").body(&body);
+    // urgency is only part of the freedesktop notification spec
+    #[cfg(all(unix, not(target_os = "macos")))]
+    notification.urgency(match failed {
+        true => notify_rust::Urgency::Critical,
+        false => notify_rust::Urgency::Normal,
+    });
+
+    if let Err(e) = notification.show() {
+        log::warn!("failed to send desktop notification: {e}");
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs} seconds"),
+        60..3600 => format!("{} minutes", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs / 60 % 60),
+    }
+}
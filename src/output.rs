@@ -0,0 +1,144 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use anstream::println;
+use anstyle::{AnsiColor, Style};
+
+use crate::{
+    events::{describe_reasons, reencode_progress, Event, Subscriber},
+    worker::FileStatus,
+};
+
+const GREEN: Style = AnsiColor::Green.on_default().bold();
+const YELLOW: Style = AnsiColor::Yellow.on_default().bold();
+const RED: Style = AnsiColor::Red.on_default().bold();
+const DIM: Style = Style::new().dimmed();
+
+/// Concise per-file lines for interactive use, written to stdout. Kept separate
+/// from the log (stderr) so either can be redirected on its own.
+pub struct Pretty {
+    enabled: bool,
+    src_root: PathBuf,
+    width: usize,
+}
+
+impl Pretty {
+    pub fn new(src_root: &Path, porcelain: bool) -> Self {
+        let width = terminal_size::terminal_size()
+            .map(|(w, _)| w.0 as usize)
+            .unwrap_or(80);
+        Pretty {
+            enabled: !porcelain && std::io::stdout().is_terminal(),
+            src_root: src_root.to_path_buf(),
+            width,
+        }
+    }
+
+    pub fn success(&self, verb: &str, src: &Path) {
+        if !self.enabled {
+            return;
+        }
+        let path = self.fit(src, verb.chars().count() + 3);
+        println!("{GREEN}✓{GREEN:#} {verb} {path}");
+    }
+
+    pub fn warning(&self, verb: &str, src: &Path) {
+        if !self.enabled {
+            return;
+        }
+        let path = self.fit(src, verb.chars().count() + 3);
+        println!("{YELLOW}!{YELLOW:#} {verb} {path}");
+    }
+
+    pub fn failure(&self, src: &Path, err: &anyhow::Error) {
+        if !self.enabled {
+            return;
+        }
+        let path = self.fit(src, "✗ failed ".chars().count());
+        println!("{RED}✗{RED:#} failed {path}");
+        println!("  {DIM}{err}{DIM:#}");
+    }
+
+    pub fn progress(&self, src: &Path, percent: f32) {
+        if !self.enabled {
+            return;
+        }
+        let suffix = format!(" ({percent}%)");
+        let path = self.fit(src, "… encoding ".chars().count() + suffix.len());
+        println!("{DIM}…{DIM:#} encoding {path}{DIM}{suffix}{DIM:#}");
+    }
+
+    pub fn failed_dir(&self, line: &str) {
+        if self.enabled {
+            println!("{RED}✗{RED:#} {line}");
+        }
+    }
+
+    pub fn summary(&self, line: &str) {
+        if self.enabled {
+            println!("{line}");
+        }
+    }
+
+    // path relative to the source root, truncated to fit after a prefix
+    fn fit(&self, src: &Path, prefix_len: usize) -> String {
+        let rel = src.strip_prefix(&self.src_root).unwrap_or(src);
+        truncate_middle(&rel.to_string_lossy(), self.width.saturating_sub(prefix_len))
+    }
+}
+
+impl Subscriber for Pretty {
+    fn on_event(&self, event: &Event) {
+        match *event {
+            Event::FileFinished { file, .. } => {
+                if !file.tag_issues.is_empty() {
+                    self.warning("tags differ", &file.src);
+                }
+                let src = &file.src;
+                match file.status {
+                    FileStatus::PassedThrough => self.success("passed through", src),
+                    FileStatus::Transcoded => self.success("transcoded", src),
+                    FileStatus::Reclaimed => self.success("reclaimed", src),
+                    FileStatus::Skipped => {}
+                    FileStatus::Conflict => self.warning("conflict", src),
+                }
+            }
+            Event::FileFailed { src, error } => self.failure(src, error),
+            Event::Progress { src, percent } => self.progress(src, percent),
+            Event::OrphanRemoved { .. } | Event::OrphanFailed { .. } => {}
+            // an aborted run's log explains why, which the summary can't
+            Event::RunFinished { stats, .. } if stats.aborted.is_some() => {}
+            Event::RunFinished { stats, started } => {
+                self.summary(&format!(
+                    "processed {}/{} files successfully ({} cached) in {:.2} seconds",
+                    stats.successes,
+                    stats.successes + stats.fails,
+                    stats.skips,
+                    started.elapsed().as_secs_f32(),
+                ));
+                if let Some(reasons) = describe_reasons(&stats.reasons) {
+                    self.summary(&format!("produced because: {reasons}"));
+                }
+                if stats.reencodes_deferred > 0 {
+                    self.summary(&reencode_progress(stats));
+                }
+            }
+        }
+    }
+}
+
+fn truncate_middle(s: &str, max: usize) -> String {
+    let len = s.chars().count();
+    if len <= max {
+        return s.to_string();
+    }
+    if max <= 1 {
+        return "…".repeat(max);
+    }
+    let keep = max - 1;
+    let head: String = s.chars().take(keep / 2).collect();
+    let tail: String = s.chars().skip(len - (keep - keep / 2)).collect();
+    format!("{head}…{tail}")
+}
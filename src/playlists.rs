@@ -0,0 +1,87 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::util::has_extension;
+
+pub const PLAYLIST_NAME: &str = "album.m3u8";
+
+// first line after the header of every generated playlist. playlists without it
+// were put there by someone else and are left alone
+const MARKER: &str = "# generated by sidechain, changes will be overwritten";
+
+// audio formats listed besides the output format, for passed-through files
+const AUDIO_EXTS: &[&str] = &[
+    "aac", "aiff", "flac", "m4a", "mp3", "ogg", "opus", "wav", "wma", "wv",
+];
+
+/// Write or update the playlist of each directory, listing its audio files in
+/// name order. Playlists of directories left without audio files are removed.
+/// Returns the number of playlists written.
+pub fn update<'a>(
+    dirs: impl Iterator<Item = &'a PathBuf>,
+    target_ext: &str,
+) -> Result<usize> {
+    let mut exts: Vec<String> = AUDIO_EXTS.iter().map(|e| e.to_string()).collect();
+    exts.push(target_ext.to_string());
+
+    let mut written = 0;
+    for dir in dirs {
+        match update_dir(dir, &exts) {
+            Ok(true) => written += 1,
+            Ok(false) => {}
+            Err(e) => {
+                log::warn!("failed to update playlist in {}: {e}", dir.display())
+            }
+        }
+    }
+    Ok(written)
+}
+
+fn update_dir(dir: &Path, exts: &[String]) -> Result<bool> {
+    let path = dir.join(PLAYLIST_NAME);
+    let existing = fs::read_to_string(&path).ok();
+    if let Some(existing) = &existing
+        && !is_generated(existing)
+    {
+        log::warn!("not overwriting playlist {}", path.display());
+        return Ok(false);
+    }
+
+    let mut entries = Vec::new();
+    if let Ok(read_dir) = fs::read_dir(dir) {
+        for entry in read_dir.flatten() {
+            let entry_path = entry.path();
+            if has_extension(&entry_path, exts) {
+                entries.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    entries.sort();
+
+    if entries.is_empty() {
+        // lets the now empty directory be removed
+        if existing.is_some() {
+            fs::remove_file(&path)?;
+        }
+        return Ok(false);
+    }
+
+    let mut contents = format!("#EXTM3U\n{MARKER}\n");
+    for entry in &entries {
+        contents.push_str(entry);
+        contents.push('\n');
+    }
+    if existing.as_deref() == Some(contents.as_str()) {
+        return Ok(false);
+    }
+    fs::write(&path, contents)?;
+    Ok(true)
+}
+
+fn is_generated(contents: &str) -> bool {
+    contents.lines().nth(1) == Some(MARKER)
+}
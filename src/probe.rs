@@ -0,0 +1,230 @@
+use std::{collections::HashMap, path::Path, process::Command};
+
+use anyhow::{ensure, Context, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioInfo {
+    pub channels: u32,
+    // None if ffprobe didn't report it (or the record predates it being cached)
+    pub sample_rate: Option<u32>,
+    // None for lossy codecs, which have no fixed bit depth
+    pub bit_depth: Option<u32>,
+}
+
+/// What one ffprobe of a source file reports, kept in the probe table so any
+/// feature needing it probes each source only once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeInfo {
+    pub codec: String,
+    // bits per second of the audio stream, or of the whole file if the stream
+    // doesn't report one (e.g. flac)
+    pub bitrate: Option<u32>,
+    pub sample_rate: Option<u32>,
+    pub channels: u32,
+    pub bit_depth: Option<u32>,
+    // in seconds
+    pub duration: Option<f64>,
+    pub has_embedded_art: bool,
+    // in the container
+    pub chapters: u32,
+    // of the container and the audio stream, keyed by their name as ffprobe
+    // reports it. container tags win over stream tags of the same name
+    pub tags: HashMap<String, String>,
+}
+
+impl ProbeInfo {
+    /// The part of the probe that decides how a file is encoded.
+    pub fn audio(&self) -> AudioInfo {
+        AudioInfo {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bit_depth: self.bit_depth,
+        }
+    }
+}
+
+/// Probe the first audio stream, the container and its chapters of a file.
+pub fn probe_file(path: &Path) -> Result<ProbeInfo> {
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-show_entries")
+        .arg(
+            "stream=codec_type,codec_name,channels,sample_rate,bits_per_sample,\
+             bits_per_raw_sample,bit_rate:stream_disposition=attached_pic:\
+             stream_tags:format=duration,bit_rate:format_tags:chapter=id",
+        )
+        .arg("-of").arg("default")
+        .arg(path)
+        .output()
+        .context("ffprobe invocation failed")?;
+    ensure!(
+        output.status.success(),
+        "ffprobe failed with status: {}",
+        output.status,
+    );
+    parse_probe(&String::from_utf8_lossy(&output.stdout))
+}
+
+pub(crate) fn parse_probe(stdout: &str) -> Result<ProbeInfo> {
+    // [STREAM], [FORMAT] and [CHAPTER] sections of key=value lines, with N/A or 0
+    // for values that don't apply and TAG: before tag names. cover art shows up as
+    // a video stream
+    let mut sections: Vec<(&str, Vec<(&str, &str)>)> = Vec::new();
+    for line in stdout.lines() {
+        let line = line.trim();
+        if line.starts_with("[/") {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name, Vec::new()));
+        } else if let Some((key, value)) = line.split_once('=')
+            && let Some((_, entries)) = sections.last_mut()
+        {
+            entries.push((key, value));
+        }
+    }
+    let get = |entries: &[(&str, &str)], key: &str| {
+        entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v.to_string())
+    };
+    let number = |entries: &[(&str, &str)], key: &str| {
+        get(entries, key)
+            .and_then(|v| v.parse::<u32>().ok())
+            .filter(|&n| n > 0)
+    };
+    let streams: Vec<&[(&str, &str)]> = sections
+        .iter()
+        .filter(|(name, _)| *name == "STREAM")
+        .map(|(_, entries)| entries.as_slice())
+        .collect();
+    let format = sections
+        .iter()
+        .find(|(name, _)| *name == "FORMAT")
+        .map(|(_, entries)| entries.as_slice())
+        .unwrap_or_default();
+    let audio = *streams
+        .iter()
+        .find(|e| get(e, "codec_type").as_deref() == Some("audio"))
+        .context("no audio stream found")?;
+    let has_embedded_art = streams.iter().any(|e| {
+        get(e, "codec_type").as_deref() == Some("video")
+            && get(e, "DISPOSITION:attached_pic").as_deref() == Some("1")
+    });
+    let chapters = sections.iter().filter(|(name, _)| *name == "CHAPTER").count();
+    let mut tags = HashMap::new();
+    for (key, value) in format.iter().chain(audio) {
+        if let Some(key) = key.strip_prefix("TAG:") {
+            tags.entry(key.to_string()).or_insert_with(|| value.to_string());
+        }
+    }
+
+    Ok(ProbeInfo {
+        codec: get(audio, "codec_name").unwrap_or_default(),
+        bitrate: number(audio, "bit_rate").or_else(|| number(format, "bit_rate")),
+        sample_rate: number(audio, "sample_rate"),
+        channels: number(audio, "channels")
+            .context("failed to parse channel count from ffprobe output")?,
+        bit_depth: number(audio, "bits_per_raw_sample")
+            .or_else(|| number(audio, "bits_per_sample")),
+        duration: get(format, "duration").and_then(|v| v.parse().ok()),
+        has_embedded_art,
+        chapters: chapters as u32,
+        tags,
+    })
+}
+
+/// Read the container and first audio stream tags of a file, like the probe has
+/// them. For files that aren't sources, which have no probe.
+pub fn probe_tags(path: &Path) -> Result<HashMap<String, String>> {
+    #[rustfmt::skip]
+    let output = Command::new("ffprobe")
+        .arg("-v").arg("error")
+        .arg("-select_streams").arg("a:0")
+        .arg("-show_entries").arg("format_tags:stream_tags")
+        .arg("-of").arg("default=noprint_wrappers=1")
+        .arg(path)
+        .output()
+        .context("ffprobe invocation failed")?;
+    ensure!(
+        output.status.success(),
+        "ffprobe failed with status: {}",
+        output.status,
+    );
+
+    // format tags come first and win over stream tags of the same name
+    let mut tags = HashMap::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let tag = line.strip_prefix("TAG:").and_then(|t| t.split_once('='));
+        if let Some((key, value)) = tag {
+            tags.entry(key.to_string()).or_insert_with(|| value.to_string());
+        }
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // what ffprobe reports for an audiobook with a cover and two chapters
+    pub(crate) const AUDIOBOOK: &str = "\
+[STREAM]
+codec_name=aac
+codec_type=audio
+sample_rate=44100
+channels=2
+bits_per_sample=0
+bits_per_raw_sample=N/A
+bit_rate=64000
+DISPOSITION:attached_pic=0
+TAG:language=eng
+TAG:title=Stream title
+[/STREAM]
+[STREAM]
+codec_name=mjpeg
+codec_type=video
+sample_rate=N/A
+channels=N/A
+bits_per_sample=N/A
+bits_per_raw_sample=8
+bit_rate=N/A
+DISPOSITION:attached_pic=1
+TAG:comment=Cover (front)
+[/STREAM]
+[CHAPTER]
+id=0
+[/CHAPTER]
+[CHAPTER]
+id=1
+[/CHAPTER]
+[FORMAT]
+duration=3600.500000
+bit_rate=65000
+TAG:title=The Book
+TAG:artist=Someone
+[/FORMAT]
+";
+
+    #[test]
+    fn one_probe_has_chapters_and_tags() {
+        let probe = parse_probe(AUDIOBOOK).unwrap();
+        assert_eq!((probe.codec.as_str(), probe.channels), ("aac", 2));
+        assert_eq!((probe.bitrate, probe.bit_depth), (Some(64000), None));
+        assert_eq!(probe.duration, Some(3600.5));
+        assert!(probe.has_embedded_art);
+        assert_eq!(probe.chapters, 2);
+        // the container's title wins, and the cover's tags aren't the file's
+        let tag = |key: &str| probe.tags.get(key).map(String::as_str);
+        assert_eq!(tag("title"), Some("The Book"));
+        assert_eq!((tag("artist"), tag("language")), (Some("Someone"), Some("eng")));
+        assert_eq!(tag("comment"), None);
+    }
+
+    #[test]
+    fn probe_needs_audio_but_not_chapters() {
+        let cover_only = AUDIOBOOK.replace("codec_type=audio", "codec_type=data");
+        assert!(parse_probe(&cover_only).is_err());
+        let chapterless = AUDIOBOOK.replace("[CHAPTER]", "[OTHER]");
+        assert_eq!(parse_probe(&chapterless).unwrap().chapters, 0);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{
+    db,
+    playlists::PLAYLIST_NAME,
+    worker::{hash_like, FileCache},
+};
+
+// how many files of a matched directory are hashed to confirm the match
+const SAMPLES: usize = 2;
+
+/// Detect source directories that were renamed as a whole, and move their
+/// destination directory in one go instead of reclaiming every file separately.
+/// Matched files are rewritten in the cache (and database) under their new source
+/// path, so workers see them as plain cache hits. Returns the number of moved
+/// directories.
+pub fn move_renamed_dirs(
+    conn: &mut Connection,
+    cache: &mut FileCache,
+    files: &[PathBuf],
+    map_dst: impl Fn(&Path) -> Result<PathBuf>,
+) -> Result<usize> {
+    // new files (not in the cache) grouped by source directory
+    let mut new_dirs: HashMap<&Path, Vec<&PathBuf>> = HashMap::new();
+    for src in files {
+        if !cache.contains_key(src)
+            && let Some(parent) = src.parent()
+        {
+            new_dirs.entry(parent).or_default().push(src);
+        }
+    }
+    if new_dirs.is_empty() {
+        return Ok(0);
+    }
+
+    // cached files whose source directory vanished entirely, grouped the same way
+    let active_dirs: std::collections::HashSet<&Path> =
+        files.iter().filter_map(|src| src.parent()).collect();
+    let mut orphan_dirs: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for src in cache.keys() {
+        if let Some(parent) = src.parent()
+            && !active_dirs.contains(parent)
+        {
+            orphan_dirs.entry(parent.to_path_buf()).or_default().push(src.clone());
+        }
+    }
+
+    // index orphaned directories by their sorted file names
+    let mut by_names: HashMap<Vec<String>, Vec<PathBuf>> = HashMap::new();
+    for (dir, srcs) in &orphan_dirs {
+        by_names.entry(file_names(srcs.iter())).or_default().push(dir.clone());
+    }
+
+    // extracted covers by path, with the file they were extracted from
+    let art = db::load_art(conn)?;
+    let mut moved = 0;
+    for (new_dir, new_srcs) in new_dirs {
+        let Some(old_dirs) = by_names.get_mut(&file_names(new_srcs.iter().copied()))
+        else {
+            continue;
+        };
+        let Some(pos) = old_dirs
+            .iter()
+            .position(|old| dir_matches(cache, &orphan_dirs[old], new_dir))
+        else {
+            continue;
+        };
+        let old_dir = old_dirs.swap_remove(pos);
+
+        let old_srcs = &orphan_dirs[&old_dir];
+        match move_dir(conn, cache, &art, old_srcs, new_dir, &map_dst) {
+            Ok(true) => {
+                log::info!(
+                    "moved renamed directory {} to {}",
+                    old_dir.display(),
+                    new_dir.display(),
+                );
+                moved += 1;
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!(
+                "failed to move renamed directory {}: {e}",
+                old_dir.display(),
+            ),
+        }
+    }
+
+    Ok(moved)
+}
+
+fn file_names<'a>(srcs: impl Iterator<Item = &'a PathBuf>) -> Vec<String> {
+    let mut names: Vec<String> = srcs
+        .filter_map(|src| src.file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .collect();
+    names.sort_unstable();
+    names
+}
+
+// names already match, compare sizes and then hash a few samples
+fn dir_matches(cache: &FileCache, old_srcs: &[PathBuf], new_dir: &Path) -> bool {
+    let sizes_match = old_srcs.iter().all(|old| {
+        let new = new_dir.join(old.file_name().unwrap_or_default());
+        fs::metadata(new).is_ok_and(|meta| meta.len() == cache[old].size)
+    });
+    sizes_match
+        && old_srcs.iter().take(SAMPLES).all(|old| {
+            let new = new_dir.join(old.file_name().unwrap_or_default());
+            let hash = &cache[old].hash;
+            hash_like(&new, hash).is_ok_and(|new_hash| new_hash == *hash)
+        })
+}
+
+// returns false if the destination layout doesn't allow a single rename
+fn move_dir(
+    conn: &mut Connection,
+    cache: &mut FileCache,
+    art: &HashMap<PathBuf, PathBuf>,
+    old_srcs: &[PathBuf],
+    new_dir: &Path,
+    map_dst: &impl Fn(&Path) -> Result<PathBuf>,
+) -> Result<bool> {
+    // every output must live directly in the same destination directory, and be
+    // renamed to the same file name in the new one
+    let mut moves = Vec::with_capacity(old_srcs.len());
+    for old in old_srcs {
+        let new = new_dir.join(old.file_name().unwrap_or_default());
+        let new_dst = map_dst(&new)?;
+        if cache[old].dst.file_name() != new_dst.file_name() {
+            return Ok(false);
+        }
+        moves.push((old.clone(), new, new_dst));
+    }
+    let Some(old_dst_dir) = cache[&old_srcs[0]].dst.parent().map(Path::to_path_buf)
+    else {
+        return Ok(false);
+    };
+    let Some(new_dst_dir) = moves[0].2.parent().map(Path::to_path_buf) else {
+        return Ok(false);
+    };
+    let same_dirs = moves.iter().all(|(old, _, new_dst)| {
+        cache[old].dst.parent() == Some(&old_dst_dir)
+            && new_dst.parent() == Some(&new_dst_dir)
+    });
+    if !same_dirs {
+        return Ok(false);
+    }
+    if new_dst_dir.exists() || !old_dst_dir.is_dir() {
+        return Ok(false);
+    }
+
+    // the old destination directory must hold exactly these outputs, otherwise
+    // the rename would take other files (or nested directories) along with it.
+    // the playlist and covers extracted from the outputs' sources go along
+    let mut entries = 0;
+    let mut art_moves = Vec::new();
+    for entry in fs::read_dir(&old_dst_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type()?.is_file() {
+            return Ok(false);
+        }
+        if old_srcs.iter().any(|old| cache[old].dst == path) {
+            entries += 1;
+            continue;
+        }
+        if entry.file_name() == PLAYLIST_NAME {
+            continue;
+        }
+        let extracted_from = art
+            .get(&path)
+            .and_then(|src| moves.iter().find(|(old, ..)| old == src));
+        let Some((_, new_src, _)) = extracted_from else {
+            return Ok(false);
+        };
+        art_moves.push((path, new_dst_dir.join(entry.file_name()), new_src.clone()));
+    }
+    if entries != old_srcs.len() {
+        return Ok(false);
+    }
+
+    if let Some(parent) = new_dst_dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&old_dst_dir, &new_dst_dir)?;
+
+    db::move_rows(conn, &moves)?;
+    db::move_art(conn, &art_moves)?;
+    for (old, new, new_dst) in moves {
+        if let Some(mut info) = cache.remove(&old) {
+            info.dst = new_dst;
+            cache.insert(new, info);
+        }
+    }
+
+    Ok(true)
+}
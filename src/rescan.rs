@@ -0,0 +1,135 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::Result;
+use argh::FromArgs;
+use rayon::prelude::*;
+
+use crate::{
+    db,
+    stats::format_bytes,
+    worker::{compute_hash, hash_like, PARTIAL_HASH_PREFIX},
+};
+
+/// Hash every cached source file again, and mark the ones whose content changed
+/// without changing their mtime or size (e.g. restored from a backup) for
+/// reprocessing by the next sync. reads the entire source.
+#[derive(FromArgs, Debug, Clone)]
+pub struct RescanArgs {
+    /// path to SQLite database
+    #[argh(option, short = 'd')]
+    pub db_path: PathBuf,
+
+    /// only check source files whose path contains this text
+    #[argh(option)]
+    pub filter: Option<String>,
+
+    /// list silently changed files without marking them
+    #[argh(switch)]
+    pub dry_run: bool,
+
+    /// replace the partial hashes --fast-hash made with full ones, so renames of
+    /// those files are detected whatever --fast-hash is set to
+    #[argh(switch)]
+    pub upgrade_partial: bool,
+}
+
+pub fn run(args: RescanArgs) -> Result<()> {
+    let mut conn = db::connect(&args.db_path)?;
+    db::init(&mut conn)?;
+
+    // orphans in their grace period have no source left to hash
+    let filter = args.filter.as_deref().unwrap_or_default();
+    let files: Vec<_> = db::load_cache(&conn)?
+        .into_iter()
+        .filter(|(src, info)| {
+            info.orphaned_at.is_none()
+                && src.to_string_lossy().contains(filter)
+                && src.is_file()
+        })
+        .collect();
+    let total_bytes: u64 = files.iter().map(|(_, info)| info.size).sum();
+    log::info!(
+        "hashing {} files ({})",
+        files.len(),
+        format_bytes(total_bytes as i64),
+    );
+
+    // progress is logged every this many files
+    const PROGRESS_INTERVAL: usize = 500;
+    let done = AtomicUsize::new(0);
+    let done_bytes = AtomicU64::new(0);
+    let upgraded = Mutex::new(Vec::new());
+    let mut changed: Vec<PathBuf> = files
+        .par_iter()
+        .filter_map(|(src, info)| {
+            // partial hashes are checked as such, the full one replaces it only
+            // if the file is unchanged
+            let hash = hash_like(src, &info.hash).and_then(|hash| {
+                let upgrade = args.upgrade_partial
+                    && hash == info.hash
+                    && hash.starts_with(PARTIAL_HASH_PREFIX);
+                if upgrade {
+                    let full = compute_hash(src)?;
+                    let mut upgraded =
+                        upgraded.lock().unwrap_or_else(|e| e.into_inner());
+                    upgraded.push((src.clone(), full));
+                }
+                Ok(hash)
+            });
+            let n = done.fetch_add(1, Ordering::Relaxed) + 1;
+            let bytes =
+                done_bytes.fetch_add(info.size, Ordering::Relaxed) + info.size;
+            if n.is_multiple_of(PROGRESS_INTERVAL) {
+                log::info!(
+                    "hashed {n}/{} files ({} of {})",
+                    files.len(),
+                    format_bytes(bytes as i64),
+                    format_bytes(total_bytes as i64),
+                );
+            }
+            match hash {
+                Ok(hash) if hash != info.hash => Some(src.clone()),
+                Ok(_) => None,
+                Err(e) => {
+                    log::warn!("failed to hash {}: {e}", src.display());
+                    None
+                }
+            }
+        })
+        .collect();
+    changed.sort();
+
+    for src in &changed {
+        println!("{}", src.display());
+    }
+    let upgraded = upgraded.into_inner().unwrap_or_else(|e| e.into_inner());
+    if args.dry_run {
+        println!(
+            "{} of {} files changed silently, not marked",
+            changed.len(),
+            files.len(),
+        );
+        if args.upgrade_partial {
+            let n = upgraded.len();
+            println!("{n} partial hashes could be upgraded, not stored");
+        }
+        return Ok(());
+    }
+    if args.upgrade_partial {
+        db::update_hashes(&mut conn, &upgraded)?;
+        println!("upgraded {} partial hashes to full ones", upgraded.len());
+    }
+    db::mark_dirty(&mut conn, changed.iter())?;
+    println!(
+        "{} of {} files changed silently, marked for reprocessing",
+        changed.len(),
+        files.len(),
+    );
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+/// The effective settings of a run by name, in a fixed order. Values are
+/// compared as text, so equal settings must always be written the same way.
+pub type Settings = Vec<(String, String)>;
+
+/// A setting whose value differs from the previous run's. `None` if one of the
+/// runs didn't record it.
+pub struct Change {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |value: &Option<String>| match value.as_deref() {
+            Some("") => "(empty)".to_string(),
+            Some(value) => value.to_string(),
+            None => "(not recorded)".to_string(),
+        };
+        write!(f, "{}: {} -> {}", self.key, show(&self.old), show(&self.new))
+    }
+}
+
+/// The settings that differ between two runs, in the order of `new`.
+pub fn compare(old: &Settings, new: &Settings) -> Vec<Change> {
+    let get = |settings: &Settings, key: &str| {
+        settings.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    };
+    let mut changes: Vec<Change> = new
+        .iter()
+        .filter_map(|(key, value)| {
+            let old = get(old, key);
+            (old.as_ref() != Some(value)).then(|| Change {
+                key: key.clone(),
+                old,
+                new: Some(value.clone()),
+            })
+        })
+        .collect();
+    // settings older versions recorded that this one doesn't know about
+    for (key, value) in old {
+        if get(new, key).is_none() {
+            let old = Some(value.clone());
+            changes.push(Change { key: key.clone(), old, new: None });
+        }
+    }
+    changes
+}
+
+/// Join extensions into a list setting, lowercased and sorted so their order on
+/// the command line doesn't count as a change.
+pub fn list(exts: &[String]) -> String {
+    let mut exts: Vec<String> = exts.iter().map(|e| e.to_lowercase()).collect();
+    exts.sort();
+    exts.dedup();
+    exts.join(",")
+}
+
+/// Split a list setting written by `list`.
+pub fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').filter(|e| !e.is_empty()).map(str::to_string).collect()
+}
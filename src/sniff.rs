@@ -0,0 +1,100 @@
+use std::{fs, io::Read, path::Path};
+
+/// Detected type of files that couldn't be classified.
+pub const UNKNOWN: &str = "unknown";
+
+// formats already encoded lossily, which lose quality again when transcoded
+const LOSSY: &[&str] = &["mp3", "ogg", "opus", "aac"];
+
+/// Detect the real format of a file for --detect-type, by magic bytes and then by
+/// the codec `probe_codec` gets from its probe, if any.
+pub fn detect_type(
+    path: &Path,
+    probe_codec: impl FnOnce() -> Option<String>,
+) -> String {
+    if let Some(format) = sniff_format(path) {
+        return format.to_string();
+    }
+    if let Some(codec) = probe_codec() {
+        return match codec.as_str() {
+            "vorbis" => "ogg".to_string(),
+            "wavpack" => "wv".to_string(),
+            "alac" => "m4a".to_string(),
+            c if c.starts_with("pcm_") => "wav".to_string(),
+            _ => codec,
+        };
+    }
+    UNKNOWN.to_string()
+}
+
+/// Map an extension to the name detection uses for its format, so that
+/// different extensions for the same format compare equal.
+pub fn canonical_type(ext: &str) -> String {
+    let ext = ext.to_lowercase();
+    match ext.as_str() {
+        "aif" => "aiff".to_string(),
+        "oga" => "ogg".to_string(),
+        "wave" => "wav".to_string(),
+        "mp4" | "m4b" | "alac" => "m4a".to_string(),
+        _ => ext,
+    }
+}
+
+pub fn is_lossy(detected: &str) -> bool {
+    LOSSY.contains(&detected)
+}
+
+/// The detected type of a file, if it disagrees with its extension.
+pub fn type_mismatch<'a>(path: &Path, detected: &'a str) -> Option<&'a str> {
+    let ext = path.extension()?.to_string_lossy();
+    (detected != UNKNOWN && canonical_type(&ext) != detected).then_some(detected)
+}
+
+/// Guess the format of a file from its magic bytes, returned as the file extension
+/// it would usually have. None if the file can't be read or isn't recognized.
+pub fn sniff_format(path: &Path) -> Option<&'static str> {
+    let mut header = [0u8; 64];
+    let mut file = fs::File::open(path).ok()?;
+    let mut len = 0;
+    // read() may return less than asked for, even before the end of the file
+    while len < header.len() {
+        match file.read(&mut header[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(_) => return None,
+        }
+    }
+    format_of(&header[..len])
+}
+
+fn format_of(header: &[u8]) -> Option<&'static str> {
+    if header.starts_with(b"fLaC") {
+        return Some("flac");
+    }
+    if header.starts_with(b"OggS") {
+        // the codec id starts the first packet, after a single-segment page header
+        return match header.get(28..36) {
+            Some(b"OpusHead") => Some("opus"),
+            _ => Some("ogg"),
+        };
+    }
+    if header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WAVE") {
+        return Some("wav");
+    }
+    if header.starts_with(b"FORM") && header.get(8..12) == Some(b"AIFF") {
+        return Some("aiff");
+    }
+    if header.get(4..8) == Some(b"ftyp") {
+        return Some("m4a");
+    }
+    if header.starts_with(b"wvpk") {
+        return Some("wv");
+    }
+    // an id3v2 tag, or an mpeg audio frame sync without one
+    if header.starts_with(b"ID3")
+        || matches!(header, [0xFF, 0xFB | 0xFA | 0xF3 | 0xF2, ..])
+    {
+        return Some("mp3");
+    }
+    None
+}
@@ -0,0 +1,132 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use argh::FromArgs;
+use rusqlite::Connection;
+
+use crate::{
+    db::{self, RunRecord},
+    settings,
+    util::unix_now,
+};
+
+/// Print statistics about a sidechain database.
+#[derive(FromArgs, Debug, Clone)]
+pub struct StatsArgs {
+    /// path to SQLite database
+    #[argh(option, short = 'd')]
+    pub db_path: PathBuf,
+
+    /// print the last N runs instead
+    #[argh(option)]
+    pub history: Option<usize>,
+}
+
+pub fn run(args: StatsArgs) -> Result<()> {
+    let mut conn = db::connect(&args.db_path)?;
+    db::init(&mut conn)?;
+
+    let now = unix_now();
+    if let Some(limit) = args.history {
+        print_history(&conn, &db::load_runs(&conn, limit)?, now)?;
+        return Ok(());
+    }
+
+    let stats = db::sync_stats(&conn)?;
+
+    println!("{} files tracked", stats.total);
+    if stats.never_synced > 0 {
+        println!("{} files with no recorded sync time", stats.never_synced);
+    }
+    for (label, time) in [
+        ("oldest sync", stats.oldest),
+        ("median sync", stats.median),
+        ("newest sync", stats.newest),
+    ] {
+        if let Some(time) = time {
+            println!("{label}: {time} ({} ago)", format_age(now - time));
+        }
+    }
+
+    if !stats.per_status.is_empty() {
+        println!("last status:");
+        for (status, count) in &stats.per_status {
+            println!("  {status}: {count}");
+        }
+    }
+    if !stats.per_reason.is_empty() {
+        println!("last produced because:");
+        for (reason, count) in &stats.per_reason {
+            println!("  {reason}: {count}");
+        }
+    }
+
+    Ok(())
+}
+
+fn print_history(conn: &Connection, runs: &[RunRecord], now: i64) -> Result<()> {
+    if runs.is_empty() {
+        println!("no runs recorded");
+    }
+    for run in runs {
+        let age = format_age(now - run.started_at);
+        let outcome = match (run.finished_at, &run.exit_status) {
+            (Some(finished), Some(status)) => {
+                format!("took {}, {status}", format_age(finished - run.started_at))
+            }
+            _ => "incomplete (crashed or still running)".to_string(),
+        };
+        let (id, started) = (run.id, run.started_at);
+        println!("run {id}: started {started} ({age} ago), {outcome}");
+        println!(
+            "  {} transcoded, {} passed through, {} reclaimed, {} cached, {} failed",
+            run.transcoded,
+            run.passed_through,
+            run.reclaimed,
+            run.skipped,
+            run.failed,
+        );
+        println!(
+            "  {} orphans removed, {} written",
+            run.orphans_removed,
+            format_bytes(run.bytes_written),
+        );
+        println!("  settings: {}", run.settings);
+
+        // against the run before, which explains why e.g. everything was re-encoded
+        let current = db::load_settings(conn, run.id)?;
+        if current.is_empty() {
+            continue;
+        }
+        if let Some((previous, old)) = db::previous_settings(conn, Some(run.id))? {
+            for change in settings::compare(&old, &current) {
+                println!("  changed since run {previous}: {change}");
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn format_bytes(bytes: i64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes.max(0) as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+fn format_age(secs: i64) -> String {
+    let secs = secs.max(0);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
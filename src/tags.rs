@@ -0,0 +1,144 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+
+use crate::probe::probe_tags;
+
+/// Tags compared by --verify-tags unless others are given.
+pub const DEFAULT_KEYS: &[&str] =
+    &["title", "artist", "album", "track", "disc", "date"];
+
+// the same field is named differently depending on the container (e.g. vorbis
+// comments vs id3), these are all mapped to the name on the right
+const ALIASES: &[(&str, &str)] = &[
+    ("tracknumber", "track"),
+    ("discnumber", "disc"),
+    ("disk", "disc"),
+    ("year", "date"),
+    ("albumartist", "album_artist"),
+    ("album artist", "album_artist"),
+    ("totaltracks", "tracktotal"),
+    ("totaldiscs", "disctotal"),
+];
+
+/// How many transcoded files to check per run, parsed from a count or `all`.
+#[derive(Debug, Clone, Copy)]
+pub enum TagSample {
+    Count(usize),
+    All,
+}
+
+impl FromStr for TagSample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(TagSample::All),
+            _ => s.parse().map(TagSample::Count).map_err(|_| {
+                format!("invalid tag sample '{s}', expected a file count or all")
+            }),
+        }
+    }
+}
+
+pub struct TagVerifier {
+    keys: Vec<String>,
+    // None when every file is checked
+    remaining: Option<AtomicUsize>,
+}
+
+impl TagVerifier {
+    pub fn new(sample: TagSample, keys: &[String]) -> Self {
+        let keys = match keys {
+            [] => DEFAULT_KEYS.iter().map(|k| normalize_key(k)).collect(),
+            keys => keys.iter().map(|k| normalize_key(k)).collect(),
+        };
+        let remaining = match sample {
+            TagSample::Count(n) => Some(AtomicUsize::new(n)),
+            TagSample::All => None,
+        };
+        TagVerifier { keys, remaining }
+    }
+
+    /// Compare the tags of a transcoded file against those of its source, unless
+    /// the sample is used up. Returns a description of every lost or changed tag.
+    pub fn check(
+        &self,
+        src_tags: impl FnOnce() -> Result<HashMap<String, String>>,
+        dst: &Path,
+    ) -> Result<Vec<String>> {
+        if let Some(remaining) = &self.remaining
+            && remaining
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                    n.checked_sub(1)
+                })
+                .is_err()
+        {
+            return Ok(Vec::new());
+        }
+
+        let src_tags = normalize(src_tags()?);
+        let dst_tags = normalize(probe_tags(dst)?);
+        let mut issues = Vec::new();
+        for key in &self.keys {
+            let Some(expected) = src_tags.get(key) else {
+                continue;
+            };
+            match dst_tags.get(key) {
+                None => issues.push(format!("{key} lost")),
+                Some(actual) if actual != expected => {
+                    let issue = format!("{key} changed ('{expected}' -> '{actual}')");
+                    issues.push(issue);
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(issues)
+    }
+}
+
+pub fn normalize_key(key: &str) -> String {
+    let key = key.to_lowercase();
+    match ALIASES.iter().find(|(alias, _)| *alias == key) {
+        Some((_, name)) => name.to_string(),
+        None => key,
+    }
+}
+
+// merges key name variants, and compares numbering without zero padding. a
+// combined "3/12" track number is split into track and tracktotal, since some
+// containers store them separately
+pub fn normalize(tags: HashMap<String, String>) -> HashMap<String, String> {
+    let mut normalized = HashMap::new();
+    for (key, value) in tags {
+        let key = normalize_key(&key);
+        let value = value.trim().to_string();
+        if key == "track" || key == "disc" {
+            let (n, total) = match value.split_once('/') {
+                Some((n, total)) => (n, Some(total)),
+                None => (value.as_str(), None),
+            };
+            if let Some(total) = total {
+                normalized
+                    .entry(format!("{key}total"))
+                    .or_insert_with(|| strip_zeros(total));
+            }
+            normalized.insert(key, strip_zeros(n));
+        } else {
+            normalized.entry(key).or_insert(value);
+        }
+    }
+    normalized
+}
+
+fn strip_zeros(n: &str) -> String {
+    match n.trim().parse::<u32>() {
+        Ok(n) => n.to_string(),
+        Err(_) => n.trim().to_string(),
+    }
+}
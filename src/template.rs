@@ -0,0 +1,170 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{tags::normalize_key, util::sanitize_component, worker::FileCache};
+
+/// A destination path built from tags, parsed from text with `{tag}` or
+/// `{tag:0N}` (zero-padded to N digits) placeholders, e.g.
+/// `{albumartist}/{album}/{track:02} {title}`.
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Tag { key: String, width: usize },
+}
+
+impl PathTemplate {
+    /// The template as it was given, for telling templates apart.
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// Render the template with normalized tags, into a path relative to the
+    /// destination without an extension. Errors with the names of missing tags.
+    pub fn render(
+        &self,
+        tags: &HashMap<String, String>,
+    ) -> Result<String, Vec<String>> {
+        let mut rendered = String::new();
+        let mut missing = Vec::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => rendered.push_str(text),
+                Part::Tag { key, width } => {
+                    // tag values become single path components, which also
+                    // keeps values like '..' from meaning anything
+                    let value = tags
+                        .get(key)
+                        .map(|v| v.trim())
+                        .filter(|v| !v.is_empty())
+                        .map(sanitize_component);
+                    match value {
+                        Some(v) if v.bytes().all(|b| b.is_ascii_digit()) => {
+                            rendered.push_str(&format!("{v:0>width$}"));
+                        }
+                        Some(v) => rendered.push_str(&v),
+                        None => missing.push(key.clone()),
+                    }
+                }
+            }
+        }
+        match missing.is_empty() {
+            true => Ok(rendered),
+            false => Err(missing),
+        }
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in path template '{s}'"))?;
+            let placeholder = &rest[start + 1..start + end];
+            let (key, width) = match placeholder.split_once(':') {
+                Some((key, spec)) => {
+                    let width = spec
+                        .strip_prefix('0')
+                        .and_then(|w| w.parse().ok())
+                        .ok_or_else(|| {
+                            format!("invalid width '{spec}' in path template")
+                        })?;
+                    (key, width)
+                }
+                None => (placeholder, 0),
+            };
+            if key.is_empty() {
+                return Err(format!("empty placeholder in path template '{s}'"));
+            }
+            parts.push(Part::Tag { key: normalize_key(key), width });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("unopened '}}' in path template '{s}'"));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if !parts.iter().any(|p| matches!(p, Part::Tag { .. })) {
+            return Err(format!("path template '{s}' has no {{tag}} placeholders"));
+        }
+        // the literal parts alone decide the directory structure, tag values can't
+        // add separators
+        let outside = Path::new(s)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir));
+        if outside || s.ends_with('/') {
+            return Err(format!(
+                "path template '{s}' must be a relative path to a file without '..'"
+            ));
+        }
+        Ok(PathTemplate { source: s.to_string(), parts })
+    }
+}
+
+/// Destinations claimed so far this run, to detect two sources getting the same
+/// output where the scan can't: rendered by --path-template, or from a type
+/// found by --detect-type.
+pub struct DstClaims {
+    // outputs as of the last run, which keep their path over newcomers
+    owners: HashMap<PathBuf, PathBuf>,
+    claimed: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl DstClaims {
+    pub fn new(cache: &FileCache) -> Self {
+        let owners = cache
+            .iter()
+            .filter(|(_, info)| info.orphaned_at.is_none())
+            .map(|(src, info)| (info.dst.clone(), src.clone()))
+            .collect();
+        DstClaims { owners, claimed: Mutex::new(HashMap::new()) }
+    }
+
+    /// Claim a destination for a source, failing if another source has it.
+    pub fn claim(&self, src: &Path, dst: &Path) -> Result<()> {
+        if let Some(owner) = self.owners.get(dst)
+            && owner != src
+            && owner.exists()
+        {
+            bail!(
+                "{} collides with the output of {}",
+                dst.display(),
+                owner.display(),
+            );
+        }
+        let mut claimed = self.claimed.lock().unwrap_or_else(|e| e.into_inner());
+        match claimed.entry(dst.to_path_buf()) {
+            Entry::Occupied(other) if other.get() != src => bail!(
+                "{} collides with the output of {}",
+                dst.display(),
+                other.get().display(),
+            ),
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(src.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+}
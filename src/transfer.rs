@@ -0,0 +1,266 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+
+use crate::{
+    db::{self, TransferredRow},
+    playlists::PLAYLIST_NAME,
+    util::unix_now,
+};
+
+/// How --staging hands the outputs on to the device once the sync is done.
+pub struct Transfer {
+    pub staging: PathBuf,
+    pub manifest: PathBuf,
+    pub command: Option<String>,
+    pub delete_command: Option<String>,
+    pub mark_transferred: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Add,
+    Update,
+    Delete,
+}
+
+impl Action {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Action::Add => "add",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// A difference between the staging destination and the device.
+pub struct Entry {
+    pub action: Action,
+    // relative to the staging destination, which mirrors the device
+    pub path: PathBuf,
+    // what the device has once the entry is done, None for deletions
+    pub row: Option<TransferredRow>,
+}
+
+/// What the device is missing compared to the staging destination: deletions
+/// first, to make room, then added and changed outputs, each by path. Playlists
+/// and extracted covers are transferred like outputs.
+pub fn pending(conn: &Connection, staging: &Path) -> Result<Vec<Entry>> {
+    let mut transferred = db::load_transferred(conn)?;
+    let staged_row = |dst: &Path, hash: String, config: String, size| {
+        let path = dst.strip_prefix(staging).ok()?.to_path_buf();
+        let dst_mtime = mtime(dst);
+        let row = TransferredRow { hash, config, dst_size: size, dst_mtime };
+        Some((path, row))
+    };
+    let extra_row = |dst: &Path, config: &str| {
+        let size = fs::metadata(dst).ok()?.len();
+        staged_row(dst, String::new(), config.to_string(), Some(size))
+    };
+    // outputs in their grace period are still in the staging destination too
+    let cache = db::load_cache(conn)?;
+    let mut staged: Vec<(PathBuf, TransferredRow)> = Vec::new();
+    let mut dirs = BTreeSet::new();
+    for info in cache.into_values() {
+        dirs.extend(info.dst.parent().map(Path::to_path_buf));
+        staged.extend(staged_row(&info.dst, info.hash, info.config, info.dst_size));
+    }
+    for playlist in dirs.iter().map(|dir| dir.join(PLAYLIST_NAME)) {
+        staged.extend(extra_row(&playlist, "playlist"));
+    }
+    for art in db::load_art(conn)?.into_keys() {
+        staged.extend(extra_row(&art, "art"));
+    }
+    staged.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut changes = Vec::new();
+    for (path, row) in staged {
+        let action = match transferred.remove(&path) {
+            None => Action::Add,
+            Some(old) if !is_current(&old, &row) => Action::Update,
+            Some(_) => continue,
+        };
+        changes.push(Entry { action, path, row: Some(row) });
+    }
+    let mut deletions: Vec<Entry> = transferred
+        .into_keys()
+        .map(|path| Entry { action: Action::Delete, path, row: None })
+        .collect();
+    deletions.sort_by(|a, b| a.path.cmp(&b.path));
+    deletions.extend(changes);
+    Ok(deletions)
+}
+
+// every re-encode changes the mtime, even when it gives a file of the same size.
+// rows from before mtimes were recorded go by the rest, until transferred again
+fn is_current(old: &TransferredRow, new: &TransferredRow) -> bool {
+    old.hash == new.hash
+        && old.config == new.config
+        && old.dst_size == new.dst_size
+        && old.dst_mtime.is_none_or(|mtime| new.dst_mtime == Some(mtime))
+}
+
+// in nanoseconds, None if the file can't be read
+fn mtime(path: &Path) -> Option<i64> {
+    let mtime = fs::symlink_metadata(path).ok()?.modified().ok()?;
+    Some(mtime.duration_since(UNIX_EPOCH).ok()?.as_nanos() as i64)
+}
+
+/// Write the manifest, run the transfer commands and record what they finished.
+/// Stops at the first failing command, since the device is likely gone; the
+/// rest is still pending for the next run.
+pub fn run(conn: &Connection, transfer: &Transfer) -> Result<()> {
+    let entries = pending(conn, &transfer.staging)?;
+    write_manifest(&transfer.manifest, &entries)?;
+    let deletions = entries.iter().filter(|e| e.action == Action::Delete).count();
+    log::info!(
+        "{} files to copy to the device and {deletions} to delete from it, \
+         manifest written to {}",
+        entries.len() - deletions,
+        transfer.manifest.display(),
+    );
+
+    let mut done = 0;
+    let mut left = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let template = match entry.action {
+            _ if transfer.mark_transferred => None,
+            Action::Delete => transfer.delete_command.as_deref(),
+            Action::Add | Action::Update => transfer.command.as_deref(),
+        };
+        if let Some(template) = template {
+            let src = transfer.staging.join(&entry.path);
+            if let Err(e) = run_command(template, &src, &entry.path) {
+                let remaining = entries.len() - i;
+                bail!(
+                    "transfer stopped at {}, {remaining} entries left for the next \
+                     run: {e:#}",
+                    entry.path.display(),
+                );
+            }
+        } else if !transfer.mark_transferred {
+            left += 1;
+            continue;
+        }
+        db::set_transferred(conn, &entry.path, entry.row.as_ref(), unix_now())?;
+        done += 1;
+    }
+    if done > 0 {
+        log::info!("transferred {done} entries");
+    }
+    if left > 0 {
+        log::info!("{left} entries have no transfer command and are still pending");
+    }
+    Ok(())
+}
+
+// one "<action>\t<path>" line per entry, replaced atomically
+fn write_manifest(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut manifest = String::new();
+    for entry in entries {
+        let line = format!("{}\t{}\n", entry.action.as_str(), entry.path.display());
+        manifest.push_str(&line);
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, manifest).context("failed to write transfer manifest")?;
+    fs::rename(&tmp, path).context("failed to write transfer manifest")?;
+    Ok(())
+}
+
+// runs a transfer command without a shell, with {src} replaced by the output in
+// the staging destination and {path} by its path relative to it
+fn run_command(template: &str, src: &Path, path: &Path) -> Result<()> {
+    let (src, path) = (src.to_string_lossy(), path.to_string_lossy());
+    let mut args = template
+        .split_whitespace()
+        .map(|arg| arg.replace("{src}", &src).replace("{path}", &path));
+    let program = args.next().context("empty transfer command")?;
+    let status = Command::new(&program)
+        .args(args)
+        .status()
+        .with_context(|| format!("failed to run {program}"))?;
+    if !status.success() {
+        bail!("{program} exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::{tests::run_recorded, testutil::TempDir};
+
+    fn pending_paths(conn: &Connection, staging: &Path) -> Vec<String> {
+        let entries = pending(conn, staging).unwrap();
+        entries.iter().map(|e| e.path.display().to_string()).collect()
+    }
+
+    fn mark_all(conn: &Connection, staging: &Path) {
+        for entry in pending(conn, staging).unwrap() {
+            db::set_transferred(conn, &entry.path, entry.row.as_ref(), 0).unwrap();
+        }
+    }
+
+    #[test]
+    fn playlists_and_art_are_transferred() {
+        let tmp = TempDir::new();
+        tmp.file("src/A/x.mp3", b"x");
+        let staging = tmp.path().join("dst");
+        fs::create_dir(&staging).unwrap();
+        run_recorded(&tmp, &["--generate-playlists"]);
+        let cover = tmp.file("dst/A/cover.jpg", b"cover");
+        let conn = db::connect(&tmp.path().join("db")).unwrap();
+        let src = tmp.path().join("src/A/x.mp3");
+        conn.execute(
+            "INSERT INTO art (dst_path, src_path) VALUES (?1, ?2)",
+            rusqlite::params![cover.to_string_lossy(), src.to_string_lossy()],
+        )
+        .unwrap();
+
+        let expected = ["A/album.m3u8", "A/cover.jpg", "A/x.mp3"];
+        assert_eq!(pending_paths(&conn, &staging), expected);
+        mark_all(&conn, &staging);
+        assert!(pending_paths(&conn, &staging).is_empty());
+
+        fs::remove_file(&cover).unwrap();
+        let entries = pending(&conn, &staging).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].action == Action::Delete);
+    }
+
+    #[test]
+    fn output_rewritten_with_the_same_size_is_updated() {
+        let tmp = TempDir::new();
+        tmp.file("src/x.mp3", b"x");
+        let staging = tmp.path().join("dst");
+        fs::create_dir(&staging).unwrap();
+        run_recorded(&tmp, &[]);
+        let conn = db::connect(&tmp.path().join("db")).unwrap();
+        mark_all(&conn, &staging);
+
+        // as a re-encode with unchanged settings would leave it
+        let output = fs::File::options().write(true).open(staging.join("x.mp3"));
+        let output = output.unwrap();
+        let later = SystemTime::now() + Duration::from_secs(5);
+        output.set_modified(later).unwrap();
+        let entries = pending(&conn, &staging).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].action == Action::Update);
+
+        // rows recorded before mtimes were only compare the rest
+        let mut row = entries.into_iter().next().unwrap().row.unwrap();
+        row.dst_mtime = None;
+        db::set_transferred(&conn, Path::new("x.mp3"), Some(&row), 0).unwrap();
+        assert!(pending_paths(&conn, &staging).is_empty());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::Connection;
+
+use crate::{db, warnings, worker::FileInfo};
+
+/// Quarantine for orphaned outputs, so a temporarily missing source doesn't
+/// immediately cost a re-encode.
+#[derive(Clone)]
+pub struct Trash {
+    pub dir: PathBuf,
+    dst_root: PathBuf,
+}
+
+impl Trash {
+    pub fn open(dir: &Path, dst_root: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).context("failed to create trash directory")?;
+        Ok(Trash {
+            dir: dir.to_path_buf(),
+            dst_root: dst_root.to_path_buf(),
+        })
+    }
+
+    /// Delete trashed files older than the retention period, returning the rest
+    /// (which are still candidates for rename-reclaim).
+    pub fn purge_expired(
+        &self,
+        conn: &mut Connection,
+        retention: u64,
+        now: i64,
+    ) -> Result<Vec<FileInfo>> {
+        let mut kept = Vec::new();
+        let mut forget = Vec::new();
+
+        for (info, trashed_at) in db::load_trash(conn)? {
+            let expired = now.saturating_sub(trashed_at) >= retention as i64;
+            if !expired && info.dst.exists() {
+                kept.push(info);
+                continue;
+            }
+            if let Err(e) = fs::remove_file(&info.dst)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warnings::warn(
+                    "failed to purge from trash",
+                    format_args!(
+                        "failed to purge {} from trash: {e}",
+                        info.dst.display(),
+                    ),
+                );
+                continue;
+            }
+            log::info!("purged {} from trash", info.dst.display());
+            forget.push(info.dst);
+        }
+        db::remove_trash(conn, forget.iter())?;
+
+        Ok(kept)
+    }
+
+    /// Move an orphaned output into the trash, keeping its path relative to the
+    /// destination. Returns the record of the trashed file.
+    pub fn quarantine(&self, info: &FileInfo) -> Result<FileInfo> {
+        let rel = info
+            .dst
+            .strip_prefix(&self.dst_root)
+            .context("orphan outside destination")?;
+        let trashed = self.dir.join(rel);
+        if let Some(parent) = trashed.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // fall back to copying if the trash is on a different filesystem
+        if fs::rename(&info.dst, &trashed).is_err() {
+            let res = fs::copy(&info.dst, &trashed)
+                .context("failed to copy to trash")
+                .and_then(|_| Ok(fs::remove_file(&info.dst)?));
+            // a copy left behind has no trash row, so nothing would ever purge it
+            if let Err(e) = res {
+                _ = fs::remove_file(&trashed);
+                return Err(e);
+            }
+        }
+        Ok(FileInfo {
+            dst: trashed,
+            ..info.clone()
+        })
+    }
+}
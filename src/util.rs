@@ -1,8 +1,14 @@
-use std::path::{Path, PathBuf};
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use anyhow::{Context, Result};
 use walkdir::DirEntry;
 
+use crate::sniff::sniff_format;
+
 pub fn has_extension(path: &Path, ext_list: &[String]) -> bool {
     if let Some(ext) = path.extension() {
         let ext_lower = ext.to_string_lossy().to_lowercase();
@@ -12,6 +18,33 @@ pub fn has_extension(path: &Path, ext_list: &[String]) -> bool {
     }
 }
 
+/// Whether a source file gets transcoded. Files without an extension are
+/// classified by their content instead if `sniff_extensionless` is set.
+pub fn is_transcodable(
+    path: &Path,
+    allowed_exts: &[String],
+    sniff_extensionless: bool,
+) -> bool {
+    if sniff_extensionless && path.extension().is_none() {
+        return sniff_format(path).is_some_and(|format| {
+            allowed_exts.iter().any(|e| e.eq_ignore_ascii_case(format))
+        });
+    }
+    has_extension(path, allowed_exts)
+}
+
+/// Marker file that makes everything in its source directory and below pass
+/// through, whatever the allowed extensions are. Never mirrored itself.
+pub const PASSTHROUGH_MARKER: &str = ".sidechain-passthrough";
+
+/// Whether a directory of `src` up to `src_root` has a passthrough marker.
+pub fn under_passthrough_marker(src: &Path, src_root: &Path) -> bool {
+    src.ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(src_root))
+        .any(|dir| dir.join(PASSTHROUGH_MARKER).is_file())
+}
+
 pub fn is_dotfile(entry: &DirEntry) -> bool {
     entry.file_name()
         .to_str()
@@ -25,13 +58,327 @@ pub fn map_src_to_dst(
     dst_root: &Path,
     target_ext: &str,
     set_ext: bool,
+    lowercase_ext: bool,
+    sanitize_names: bool,
 ) -> Result<PathBuf> {
     let rel_path = src.strip_prefix(src_root).context("src outside root")?;
-    let mut dst = dst_root.join(rel_path);
+    let mut rel_dst = rel_path.to_path_buf();
 
     if set_ext {
-        dst.set_extension(target_ext);
+        rel_dst.set_extension(target_ext);
+    }
+    if lowercase_ext && let Some(ext) = rel_dst.extension() {
+        let ext_lower = ext.to_string_lossy().to_lowercase();
+        rel_dst.set_extension(ext_lower);
+    }
+    // directories too, an invalid album name fails every file inside it
+    if sanitize_names {
+        rel_dst = rel_dst
+            .components()
+            .map(|c| sanitize_component(&c.as_os_str().to_string_lossy()))
+            .collect();
+    }
+
+    Ok(dst_root.join(rel_dst))
+}
+
+/// Replace the characters FAT, exFAT and NTFS don't allow in names, and strip the
+/// trailing dots and spaces they can't store. Only depends on the name, so the
+/// same source always maps to the same destination.
+pub fn sanitize_component(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    match replaced.trim_end_matches(['.', ' ']) {
+        // names made only of dots, like ..., would otherwise vanish
+        "" => "_".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Output formats that are encoded losslessly, and so take no bitrate.
+pub const LOSSLESS_FORMATS: &[&str] = &["flac", "wav", "aiff", "wv", "tta"];
+
+pub fn is_lossless_format(format: &str) -> bool {
+    LOSSLESS_FORMATS.iter().any(|f| f.eq_ignore_ascii_case(format))
+}
+
+/// Bitrates (in kbps) that the encoder ffmpeg picks for a lossy format handles
+/// well with this many output channels, and the codec's name. None if the
+/// format's limits aren't known.
+pub fn bitrate_range(
+    format: &str,
+    channels: u32,
+) -> Option<(&'static str, RangeInclusive<u32>)> {
+    let channels = channels.max(1);
+    match format.to_lowercase().as_str() {
+        // libopus allows up to 255 per channel, 510 for stereo
+        "opus" => Some(("opus", 6..=255 * channels)),
+        "mp3" => Some(("mp3", 8..=320)),
+        "ogg" => Some(("vorbis", 32..=250 * channels)),
+        "m4a" | "m4b" | "mp4" | "aac" => Some(("aac", 8..=256 * channels)),
+        _ => None,
     }
+}
+
+/// Bitrate override for sources with a specific channel count, parsed from
+/// `channels=<n>:<kbps>`.
+#[derive(Debug, Clone)]
+pub struct BitrateRule {
+    pub channels: u32,
+    pub bitrate: u32,
+}
+
+impl FromStr for BitrateRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err =
+            || format!("invalid bitrate rule '{s}', expected channels=<n>:<kbps>");
+        let (channels, bitrate) = s
+            .strip_prefix("channels=")
+            .and_then(|rest| rest.split_once(':'))
+            .ok_or_else(err)?;
+        Ok(BitrateRule {
+            channels: channels.parse().map_err(|_| err())?,
+            bitrate: bitrate.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+/// How passed-through files are placed in the destination, parsed from `hard`,
+/// `soft` or `copy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    Hard,
+    Soft,
+    Copy,
+}
+
+impl FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hard" => Ok(LinkMode::Hard),
+            "soft" => Ok(LinkMode::Soft),
+            "copy" => Ok(LinkMode::Copy),
+            _ => Err(format!("invalid link mode '{s}', expected hard, soft or copy")),
+        }
+    }
+}
+
+/// Path of `to` relative to the directory `from_dir`, resolving symlinks in both.
+pub fn relative_path(from_dir: &Path, to: &Path) -> Result<PathBuf> {
+    let from_dir = std::fs::canonicalize(from_dir)?;
+    let to = std::fs::canonicalize(to)?;
+    let common = from_dir
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut rel = PathBuf::new();
+    for _ in from_dir.components().skip(common) {
+        rel.push("..");
+    }
+    rel.extend(to.components().skip(common));
+    Ok(rel)
+}
+
+/// Like `fs::canonicalize`, for paths that may not exist yet: their longest
+/// existing ancestor is resolved and the rest appended as is.
+pub fn canonicalize_missing(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    for ancestor in path.ancestors() {
+        if let Ok(canon) = std::fs::canonicalize(ancestor) {
+            let rest = path.strip_prefix(ancestor).expect("ancestor of the path");
+            return Ok(canon.join(rest));
+        }
+    }
+    Ok(path)
+}
 
-    Ok(dst)
+pub fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// A duration given on the command line as a number with a unit suffix (s, m, h,
+/// d or w), e.g. `30d`.
+#[derive(Debug, Clone, Copy)]
+pub struct HumanDuration(u64);
+
+impl HumanDuration {
+    pub fn secs(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err =
+            || format!("invalid duration '{s}', expected e.g. 90s, 30m, 12h or 30d");
+        let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+        let (value, unit) = s.split_at(split);
+        let value: u64 = value.parse().map_err(|_| err())?;
+        let multiplier = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 60 * 60 * 24,
+            "w" => 60 * 60 * 24 * 7,
+            _ => return Err(err()),
+        };
+        Ok(HumanDuration(value * multiplier))
+    }
+}
+
+/// A size given on the command line in bytes, or with a K, M, G or T suffix for
+/// binary multiples, e.g. `20G`.
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid size '{s}', expected e.g. 500M or 20G");
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        let value: u64 = value.parse().map_err(|_| err())?;
+        let shift = match unit.to_ascii_uppercase().as_str() {
+            "" | "B" => 0,
+            "K" => 10,
+            "M" => 20,
+            "G" => 30,
+            "T" => 40,
+            _ => return Err(err()),
+        };
+        value.checked_mul(1 << shift).map(ByteSize).ok_or_else(err)
+    }
+}
+
+/// Space available to unprivileged users on the filesystem containing `path`.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Result<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: statvfs only writes to the struct, and c_path is nul-terminated
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to stat {}", path.display()));
+    }
+    // field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Result<u64> {
+    anyhow::bail!("checking free space is only supported on unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepts(format: &str, channels: u32, bitrate: u32) -> bool {
+        let (_, range) = bitrate_range(format, channels).expect("known format");
+        range.contains(&bitrate)
+    }
+
+    #[test]
+    fn sanitize_component_fixes_fat_names() {
+        assert_eq!(sanitize_component("AC/DC: Live?"), "AC_DC_ Live_");
+        assert_eq!(sanitize_component("Vol. 2. "), "Vol. 2");
+        assert_eq!(sanitize_component("..."), "_");
+        assert_eq!(sanitize_component("tab\there"), "tab_here");
+        assert_eq!(sanitize_component("fine"), "fine");
+    }
+
+    #[test]
+    fn sanitize_names_fixes_nested_directories() {
+        let map = |src: &str, sanitize| {
+            let src = Path::new("/src").join(src);
+            let (src_root, dst_root) = (Path::new("/src"), Path::new("/dst"));
+            map_src_to_dst(&src, src_root, dst_root, "opus", true, false, sanitize)
+                .unwrap()
+        };
+        assert_eq!(
+            map("What Is...?/Vol. 2./x.flac", true),
+            Path::new("/dst/What Is..._/Vol. 2/x.opus"),
+        );
+        assert_eq!(
+            map("What Is...?/Vol. 2./x.flac", false),
+            Path::new("/dst/What Is...?/Vol. 2./x.opus"),
+        );
+        // the extension is set before sanitizing, so 'x.' doesn't lose it
+        assert_eq!(map("a|b/x..flac", true), Path::new("/dst/a_b/x..opus"));
+    }
+
+    #[test]
+    fn opus_bitrate_bounds() {
+        assert!(!accepts("opus", 1, 5));
+        assert!(accepts("opus", 1, 6));
+        assert!(accepts("opus", 1, 255));
+        assert!(!accepts("opus", 1, 256));
+        assert!(accepts("opus", 2, 510));
+        assert!(!accepts("opus", 2, 511));
+        assert!(accepts("opus", 6, 6 * 255));
+        assert!(!accepts("opus", 6, 6 * 256));
+    }
+
+    #[test]
+    fn mp3_bitrate_bounds_ignore_channels() {
+        for channels in [1, 2, 6] {
+            assert!(!accepts("mp3", channels, 7));
+            assert!(accepts("mp3", channels, 8));
+            assert!(accepts("mp3", channels, 320));
+            assert!(!accepts("mp3", channels, 321));
+        }
+    }
+
+    #[test]
+    fn vorbis_and_aac_bitrate_bounds() {
+        assert_eq!(bitrate_range("ogg", 2).unwrap().0, "vorbis");
+        assert!(!accepts("ogg", 1, 31));
+        assert!(accepts("ogg", 1, 32));
+        assert!(accepts("ogg", 2, 500));
+        assert!(!accepts("ogg", 2, 501));
+
+        for format in ["m4a", "m4b", "mp4", "AAC"] {
+            assert_eq!(bitrate_range(format, 2).unwrap().0, "aac");
+            assert!(!accepts(format, 1, 7));
+            assert!(accepts(format, 1, 8));
+            assert!(accepts(format, 2, 512));
+            assert!(!accepts(format, 2, 513));
+        }
+    }
+
+    #[test]
+    fn bitrate_range_unknown_formats() {
+        assert!(bitrate_range("flac", 2).is_none());
+        assert!(bitrate_range("wav", 2).is_none());
+        // zero channels counts as mono rather than allowing nothing
+        assert!(accepts("opus", 0, 255));
+    }
 }
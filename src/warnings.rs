@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, fmt, sync::Mutex};
+
+// instances of each class logged as warnings before the rest are suppressed
+const SHOWN_PER_CLASS: usize = 5;
+
+// number of warnings seen per class this run
+static COUNTS: Mutex<BTreeMap<&'static str, usize>> = Mutex::new(BTreeMap::new());
+
+/// Log a warning of a class that may repeat for thousands of files. The first
+/// few of each class are logged as warnings, the rest only at debug level, and
+/// `log_suppressed` summarizes how many were held back.
+pub fn warn(class: &'static str, message: fmt::Arguments) {
+    let count = {
+        let mut counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+        let count = counts.entry(class).or_default();
+        *count += 1;
+        *count
+    };
+    match count {
+        ..=SHOWN_PER_CLASS => log::warn!("{message}"),
+        _ => {
+            if count == SHOWN_PER_CLASS + 1 {
+                log::warn!("suppressing further '{class}' warnings");
+            }
+            log::debug!("{message}");
+        }
+    }
+}
+
+/// Log one line per class of warnings that `warn` suppressed.
+pub fn log_suppressed() {
+    let counts = COUNTS.lock().unwrap_or_else(|e| e.into_inner());
+    for (class, count) in counts.iter() {
+        if let Some(suppressed) = count.checked_sub(SHOWN_PER_CLASS)
+            && suppressed > 0
+        {
+            let suppressed = group_thousands(suppressed);
+            log::warn!(
+                "…and {suppressed} more '{class}' warnings, set RUST_LOG=debug to \
+                 see them all",
+            );
+        }
+    }
+}
+
+/// Format a count with thousands separators, e.g. 4312 -> 4,312.
+pub fn group_thousands(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
@@ -1,17 +1,101 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    io::Read,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, ensure, Context, Result};
+use smallvec::SmallVec;
 
-use crate::util::{has_extension, map_src_to_dst};
+use crate::{
+    art::ArtExtractor,
+    probe::{probe_file, AudioInfo, ProbeInfo},
+    sniff::{canonical_type, detect_type, is_lossy, UNKNOWN},
+    tags::{normalize, TagVerifier},
+    template::{DstClaims, PathTemplate},
+    util::{is_transcodable, map_src_to_dst, relative_path, BitrateRule, LinkMode},
+    warnings,
+};
+
+// source containers that can have chapters, and target formats that can keep them
+pub const CHAPTER_SOURCES: &[&str] =
+    &["m4a", "m4b", "mp4", "mka", "mkv", "ogg", "opus"];
+const CHAPTER_TARGETS: &[&str] = &["m4a", "m4b", "mp4", "mka", "mp3", "ogg", "opus"];
+
+// sources at least this large report how far their encoding got, at most once per
+// interval. smaller ones finish before anyone wonders whether ffmpeg hung
+const PROGRESS_MIN_SIZE: u64 = 100 * 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
 
 pub type FileCache = HashMap<PathBuf, FileInfo>;
-pub type OrphanCache = HashMap<String, Vec<FileInfo>>;
+pub type ProbeCache = HashMap<PathBuf, CachedProbe>;
+
+/// An orphaned output that a new source with the same content may take over.
+pub struct Orphan {
+    pub dst: PathBuf,
+    pub size: u64,
+    pub config: String,
+}
+
+/// Reclaim candidates by content hash. Nearly every hash has a single orphan,
+/// which is stored without a separate allocation.
+#[derive(Default)]
+pub struct OrphanCache {
+    by_hash: HashMap<String, SmallVec<[Orphan; 1]>>,
+}
+
+impl OrphanCache {
+    pub fn insert(&mut self, hash: &str, orphan: Orphan) {
+        self.by_hash.entry(hash.to_string()).or_default().push(orphan);
+    }
+
+    pub fn get(&self, hash: &str) -> &[Orphan] {
+        self.by_hash.get(hash).map_or(&[], |orphans| orphans.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_hash.values().map(SmallVec::len).sum()
+    }
+}
+
+/// Destination directories known to exist, so each one is created at most once
+/// per run however many outputs go into it.
+#[derive(Default)]
+pub struct DstDirs {
+    created: Mutex<HashSet<PathBuf>>,
+}
+
+impl DstDirs {
+    /// Create `dir` and its parents, unless an earlier call did.
+    pub fn ensure(&self, dir: &Path) -> Result<()> {
+        // held while creating, so workers racing for a new directory wait for the
+        // first one instead of creating it again
+        let mut created = self.created.lock().unwrap_or_else(|e| e.into_inner());
+        if created.contains(dir) {
+            return Ok(());
+        }
+        fs::create_dir_all(dir).with_context(|| {
+            format!("failed to create destination directory {}", dir.display())
+        })?;
+        created.extend(dir.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+}
+
+/// A probe of a source, valid while its mtime and size are unchanged.
+#[derive(Debug, Clone)]
+pub struct CachedProbe {
+    pub mtime: i64,
+    pub size: u64,
+    pub info: ProbeInfo,
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct FileInfo {
@@ -20,6 +104,14 @@ pub struct FileInfo {
     pub mtime: i64,
     pub size: u64,
     pub config: String,
+    // the real format of the source found by --detect-type
+    pub detected_type: Option<String>,
+    // the --path-template dst was chosen with, even if it fell back to mirroring
+    pub path_template: Option<String>,
+    // size of the output when it was produced, None if it wasn't recorded
+    pub dst_size: Option<u64>,
+    // when the source was first found missing, while in the orphan grace period
+    pub orphaned_at: Option<i64>,
 }
 
 #[derive(Debug, Clone)]
@@ -27,6 +119,48 @@ pub struct ProcessedFile {
     pub src: PathBuf,
     pub info: FileInfo,
     pub status: FileStatus,
+    // true if a Skipped file's record differs from the cached one
+    pub record_changed: bool,
+    // true if --paranoid found the source hash differing from the cached one
+    pub hash_contradicted: bool,
+    // true if --verify-reclaim rejected an orphan, so the file was processed anew
+    pub reclaim_rejected: bool,
+    // why the output was produced this run, None if it was skipped
+    pub reason: Option<ReprocessReason>,
+    // true if --max-reencodes left the stale output for a later run
+    pub deferred: bool,
+    // true if the cached output was empty or cut short, so it was produced again
+    pub repaired: bool,
+    // a file that --no-delete kept around instead of deleting
+    pub would_delete: Option<PathBuf>,
+    // tags --verify-tags found lost or changed in the output
+    pub tag_issues: Vec<String>,
+    // cover written next to the output by --extract-art
+    pub extracted_art: Option<PathBuf>,
+    // a probe of the source that isn't in the probe table yet
+    pub probed: Option<ProbeInfo>,
+}
+
+impl ProcessedFile {
+    /// A result with nothing else to report, for return paths to override the
+    /// fields they set.
+    pub fn new(src: &Path, info: FileInfo, status: FileStatus) -> Self {
+        ProcessedFile {
+            src: src.to_path_buf(),
+            info,
+            status,
+            record_changed: false,
+            hash_contradicted: false,
+            reclaim_rejected: false,
+            reason: None,
+            deferred: false,
+            repaired: false,
+            would_delete: None,
+            tag_issues: Vec::new(),
+            extracted_art: None,
+            probed: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +169,93 @@ pub enum FileStatus {
     Transcoded,
     Reclaimed,
     Skipped,
+    // dst is occupied by a file sidechain didn't produce, and --no-delete is set
+    Conflict,
+}
+
+impl FileStatus {
+    /// Value stored in the last_status column, None if the output wasn't produced
+    /// this run.
+    pub fn as_db_str(&self) -> Option<&'static str> {
+        match self {
+            FileStatus::PassedThrough => Some("passthrough"),
+            FileStatus::Transcoded => Some("transcoded"),
+            FileStatus::Reclaimed => Some("reclaimed"),
+            FileStatus::Skipped | FileStatus::Conflict => None,
+        }
+    }
+}
+
+/// Why a source was processed instead of skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReprocessReason {
+    /// Not in the cache, e.g. added or renamed in the source.
+    New,
+    ConfigChanged,
+    /// The source or its output moved, e.g. a renamed source that reclaimed its
+    /// old output.
+    Renamed,
+    SourceModified,
+    /// The output is gone, or was emptied or cut short.
+    DstMissing,
+    /// Its config was named by --reencode-config.
+    Forced,
+}
+
+impl ReprocessReason {
+    /// Value stored in the last_reason column.
+    pub fn as_db_str(&self) -> &'static str {
+        match self {
+            ReprocessReason::New => "new",
+            ReprocessReason::ConfigChanged => "config_changed",
+            ReprocessReason::Renamed => "renamed",
+            ReprocessReason::SourceModified => "source_modified",
+            ReprocessReason::DstMissing => "dst_missing",
+            ReprocessReason::Forced => "forced",
+        }
+    }
+}
+
+impl std::fmt::Display for ReprocessReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ReprocessReason::New => "new",
+            ReprocessReason::ConfigChanged => "config changed",
+            ReprocessReason::Renamed => "renamed",
+            ReprocessReason::SourceModified => "source modified",
+            ReprocessReason::DstMissing => "output missing",
+            ReprocessReason::Forced => "--reencode-config",
+        })
+    }
+}
+
+// how a single file is encoded
+#[derive(Debug, Clone)]
+pub enum Encoding {
+    Lossy { bitrate: u32 },
+    Lossless {
+        sample_rate: Option<u32>,
+        bit_depth: Option<u32>,
+    },
+}
+
+impl Encoding {
+    // part of the config string, so switching modes or parameters reprocesses
+    fn config(&self) -> String {
+        match self {
+            Encoding::Lossy { bitrate } => bitrate.to_string(),
+            Encoding::Lossless { sample_rate, bit_depth } => {
+                let mut config = "lossless".to_string();
+                if let Some(rate) = sample_rate {
+                    config.push_str(&format!(":ar{rate}"));
+                }
+                if let Some(depth) = bit_depth {
+                    config.push_str(&format!(":s{depth}"));
+                }
+                config
+            }
+        }
+    }
 }
 
 pub struct WorkerSettings<'a> {
@@ -42,104 +263,444 @@ pub struct WorkerSettings<'a> {
     pub dst_root: &'a Path,
     pub allowed_exts: &'a [String],
     pub target_ext: &'a str,
-    pub bitrate: u32,
-    pub should_copy: bool,
+    pub lowercase_ext: bool,
+    pub sanitize_names: bool,
+    pub sniff_extensionless: bool,
+    pub detect_type: bool,
+    // None when the target format is lossless
+    pub bitrate: Option<u32>,
+    pub bitrate_per_channel: Option<u32>,
+    pub bitrate_rules: &'a [BitrateRule],
+    pub max_channels: Option<u32>,
+    pub max_sample_rate: Option<u32>,
+    pub bit_depth: Option<u32>,
+    pub mtime_window: u64,
+    pub paranoid: bool,
+    pub fast_hash: Option<u64>,
+    pub reencode_configs: &'a [String],
+    pub verify_reclaim: bool,
+    pub embed_provenance: bool,
+    pub no_delete: bool,
+    // the destination was emptied, so old outputs aren't looked for
+    pub rebuild: bool,
+    pub link_mode: LinkMode,
+    pub tag_verifier: Option<&'a TagVerifier>,
+    pub path_template: Option<&'a PathTemplate>,
+    pub claims: Option<&'a DstClaims>,
+    pub art: Option<&'a ArtExtractor>,
     pub orphans: &'a OrphanCache,
+    // the source is below a passthrough marker
+    pub force_passthrough: bool,
     pub cache: &'a FileCache,
+    pub probes: &'a ProbeCache,
+    // called with the fraction of a large source encoded so far
+    pub progress: Option<&'a dyn Fn(f32)>,
+    // directories created so far this run, shared by all workers
+    pub dirs: Option<&'a DstDirs>,
+    // re-encodes --max-reencodes still allows this run, shared by all workers
+    pub reencode_budget: Option<&'a AtomicUsize>,
 }
 
-pub fn process_file(src: &Path, args: WorkerSettings) -> Result<ProcessedFile> {
-    let do_transcode = has_extension(src, args.allowed_exts);
+impl<'a> WorkerSettings<'a> {
+    /// The probe of a source in the probe table, unless the source changed since.
+    pub fn cached_probe(
+        &self,
+        src: &Path,
+        mtime: i64,
+        size: u64,
+    ) -> Option<&'a ProbeInfo> {
+        let window = self.mtime_window;
+        self.probes
+            .get(src)
+            .filter(|p| p.size == size && p.mtime.abs_diff(mtime) <= window)
+            .map(|p| &p.info)
+    }
+}
 
-    // for change detection, when the user changes bitrate or format we should re-enc
-    // we should also track passed-through files, so we never mix the two types
-    let config = if do_transcode {
-        format!("{}:{}", args.target_ext, args.bitrate)
-    } else {
-        "passthrough".to_string()
-    };
+/// Probes a single source at most once per job, reusing the probe table as long
+/// as the source is unchanged. Features needing anything ffprobe reports about a
+/// source should go through this.
+pub struct Prober<'a> {
+    src: &'a Path,
+    cached: Option<&'a ProbeInfo>,
+    // probed during this job, written to the probe table with the file's record
+    fresh: Option<ProbeInfo>,
+}
 
+impl<'a> Prober<'a> {
+    pub fn new(
+        args: &WorkerSettings<'a>,
+        src: &'a Path,
+        mtime: i64,
+        size: u64,
+    ) -> Self {
+        let cached = args.cached_probe(src, mtime, size);
+        Prober { src, cached, fresh: None }
+    }
+
+    pub fn probe(&mut self) -> Result<&ProbeInfo> {
+        if let Some(info) = self.cached {
+            return Ok(info);
+        }
+        if self.fresh.is_none() {
+            self.fresh = Some(probe_file(self.src)?);
+        }
+        Ok(self.fresh.as_ref().expect("just probed"))
+    }
+}
+
+pub fn process_file(src: &Path, args: WorkerSettings) -> Result<ProcessedFile> {
     let meta = fs::metadata(src).context("failed to stat file")?;
     let mtime = meta
         .modified()?
         .duration_since(std::time::UNIX_EPOCH)?
         .as_secs() as i64;
     let size = meta.len();
+
+    // mtimes within the window count as equal, for coarse fs timestamps
+    let source_unchanged = |hit: &FileInfo| {
+        hit.size == size && hit.mtime.abs_diff(mtime) <= args.mtime_window
+    };
+
+    let mut prober = Prober::new(&args, src, mtime, size);
+
+    // with --detect-type the content decides instead of the extension. only files
+    // claiming to be transcodable are worth an ffprobe if magic bytes don't tell
+    let claims_transcodable =
+        is_transcodable(src, args.allowed_exts, args.sniff_extensionless);
+    let detected_type = args.detect_type.then(|| {
+        let cached = args
+            .cache
+            .get(src)
+            .filter(|hit| source_unchanged(hit))
+            .and_then(|hit| hit.detected_type.clone());
+        cached.unwrap_or_else(|| {
+            let codec = || match claims_transcodable {
+                true => prober.probe().ok().map(|p| p.codec.clone()),
+                false => None,
+            };
+            detect_type(src, codec)
+        })
+    });
+    let do_transcode = match detected_type.as_deref() {
+        _ if args.force_passthrough => false,
+        Some(detected) if detected != UNKNOWN => args
+            .allowed_exts
+            .iter()
+            .any(|ext| canonical_type(ext) == detected),
+        _ => claims_transcodable,
+    };
+
     let dst = map_src_to_dst(
         src,
         args.src_root,
         args.dst_root,
         args.target_ext,
         do_transcode,
+        args.lowercase_ext,
+        args.sanitize_names,
     )?;
+    // only transcoded files are placed by --path-template, what is passed through
+    // (cover art, booklets) has no tags to go by
+    let path_template = args.path_template.filter(|_| do_transcode);
+    let dst = match path_template {
+        Some(template) => {
+            // rendering means reading tags, so it's only redone when the source,
+            // template or format changed
+            let cached = args.cache.get(src).filter(|hit| {
+                source_unchanged(hit)
+                    && hit.path_template.as_deref() == Some(template.as_str())
+                    && hit.dst.extension().is_some_and(|e| e == args.target_ext)
+            });
+            match cached {
+                Some(hit) => hit.dst.clone(),
+                None => render_dst(src, template, &args, &mut prober, dst)?,
+            }
+        }
+        None => dst,
+    };
+    if let Some(claims) = args.claims {
+        claims.claim(src, &dst)?;
+    }
+    let path_template = path_template.map(|t| t.as_str().to_string());
+
+    // only probe if a bitrate rule, downmix or resample could apply
+    let probe = match do_transcode && needs_probe(&args) {
+        true => Some(prober.probe()?.audio()),
+        false => None,
+    };
+    // containers that can't have chapters aren't probed for them, and a source
+    // that can't be probed is encoded like one without
+    let chapters = match do_transcode && may_have_chapters(src) {
+        true => match prober.probe() {
+            Ok(info) => Some(info.chapters),
+            Err(e) => {
+                warnings::warn(
+                    "failed to probe chapters",
+                    format_args!(
+                        "failed to probe chapters of {}, encoding it without: {e:#}",
+                        src.display(),
+                    ),
+                );
+                None
+            }
+        },
+        false => None,
+    };
+    let Plan { encoding, downmix, keep_chapters, config: transcode_config } =
+        plan(&args, probe.as_ref(), chapters);
+
+    // symlinks are relative to their own location, so they can't be moved around
+    // like other outputs
+    let symlinked = !do_transcode && args.link_mode == LinkMode::Soft;
+
+    // for change detection, when the user changes bitrate or format we should re-enc
+    // we should also track passed-through files, so we never mix the two types
+    let config = if do_transcode {
+        transcode_config
+    } else if symlinked {
+        "passthrough:symlink".to_string()
+    } else {
+        "passthrough".to_string()
+    };
+
+    // a hash computed while validating the cache, so it isn't computed twice
+    let mut known_hash = None;
+    let mut hash_contradicted = false;
+    let mut would_delete = None;
+    let mut repaired = false;
+    let mut reason = ReprocessReason::New;
 
     if let Some(hit) = args.cache.get(src) {
-        if hit.config != config {
+        // if only the config or destination changed, the source bytes are the
+        // same and the cached hash can be reused instead of reading the file again
+        if source_unchanged(hit) && !args.paranoid {
+            known_hash = Some(hit.hash.clone());
+        }
+        // a single stat tells whether the old output is still there and whole.
+        // outputs emptied or cut short (e.g. by a power loss) don't count as there
+        let old_dst_size = fs::metadata(&hit.dst).ok().map(|meta| meta.len());
+        let old_dst_intact = old_dst_size.is_some_and(|len| {
+            (len > 0 || hit.size == 0) && hit.dst_size.is_none_or(|n| n == len)
+        });
+
+        if hit.config != config
+            && source_unchanged(hit)
+            && old_dst_intact
+            && !take_reencode(&args)
+        {
+            // the old output stays, still recorded with its old config
+            log::debug!(
+                "config for file {} changed, leaving it for a later run",
+                hit.dst.display(),
+            );
+            let extracted_art =
+                skipped_art(src, &hit.dst, do_transcode, &args, &mut prober);
+            return Ok(ProcessedFile {
+                deferred: true,
+                extracted_art,
+                probed: prober.fresh,
+                ..ProcessedFile::new(src, hit.clone(), FileStatus::Skipped)
+            });
+        } else if hit.config != config {
             // user changed bitrate or format, reprocess even if it's in the cache
             log::debug!(
                 "config for file {} changed, reprocessing",
                 hit.dst.display(),
             );
+            reason = ReprocessReason::ConfigChanged;
+        } else if args.reencode_configs.contains(&hit.config) {
+            log::debug!(
+                "file {} was produced with {}, reprocessing for --reencode-config",
+                hit.dst.display(),
+                hit.config,
+            );
+            reason = ReprocessReason::Forced;
         } else if hit.dst != dst {
-            // the source file was renamed, reprocess
+            reason = ReprocessReason::Renamed;
+            // the destination mapping changed (e.g. --lowercase-extensions was
+            // enabled) but the source didn't, so the old output can just be moved
+            if source_unchanged(hit)
+                && old_dst_intact
+                && !args.no_delete
+                && !args.rebuild
+                && !symlinked
+            {
+                create_parent(&args, &dst)?;
+                if fs::rename(&hit.dst, &dst).is_ok() {
+                    let info = FileInfo {
+                        dst,
+                        hash: hit.hash.clone(),
+                        mtime,
+                        size,
+                        config,
+                        detected_type,
+                        path_template: path_template.clone(),
+                        orphaned_at: None,
+                        dst_size: None,
+                    };
+                    return Ok(ProcessedFile {
+                        reason: Some(ReprocessReason::Renamed),
+                        ..reclaimed(src, info, do_transcode, &args, prober)
+                    });
+                }
+            }
+            // the old output couldn't be moved, reprocess
             log::debug!(
                 "file {} renamed to {}, reprocessing",
                 hit.dst.display(),
                 dst.display(),
             );
-        } else if hit.mtime == mtime && hit.size == size && hit.dst.exists() {
+        } else if source_unchanged(hit)
+            && old_dst_intact
+            && (!symlinked || symlink_points_to(&dst, src))
+        {
             // cache hit, the config and file are unchanged
             // we only skip if EVERYTHING matches, including the dest path
-            return Ok(ProcessedFile {
-                src: src.to_path_buf(),
-                info: FileInfo {
-                    dst: dst,
-                    hash: hit.hash.clone(),
-                    mtime: hit.mtime,
-                    size: hit.size,
+            // the observed mtime is stored so drift within the window can't add up
+            let hash = if args.paranoid {
+                hash_like(src, &hit.hash)?
+            } else {
+                hit.hash.clone()
+            };
+            if hash == hit.hash {
+                let record_changed = hit.mtime != mtime
+                    || hit.dst_size != old_dst_size
+                    || hit.detected_type != detected_type
+                    || hit.path_template != path_template;
+                let extracted_art =
+                    skipped_art(src, &dst, do_transcode, &args, &mut prober);
+                let info = FileInfo {
+                    dst,
+                    hash,
+                    mtime,
+                    size,
                     config,
-                },
-                status: FileStatus::Skipped,
-            });
+                    detected_type,
+                    path_template: path_template.clone(),
+                    orphaned_at: None,
+                    dst_size: old_dst_size,
+                };
+                return Ok(ProcessedFile {
+                    record_changed,
+                    extracted_art,
+                    probed: prober.fresh,
+                    ..ProcessedFile::new(src, info, FileStatus::Skipped)
+                });
+            }
+            warnings::warn(
+                "changed without changing mtime or size",
+                format_args!(
+                    "file {} changed without changing mtime or size, reprocessing",
+                    src.display(),
+                ),
+            );
+            known_hash = Some(hash);
+            hash_contradicted = true;
+            reason = ReprocessReason::SourceModified;
+        } else if !source_unchanged(hit) {
+            reason = ReprocessReason::SourceModified;
+        } else if old_dst_size.is_some() && !old_dst_intact {
+            let found = match (old_dst_size, hit.dst_size) {
+                (Some(len), Some(expected)) if len > 0 => {
+                    format!("{len} bytes instead of {expected}")
+                }
+                _ => "empty".to_string(),
+            };
+            warnings::warn(
+                "truncated output",
+                format_args!("output {} is {found}, reprocessing", hit.dst.display()),
+            );
+            repaired = true;
+            reason = ReprocessReason::DstMissing;
+        } else {
+            // gone, or a symlink pointing somewhere else
+            reason = ReprocessReason::DstMissing;
         }
 
-        if let Err(e) = fs::remove_file(&hit.dst) {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                log::warn!(
-                    "failed to remove stale file {}: {}",
-                    hit.dst.display(),
-                    e,
-                );
+        if args.rebuild {
+            // nothing to remove, whatever is left at dst is overwritten
+        } else if args.no_delete && hit.dst != dst {
+            // replacing our own output at the same path is fine, but removing
+            // it from somewhere else isn't
+            if hit.dst.exists() {
+                would_delete = Some(hit.dst.clone());
             }
+        } else if let Err(e) = fs::remove_file(&hit.dst)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warnings::warn(
+                "failed to remove stale file",
+                format_args!(
+                    "failed to remove stale file {}: {e}",
+                    hit.dst.display(),
+                ),
+            );
         }
     }
 
-    let hash = compute_hash(src)?;
-    if let Some(parent) = dst.parent() {
-        // multiple workers may try to create the same directory
-        // don't handle this error, let later file operations fail if needed
-        // TODO: this might be bad for perf
-        _ = fs::create_dir_all(parent);
+    // by now our own output at dst (if any) was removed, so whatever is left
+    // there belongs to someone else
+    if args.no_delete && dst.exists() {
+        let info = FileInfo {
+            dst: dst.clone(),
+            mtime,
+            size,
+            config,
+            detected_type,
+            path_template,
+            ..Default::default()
+        };
+        return Ok(ProcessedFile {
+            hash_contradicted,
+            reason: Some(reason),
+            repaired,
+            would_delete: Some(dst),
+            probed: prober.fresh,
+            ..ProcessedFile::new(src, info, FileStatus::Conflict)
+        });
     }
 
-    // optimistic rename detection
-    if let Some(candidates) = args.orphans.get(&hash) {
-        for info in candidates {
-            if !info.dst.exists() {
-                continue;
-            }
+    let hash = match known_hash {
+        Some(hash) => hash,
+        None => hash_file(src, size, args.fast_hash)?,
+    };
+    create_parent(&args, &dst)?;
 
-            // only reclaim if the config matches
+    // optimistic rename detection. reclaiming moves the orphan away from its old
+    // path, which --no-delete doesn't allow
+    let reclaimable = !args.no_delete && !args.rebuild && !symlinked;
+    let mut reclaim_rejected = false;
+    if reclaimable {
+        for info in args.orphans.get(&hash) {
+            // orphans were checked to exist when the run started, and ones the
+            // settings can't produce were left out
             if info.config != config {
                 continue;
             }
 
             if info.size != size {
-                log::warn!(
-                    "file {} and orphan {} have same hash but differing sizes",
-                    src.display(),
-                    info.dst.display(),
+                warnings::warn(
+                    "same hash but differing sizes",
+                    format_args!(
+                        "file {} and orphan {} have same hash but differing sizes",
+                        src.display(),
+                        info.dst.display(),
+                    ),
+                );
+                continue;
+            }
+
+            if args.verify_reclaim && !reclaim_candidate_valid(info, do_transcode) {
+                warnings::warn(
+                    "orphan failed validation",
+                    format_args!(
+                        "orphan {} failed validation, not reclaiming it for {}",
+                        info.dst.display(),
+                        src.display(),
+                    ),
                 );
+                reclaim_rejected = true;
                 continue;
             }
 
@@ -154,57 +715,482 @@ pub fn process_file(src: &Path, args: WorkerSettings) -> Result<ProcessedFile> {
             // just fall back to a safe option (re-transcode or passthrough)
             if fs::rename(&info.dst, &dst).is_ok() {
                 // no other worker got it, we successfully renamed the file
+                let info = FileInfo {
+                    dst,
+                    hash,
+                    mtime,
+                    size,
+                    config,
+                    detected_type,
+                    path_template: path_template.clone(),
+                    orphaned_at: None,
+                    dst_size: None,
+                };
                 return Ok(ProcessedFile {
-                    src: src.to_path_buf(),
-                    info: FileInfo {
-                        dst,
-                        hash,
-                        mtime,
-                        size,
-                        config,
-                    },
-                    status: FileStatus::Reclaimed,
+                    hash_contradicted,
+                    reclaim_rejected,
+                    // a new source taking over an orphan is most likely a rename
+                    reason: Some(match reason {
+                        ReprocessReason::New => ReprocessReason::Renamed,
+                        reason => reason,
+                    }),
+                    repaired,
+                    would_delete,
+                    ..reclaimed(src, info, do_transcode, &args, prober)
                 });
             }
         }
     }
 
     // fallback to transcode or passthrough
+    let mut tag_issues = Vec::new();
+    let mut extracted_art = None;
     let status = if do_transcode {
-        spawn_ffmpeg(src, &dst, args.bitrate)?;
+        if let Some(detected) = detected_type.as_deref().filter(|t| is_lossy(t)) {
+            warnings::warn(
+                "already lossy",
+                format_args!(
+                    "{} is already lossy ({detected}), transcoding loses quality \
+                     again",
+                    src.display(),
+                ),
+            );
+        }
+        let mut metadata = Vec::new();
+        if args.embed_provenance {
+            let rel = src.strip_prefix(args.src_root).unwrap_or(src);
+            metadata.push(("SIDECHAIN_SRC_HASH", hash.clone()));
+            metadata.push(("SIDECHAIN_CONFIG", config.clone()));
+            metadata.push(("SIDECHAIN_SRC_PATH", rel.to_string_lossy().into_owned()));
+        }
+        if keep_chapters == Some(false) {
+            warnings::warn(
+                "chapters lost",
+                format_args!(
+                    "{} has {} chapters, which {} files can't carry",
+                    src.display(),
+                    chapters.unwrap_or_default(),
+                    args.target_ext,
+                ),
+            );
+        }
+        // progress is relative to the duration, which takes a probe
+        let duration = match args.progress {
+            Some(_) if size >= PROGRESS_MIN_SIZE => {
+                prober.probe().ok().and_then(|p| p.duration)
+            }
+            _ => None,
+        };
+        let progress = duration.filter(|&d| d > 0.0).zip(args.progress);
+        let res = spawn_ffmpeg(
+            src,
+            &dst,
+            &encoding,
+            downmix,
+            keep_chapters,
+            &metadata,
+            progress,
+        );
+        remove_partial(res, &dst)?;
+        (tag_issues, extracted_art) = check_transcoded(src, &dst, &args, &mut prober);
         FileStatus::Transcoded
     } else {
-        if dst.exists() {
+        // don't follow links at dst, copying through one would overwrite its target
+        if dst.symlink_metadata().is_ok() {
             fs::remove_file(&dst)?;
         }
-        if args.should_copy {
-            fs::copy(src, &dst).context("failed to copy")?;
-        } else {
-            fs::hard_link(src, &dst).with_context(|| {
-                format!(
-                    "failed to hardlink {} -> {}. if source and destination are on different filesystems, or if your fs doesn't support hardlinks, use the --copy flag",
-                    src.display(),
-                    dst.display(),
-                )
-            })?;
+        match args.link_mode {
+            LinkMode::Copy => {
+                let res = fs::copy(src, &dst).context("failed to copy");
+                remove_partial(res, &dst)?;
+            }
+            LinkMode::Hard => {
+                fs::hard_link(src, &dst).with_context(|| {
+                    format!(
+                        "failed to hardlink {} -> {}. if source and destination are on different filesystems, or if your fs doesn't support hardlinks, use --link-mode copy",
+                        src.display(),
+                        dst.display(),
+                    )
+                })?;
+            }
+            LinkMode::Soft => {
+                let parent = dst.parent().context("destination has no parent")?;
+                let target = relative_path(parent, src)?;
+                symlink(&target, &dst).with_context(|| {
+                    let (dst, target) = (dst.display(), target.display());
+                    format!("failed to symlink {dst} -> {target}")
+                })?;
+            }
         }
         FileStatus::PassedThrough
     };
+    // following symlinks, like the check on the next run
+    let dst_size = fs::metadata(&dst).ok().map(|meta| meta.len());
+    let info = FileInfo {
+        dst,
+        hash,
+        mtime,
+        size,
+        config,
+        detected_type,
+        path_template,
+        orphaned_at: None,
+        dst_size,
+    };
 
     Ok(ProcessedFile {
-        src: src.to_path_buf(),
-        info: FileInfo {
-            dst,
-            hash,
-            mtime,
-            size,
-            config,
-        },
-        status,
+        hash_contradicted,
+        reclaim_rejected,
+        reason: Some(reason),
+        repaired,
+        would_delete,
+        tag_issues,
+        extracted_art,
+        probed: prober.fresh,
+        ..ProcessedFile::new(src, info, status)
+    })
+}
+
+// an output moved into place, from the old path of its own source or from an
+// orphan. from here on it gets what a new output of the same source would get.
+// hardlinks share the source's inode, which keeps its own mtime
+fn reclaimed(
+    src: &Path,
+    mut info: FileInfo,
+    transcoded: bool,
+    args: &WorkerSettings,
+    mut prober: Prober,
+) -> ProcessedFile {
+    if transcoded || args.link_mode == LinkMode::Copy {
+        touch(&info.dst);
+    }
+    let (tag_issues, extracted_art) = match transcoded {
+        true => check_transcoded(src, &info.dst, args, &mut prober),
+        false => (Vec::new(), None),
+    };
+    info.dst_size = fs::metadata(&info.dst).ok().map(|meta| meta.len());
+    ProcessedFile {
+        tag_issues,
+        extracted_art,
+        probed: prober.fresh,
+        ..ProcessedFile::new(src, info, FileStatus::Reclaimed)
+    }
+}
+
+// the checks every new transcoded output gets, encoded or reclaimed: returns the
+// tags --verify-tags found lost or changed, and the cover --extract-art wrote
+fn check_transcoded(
+    src: &Path,
+    dst: &Path,
+    args: &WorkerSettings,
+    prober: &mut Prober,
+) -> (Vec<String>, Option<PathBuf>) {
+    let mut tag_issues = Vec::new();
+    if let Some(verifier) = args.tag_verifier {
+        // a failed check doesn't make the output any less usable
+        let src_tags = || Ok(prober.probe()?.tags.clone());
+        match verifier.check(src_tags, dst) {
+            Ok(issues) => tag_issues = issues,
+            Err(e) => {
+                warnings::warn(
+                    "failed to verify tags",
+                    format_args!("failed to verify tags of {}: {e}", dst.display()),
+                );
+            }
+        }
+    }
+    let extracted_art = extract_art(src, dst, args, prober);
+    (tag_issues, extracted_art)
+}
+
+// skipped outputs get a cover too, if their directory still has none, e.g.
+// because it was made before --extract-art or none of its files had one so far
+fn skipped_art(
+    src: &Path,
+    dst: &Path,
+    transcoded: bool,
+    args: &WorkerSettings,
+    prober: &mut Prober,
+) -> Option<PathBuf> {
+    match transcoded {
+        true => extract_art(src, dst, args, prober),
+        false => None,
+    }
+}
+
+// the probe tells whether there is a picture to extract, which keeps directories
+// of artless files from costing an ffmpeg per file on every run
+fn extract_art(
+    src: &Path,
+    dst: &Path,
+    args: &WorkerSettings,
+    prober: &mut Prober,
+) -> Option<PathBuf> {
+    let has_picture = || prober.probe().ok().map(|p| p.has_embedded_art);
+    args.art.and_then(|art| art.extract(src, dst, has_picture))
+}
+
+fn create_parent(args: &WorkerSettings, dst: &Path) -> Result<()> {
+    let Some(parent) = dst.parent() else {
+        return Ok(());
+    };
+    match args.dirs {
+        Some(dirs) => dirs.ensure(parent),
+        None => fs::create_dir_all(parent).with_context(|| {
+            format!("failed to create destination directory {}", parent.display())
+        }),
+    }
+}
+
+// takes one of the re-encodes --max-reencodes allows, false if none are left
+fn take_reencode(args: &WorkerSettings) -> bool {
+    args.reencode_budget.is_none_or(|left| {
+        left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
     })
 }
 
-fn compute_hash(path: &Path) -> Result<String> {
+// give a reclaimed output the mtime a new one would have, for players that sort by
+// date added
+fn touch(dst: &Path) {
+    let res = fs::File::options()
+        .write(true)
+        .open(dst)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = res {
+        warnings::warn(
+            "failed to set mtime",
+            format_args!("failed to set mtime of {}: {e}", dst.display()),
+        );
+    }
+}
+
+// whether a bitrate rule, downmix or resample could apply, which needs a probe
+fn needs_probe(args: &WorkerSettings) -> bool {
+    args.bitrate_per_channel.is_some()
+        || !args.bitrate_rules.is_empty()
+        || args.max_channels.is_some()
+        || args.max_sample_rate.is_some()
+        || args.bit_depth.is_some()
+}
+
+// how a file is transcoded, and the config string recording it
+struct Plan {
+    encoding: Encoding,
+    downmix: Option<u32>,
+    // Some(keep) for sources with chapters, files without them get no options
+    keep_chapters: Option<bool>,
+    config: String,
+}
+
+fn plan(
+    args: &WorkerSettings,
+    probe: Option<&AudioInfo>,
+    chapters: Option<u32>,
+) -> Plan {
+    let channels = probe.map(|p| p.channels);
+    let keep_chapters = chapters
+        .filter(|&n| n > 0)
+        .map(|_| in_formats(args.target_ext, CHAPTER_TARGETS));
+    // never upmix, only pass -ac when the source actually has more channels
+    let downmix = match (channels, args.max_channels) {
+        (Some(n), Some(max)) if n > max => Some(max),
+        _ => None,
+    };
+    let encoding = match args.bitrate {
+        Some(base) => Encoding::Lossy {
+            bitrate: effective_bitrate(args, base, channels, downmix),
+        },
+        // likewise never upsample or pad the bit depth, but do apply the limits if
+        // the source didn't report its own values
+        None => Encoding::Lossless {
+            sample_rate: args.max_sample_rate.filter(|&max| {
+                probe.and_then(|p| p.sample_rate).is_none_or(|rate| rate > max)
+            }),
+            bit_depth: args.bit_depth.filter(|&max| {
+                probe.and_then(|p| p.bit_depth).is_none_or(|depth| depth > max)
+            }),
+        },
+    };
+
+    let mut config = format!("{}:{}", args.target_ext, encoding.config());
+    if let Some(n) = downmix {
+        config.push_str(&format!(":ac{n}"));
+    }
+    if args.embed_provenance {
+        config.push_str(":provenance");
+    }
+    if keep_chapters == Some(true) {
+        config.push_str(":chapters");
+    }
+    Plan { encoding, downmix, keep_chapters, config }
+}
+
+/// The config a cached transcoded file would get with the current settings and
+/// the cached probe of its source, without touching the file. None if that
+/// depends on a probe it lacks, or on a source that isn't known.
+pub fn predicted_config(
+    args: &WorkerSettings,
+    src: Option<&Path>,
+    hit: &FileInfo,
+) -> Option<String> {
+    let probe = src.and_then(|src| args.cached_probe(src, hit.mtime, hit.size));
+    let chapter_source = src.is_some_and(may_have_chapters);
+    if (needs_probe(args) || chapter_source) && probe.is_none() {
+        return None;
+    }
+    let audio = probe.filter(|_| needs_probe(args)).map(ProbeInfo::audio);
+    let chapters = probe.filter(|_| chapter_source).map(|p| p.chapters);
+    Some(plan(args, audio.as_ref(), chapters).config)
+}
+
+/// Whether an orphan could be reclaimed under these settings at all, i.e. they
+/// would produce its config again for the same content. Whether a passthrough
+/// orphan is taken over still depends on the new source.
+pub fn may_reclaim(
+    args: &WorkerSettings,
+    src: Option<&Path>,
+    orphan: &FileInfo,
+) -> bool {
+    if args.no_delete
+        || args.rebuild
+        || args.reencode_configs.contains(&orphan.config)
+    {
+        return false;
+    }
+    match orphan.config.as_str() {
+        // symlinks can't be moved, and new ones aren't reclaimed either
+        "passthrough:symlink" => false,
+        "passthrough" => args.link_mode != LinkMode::Soft,
+        config => predicted_config(args, src, orphan).is_none_or(|c| c == config),
+    }
+}
+
+// rules for an exact source channel count win over the per-channel rate (which
+// counts output channels, after downmixing), which wins over the base bitrate
+fn effective_bitrate(
+    args: &WorkerSettings,
+    base: u32,
+    channels: Option<u32>,
+    downmix: Option<u32>,
+) -> u32 {
+    let Some(channels) = channels else {
+        return base;
+    };
+    if let Some(rule) = args.bitrate_rules.iter().find(|r| r.channels == channels) {
+        return rule.bitrate;
+    }
+    args.bitrate_per_channel
+        .map(|per_channel| per_channel * downmix.unwrap_or(channels))
+        .unwrap_or(base)
+}
+
+// the destination --path-template renders for a file, or the mirrored one if the
+// file lacks one of its tags
+fn render_dst(
+    src: &Path,
+    template: &PathTemplate,
+    args: &WorkerSettings,
+    prober: &mut Prober,
+    mirrored: PathBuf,
+) -> Result<PathBuf> {
+    let tags = normalize(prober.probe()?.tags.clone());
+    match template.render(&tags) {
+        Ok(rel) => Ok(args.dst_root.join(format!("{rel}.{}", args.target_ext))),
+        Err(missing) => {
+            warnings::warn(
+                "missing template tags",
+                format_args!(
+                    "{} has no {} tag for --path-template, mirroring its path",
+                    src.display(),
+                    missing.join(" or "),
+                ),
+            );
+            Ok(mirrored)
+        }
+    }
+}
+
+// catches orphans that were truncated or corrupted after being recorded.
+// passed-through files must still match the source size, transcoded ones are
+// only checked for a readable audio stream. orphans aren't sources, so their
+// probe isn't kept
+fn reclaim_candidate_valid(info: &Orphan, transcoded: bool) -> bool {
+    if transcoded {
+        probe_file(&info.dst).is_ok()
+    } else {
+        fs::metadata(&info.dst).is_ok_and(|meta| meta.len() == info.size)
+    }
+}
+
+// whether dst is a symlink with the relative target soft mode would create for src
+fn symlink_points_to(dst: &Path, src: &Path) -> bool {
+    let Some(parent) = dst.parent() else {
+        return false;
+    };
+    match (fs::read_link(dst), relative_path(parent, src)) {
+        (Ok(target), Ok(expected)) => target == expected,
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+// bytes hashed at each end of a file by --fast-hash
+const PARTIAL_HASH_CHUNK: u64 = 16 * 1024 * 1024;
+
+/// Prefix of hashes made by --fast-hash from only the head, the tail and the
+/// length of a file. The prefix keeps them from ever matching a full hash.
+pub const PARTIAL_HASH_PREFIX: &str = "partial:";
+
+/// Hash a file, only its head and tail if it is larger than `fast_hash` bytes.
+pub fn hash_file(path: &Path, size: u64, fast_hash: Option<u64>) -> Result<String> {
+    match fast_hash {
+        Some(threshold) if size > threshold => compute_partial_hash(path),
+        _ => compute_hash(path),
+    }
+}
+
+/// Hash a file the same way `like` was made, so the two can be compared.
+pub fn hash_like(path: &Path, like: &str) -> Result<String> {
+    match like.starts_with(PARTIAL_HASH_PREFIX) {
+        true => compute_partial_hash(path),
+        false => compute_hash(path),
+    }
+}
+
+fn compute_partial_hash(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    // head and tail overlap for files under twice the chunk size, which is fine
+    std::io::copy(&mut (&mut file).take(PARTIAL_HASH_CHUNK), &mut hasher)?;
+    file.seek(SeekFrom::Start(len.saturating_sub(PARTIAL_HASH_CHUNK)))?;
+    std::io::copy(&mut file.take(PARTIAL_HASH_CHUNK), &mut hasher)?;
+    Ok(format!("{PARTIAL_HASH_PREFIX}{}", hasher.finalize().to_hex()))
+}
+
+#[cfg(test)]
+thread_local! {
+    // full hashes computed on this thread, for tests of what reads the sources
+    pub static HASHED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    // a script sh runs instead of ffmpeg on this thread, for tests of failed encodes
+    pub static FFMPEG_STUB: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+pub fn compute_hash(path: &Path) -> Result<String> {
+    #[cfg(test)]
+    HASHED.with(|n| n.set(n.get() + 1));
     // streaming hash so we don't use a ton of memory on large input files
     let mut file = fs::File::open(path)?;
     let mut hasher = blake3::Hasher::new();
@@ -219,22 +1205,374 @@ fn compute_hash(path: &Path) -> Result<String> {
     Ok(hasher.finalize().to_hex().to_string())
 }
 
-fn spawn_ffmpeg(src: &Path, dst: &Path, bitrate: u32) -> Result<()> {
+fn in_formats(ext: &str, formats: &[&str]) -> bool {
+    formats.iter().any(|f| f.eq_ignore_ascii_case(ext))
+}
+
+/// Whether the container of a source can have chapters, so it's probed for them.
+pub fn may_have_chapters(src: &Path) -> bool {
+    src.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| in_formats(e, CHAPTER_SOURCES))
+}
+
+// a failed write can leave a truncated file behind, which isn't in the database
+// and would never be cleaned up. failing to remove it doesn't replace the
+// original error, it's only mentioned after it
+fn remove_partial<T>(res: Result<T>, dst: &Path) -> Result<T> {
+    let Err(e) = res else {
+        return res;
+    };
+    match fs::remove_file(dst) {
+        Err(rm) if rm.kind() != std::io::ErrorKind::NotFound => Err(anyhow!(
+            "{e:#}\n(also failed to remove the partial output {}: {rm})",
+            dst.display(),
+        )),
+        _ => Err(e),
+    }
+}
+
+// metadata is added as extra tags, on top of the ones copied from the source
+fn spawn_ffmpeg(
+    src: &Path,
+    dst: &Path,
+    encoding: &Encoding,
+    downmix: Option<u32>,
+    keep_chapters: Option<bool>,
+    metadata: &[(&str, String)],
+    progress: Option<(f64, &dyn Fn(f32))>,
+) -> Result<()> {
     if dst.exists() {
         fs::remove_file(dst)?;
     }
+    let mut cmd = ffmpeg_command(
+        src,
+        dst,
+        encoding,
+        downmix,
+        keep_chapters,
+        metadata,
+        progress.is_some(),
+    );
+    let output = match progress {
+        Some((duration, report)) => output_with_progress(&mut cmd, duration, report),
+        None => cmd.output(),
+    }
+    .context("ffmpeg invocation failed")?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    ensure!(
+        output.status.success(),
+        "ffmpeg failed with status: {}{}",
+        output.status,
+        match stderr.trim() {
+            "" => String::new(),
+            stderr => format!("\n{stderr}"),
+        },
+    );
+    Ok(())
+}
+
+// what spawn_ffmpeg runs, apart from running it so its arguments can be checked
+fn ffmpeg_command(
+    src: &Path,
+    dst: &Path,
+    encoding: &Encoding,
+    downmix: Option<u32>,
+    keep_chapters: Option<bool>,
+    metadata: &[(&str, String)],
+    progress: bool,
+) -> Command {
+    let mut cmd = Command::new("ffmpeg");
+    #[cfg(test)]
+    if let Some(stub) = FFMPEG_STUB.with_borrow(Clone::clone) {
+        cmd = Command::new("sh");
+        cmd.arg(stub);
+    }
     #[rustfmt::skip]
-    let status = Command::new("ffmpeg")
+    cmd
         // we are already running worker threads in parallel, each worker
         // thread shouldn't spawn even more threads
         .arg("-threads").arg("1")
         .arg("-v").arg("error")
         .arg("-i").arg(src)
-        .arg("-b:a").arg(format!("{bitrate}k"))
-        .arg("-vn")
-        .arg(dst)
-        .status()
+        .arg("-vn");
+    match *encoding {
+        Encoding::Lossy { bitrate } => {
+            cmd.arg("-b:a").arg(format!("{bitrate}k"));
+        }
+        Encoding::Lossless { sample_rate, bit_depth } => {
+            if let Some(rate) = sample_rate {
+                cmd.arg("-ar").arg(rate.to_string());
+            }
+            match bit_depth {
+                Some(16) => {
+                    cmd.arg("-sample_fmt").arg("s16");
+                }
+                // 24-bit samples are stored in 32-bit ones
+                Some(depth) => {
+                    cmd.arg("-sample_fmt").arg("s32");
+                    cmd.arg("-bits_per_raw_sample").arg(depth.to_string());
+                }
+                None => {}
+            }
+        }
+    }
+    if let Some(n) = downmix {
+        cmd.arg("-ac").arg(n.to_string());
+    }
+    match keep_chapters {
+        Some(true) => {
+            cmd.arg("-map_chapters").arg("0");
+        }
+        // some muxers fail on chapters they can't write
+        Some(false) => {
+            cmd.arg("-map_chapters").arg("-1");
+        }
+        None => {}
+    }
+    for (key, value) in metadata {
+        cmd.arg("-metadata").arg(format!("{key}={value}"));
+    }
+    if progress {
+        cmd.arg("-progress").arg("pipe:1").arg("-nostats");
+    }
+    // stderr is kept for the error, where it stays next to the file it's about
+    cmd.arg(dst).stdin(Stdio::null());
+    cmd
+}
+
+// like Command::output, reporting the out_time lines ffmpeg writes to stdout with
+// -progress as a fraction of the source's duration
+fn output_with_progress(
+    cmd: &mut Command,
+    duration: f64,
+    report: &dyn Fn(f32),
+) -> std::io::Result<Output> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+
+    // stderr is drained on its own thread, so ffmpeg can't block on a full pipe
+    // while stdout is being read
+    let stderr = std::thread::scope(|scope| {
+        let reader = scope.spawn(move || {
+            let mut buf = Vec::new();
+            _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+        let mut last_report = Instant::now();
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            // N/A until the first frame is written
+            let Some(us) = line
+                .strip_prefix("out_time_us=")
+                .and_then(|v| v.trim().parse::<f64>().ok())
+            else {
+                continue;
+            };
+            if last_report.elapsed() >= PROGRESS_INTERVAL {
+                last_report = Instant::now();
+                report((us / 1_000_000.0 / duration).clamp(0.0, 1.0) as f32);
+            }
+        }
+        reader.join().unwrap_or_default()
+    });
+    Ok(Output { status: child.wait()?, stdout: Vec::new(), stderr })
+}
+
+/// Encode a one second test tone with the same arguments used for real files, so
+/// a bad format or encoder option fails the run up front instead of on every file.
+pub fn preflight(
+    target_ext: &str,
+    encoding: &Encoding,
+    downmix: Option<u32>,
+) -> Result<()> {
+    let dir = std::env::temp_dir()
+        .join(format!("sidechain-preflight-{}", std::process::id()));
+    fs::create_dir_all(&dir).context("failed to create preflight directory")?;
+    let result = encode_test_tone(&dir, target_ext, encoding, downmix);
+    _ = fs::remove_dir_all(&dir);
+    result
+}
+
+fn encode_test_tone(
+    dir: &Path,
+    target_ext: &str,
+    encoding: &Encoding,
+    downmix: Option<u32>,
+) -> Result<()> {
+    let tone = dir.join("tone.wav");
+    // one channel more than the limit, so -ac is exercised too
+    let channels = downmix.map(|n| n + 1).unwrap_or(2);
+    #[rustfmt::skip]
+    let output = Command::new("ffmpeg")
+        .arg("-v").arg("error")
+        .arg("-f").arg("lavfi")
+        .arg("-i").arg("sine=frequency=440:duration=1")
+        .arg("-ac").arg(channels.to_string())
+        .arg(&tone)
+        .stdin(Stdio::null())
+        .output()
         .context("ffmpeg invocation failed")?;
-    ensure!(status.success(), "ffmpeg failed with status: {}", status);
-    Ok(())
+    ensure!(
+        output.status.success(),
+        "failed to generate test tone, use --skip-preflight if lavfi is \
+         unavailable: {}",
+        String::from_utf8_lossy(&output.stderr).trim(),
+    );
+
+    let out = dir.join(format!("tone.{target_ext}"));
+    spawn_ffmpeg(&tone, &out, encoding, downmix, None, &[], None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        probe::{parse_probe, tests::AUDIOBOOK},
+        tests::args,
+        testutil::TempDir,
+        worker_settings,
+    };
+
+    fn ffmpeg_args(plan: &Plan) -> Vec<String> {
+        let (src, dst) = (Path::new("in.flac"), Path::new("out.opus"));
+        let Plan { encoding, downmix, keep_chapters, .. } = plan;
+        let cmd =
+            ffmpeg_command(src, dst, encoding, *downmix, *keep_chapters, &[], false);
+        cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    fn process(args: &crate::Args, src: &Path, cache: &FileCache) -> Result<()> {
+        let (orphans, probes) = (OrphanCache::default(), ProbeCache::new());
+        let settings =
+            worker_settings(args, None, None, None, &orphans, cache, &probes);
+        process_file(src, settings).map(|_| ())
+    }
+
+    #[test]
+    fn failed_transcode_leaves_no_output() {
+        let tmp = TempDir::new();
+        let src = tmp.file("src/a.flac", b"flac");
+        // stands in for ffmpeg dying halfway through an encode
+        let stub = tmp.file(
+            "ffmpeg",
+            b"for a; do out=$a; done\necho half an encode > \"$out\"\nexit 1\n",
+        );
+        let mut args = args(&["-f", "opus", "-b", "128"]);
+        args.source = tmp.path().join("src");
+        args.destination = tmp.path().join("dst");
+
+        FFMPEG_STUB.set(Some(stub));
+        let res = process(&args, &src, &FileCache::new());
+        FFMPEG_STUB.set(None);
+        let err = res.unwrap_err().to_string();
+        assert!(err.starts_with("ffmpeg failed with status: exit status: 1"));
+        let dst = tmp.path().join("dst/a.opus");
+        assert!(dst.symlink_metadata().is_err());
+        assert_eq!(fs::read_dir(tmp.path().join("dst")).unwrap().count(), 0);
+    }
+
+    // reading /proc/self/mem from the start fails with EIO, after the copy
+    // already created the output. a cached hash keeps it from being hashed first
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn failed_copy_leaves_no_output() {
+        let tmp = TempDir::new();
+        let src = Path::new("/proc/self/mem");
+        let meta = fs::metadata(src).unwrap();
+        let mtime = meta.modified().unwrap().duration_since(std::time::UNIX_EPOCH);
+        let hit = FileInfo {
+            dst: tmp.path().join("mem"),
+            hash: "0".repeat(64),
+            mtime: mtime.unwrap().as_secs() as i64,
+            size: meta.len(),
+            config: "passthrough".to_string(),
+            ..Default::default()
+        };
+        let mut args = args(&["-f", "opus", "-b", "128", "--copy"]);
+        (args.source, args.destination) = ("/proc/self".into(), tmp.path().into());
+
+        let cache = FileCache::from([(src.to_path_buf(), hit)]);
+        let err = process(&args, src, &cache).unwrap_err().to_string();
+        assert!(err.starts_with("failed to copy"), "{err}");
+        assert!(tmp.path().join("mem").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn failed_removal_is_mentioned_after_the_error() {
+        let tmp = TempDir::new();
+        // a directory where the output would be can't be removed as a file
+        let dst = tmp.path().join("out.opus");
+        fs::create_dir(&dst).unwrap();
+        let res: Result<()> = Err(anyhow!("failed to copy"));
+        let err = remove_partial(res, &dst).unwrap_err().to_string();
+        assert!(err.starts_with("failed to copy\n(also failed to remove"));
+    }
+
+    #[test]
+    fn only_sources_with_more_channels_are_downmixed() {
+        let args = args(&["-f", "opus", "-b", "128", "--channels", "2"]);
+        let (orphans, cache, probes) =
+            (OrphanCache::default(), FileCache::new(), ProbeCache::new());
+        let settings =
+            worker_settings(&args, None, None, None, &orphans, &cache, &probes);
+        let cases = [(6, Some(2)), (3, Some(2)), (2, None), (1, None)];
+        for (channels, downmix) in cases {
+            let (sample_rate, bit_depth) = (Some(48000), None);
+            let audio = AudioInfo { channels, sample_rate, bit_depth };
+            let plan = plan(&settings, Some(&audio), None);
+            assert_eq!(plan.downmix, downmix, "{channels} channels");
+            let ffmpeg = ffmpeg_args(&plan);
+            let ac = ffmpeg.iter().position(|a| a == "-ac").map(|i| &ffmpeg[i + 1]);
+            assert_eq!(ac.map(String::as_str), downmix.map(|_| "2"), "{ffmpeg:?}");
+            assert_eq!(plan.config.ends_with(":ac2"), downmix.is_some());
+        }
+    }
+
+    #[test]
+    fn chapters_survive_a_transcode() {
+        let src = Path::new("/src/book.m4b");
+        let hit = FileInfo { mtime: 1, size: 2, ..Default::default() };
+        let chapterless = AUDIOBOOK.replace("[CHAPTER]", "[OTHER]");
+        let cases = [
+            ("opus", AUDIOBOOK, Some("0")),
+            ("flac", AUDIOBOOK, Some("-1")),
+            ("opus", chapterless.as_str(), None),
+        ];
+        for (format, probed, map_chapters) in cases {
+            let info = parse_probe(probed).unwrap();
+            let cached = CachedProbe { mtime: 1, size: 2, info };
+            let probes = ProbeCache::from([(src.into(), cached)]);
+            let args = args(&["-f", format, "-b", "128"]);
+            let (orphans, cache) = (OrphanCache::default(), FileCache::new());
+            let settings =
+                worker_settings(&args, None, None, None, &orphans, &cache, &probes);
+            // the chapter count comes from the probe table, like in process_file
+            let probe = settings.cached_probe(src, hit.mtime, hit.size).unwrap();
+            let plan = plan(&settings, None, Some(probe.chapters));
+            let ffmpeg = ffmpeg_args(&plan);
+            let map = ffmpeg.iter().position(|a| a == "-map_chapters");
+            let map = map.map(|i| ffmpeg[i + 1].as_str());
+            assert_eq!(map, map_chapters, "{format}: {ffmpeg:?}");
+            let kept = map_chapters == Some("0");
+            assert_eq!(plan.config.ends_with(":chapters"), kept, "{format}");
+            let predicted = predicted_config(&settings, Some(src), &hit);
+            assert_eq!(predicted, Some(plan.config), "{format}");
+        }
+    }
+
+    #[test]
+    fn chapter_sources_without_a_probe_arent_predicted() {
+        let args = args(&["-f", "opus", "-b", "128"]);
+        let (orphans, cache, probes) =
+            (OrphanCache::default(), FileCache::new(), ProbeCache::new());
+        let settings =
+            worker_settings(&args, None, None, None, &orphans, &cache, &probes);
+        let hit = FileInfo::default();
+        let predicted =
+            |src: &str| predicted_config(&settings, Some(src.as_ref()), &hit);
+        assert_eq!(predicted("/src/book.m4b"), None);
+        let album = predicted("/src/a.flac");
+        assert_eq!(album.as_deref(), Some("opus:128"));
+    }
 }